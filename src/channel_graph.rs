@@ -0,0 +1,776 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+
+use serde::Serialize;
+
+use crate::{
+    gossip_store::{GossipStore, MemoryGossipStore},
+    messages::{
+        ChannelAnnouncementMessage, ChannelUpdateMessage, NodeAnnouncementMessage,
+        QueryChannelRangeMessage, ReplyChannelRangeMessage, QUERY_OPTION_CHECKSUMS,
+        QUERY_OPTION_TIMESTAMPS,
+    },
+    serialization::{PointElement, ShortChannelIDElement},
+};
+
+// one past the highest block height a scid can hold
+const SCID_BLOCK_LIMIT: u64 = 1 << 24;
+
+#[derive(Debug, Clone)]
+pub struct ChannelEdge {
+    pub announcement: ChannelAnnouncementMessage,
+    // latest update per direction, indexed by ChannelUpdateMessage::direction
+    pub updates: [Option<ChannelUpdateMessage>; 2],
+}
+
+impl ChannelEdge {
+    pub fn new(announcement: ChannelAnnouncementMessage) -> Self {
+        ChannelEdge {
+            announcement,
+            updates: [None, None],
+        }
+    }
+
+    // This is only an estimate: gossip never carries the funding amount, so we take the
+    // largest htlc_maximum_msat either side has advertised as a lower bound on capacity.
+    pub fn inferred_capacity_msat(&self) -> Option<u64> {
+        self.updates
+            .iter()
+            .flatten()
+            .filter_map(|update| update.htlc_maximum_msat)
+            .max()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphInconsistency {
+    // the channel's endpoint never sent a node_announcement
+    ChannelWithoutNodeAnnouncement {
+        short_channel_id: ShortChannelIDElement,
+        node_id: [u8; 33],
+    },
+    // an update stored under an edge it doesn't belong to, i.e. with no announcement
+    // of its own
+    UpdateWithoutAnnouncement {
+        short_channel_id: ShortChannelIDElement,
+        direction: usize,
+    },
+    NodeWithoutChannels {
+        node_id: [u8; 33],
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetworkSummary {
+    pub total_channels: usize,
+    // every node we know of, announced or only seen as a channel endpoint
+    pub total_nodes: usize,
+    pub announced_nodes: usize,
+    pub referenced_only_nodes: usize,
+    pub total_inferred_capacity_msat: u64,
+}
+
+pub struct ChannelGraph {
+    store: Box<dyn GossipStore>,
+    // node id -> scids of the channels it is an endpoint of, so a node's channels can be
+    // found without scanning the store
+    node_channels: HashMap<[u8; 33], Vec<ShortChannelIDElement>>,
+    // every scid by its u64 form, which sorts by block height, so a block range is a
+    // range of this map instead of a scan of the store
+    scid_index: BTreeMap<u64, ShortChannelIDElement>,
+    // node id -> timestamp of its stored announcement, so rebroadcasts are turned away
+    // without reading the announcement back out of the store
+    node_timestamps: HashMap<[u8; 33], u32>,
+}
+
+impl ChannelGraph {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        ChannelGraph::with_store(Box::new(MemoryGossipStore::new()))
+    }
+
+    pub fn with_store(store: Box<dyn GossipStore>) -> Self {
+        let mut graph = ChannelGraph {
+            store,
+            node_channels: HashMap::new(),
+            scid_index: BTreeMap::new(),
+            node_timestamps: HashMap::new(),
+        };
+        // the store may already hold channels from an earlier run
+        let announcements: Vec<ChannelAnnouncementMessage> = graph
+            .store
+            .iter_channels()
+            .map(|edge| edge.announcement)
+            .collect();
+        for announcement in &announcements {
+            graph.index_channel(announcement);
+        }
+        graph.node_timestamps = graph
+            .store
+            .iter_nodes()
+            .map(|node| (node.node_id.value, node.timestamp))
+            .collect();
+        graph
+    }
+
+    fn index_channel(&mut self, announcement: &ChannelAnnouncementMessage) {
+        let short_channel_id = &announcement.short_channel_id;
+        self.scid_index
+            .insert(short_channel_id.to_u64(), short_channel_id.clone());
+        let mut node_ids = vec![announcement.node_id_1.value, announcement.node_id_2.value];
+        node_ids.dedup();
+        for node_id in node_ids {
+            self.node_channels
+                .entry(node_id)
+                .or_default()
+                .push(announcement.short_channel_id.clone());
+        }
+    }
+
+    // announcements of every channel the node is an endpoint of, in the order we learned them
+    pub fn node_channels(&self, node_id: &[u8; 33]) -> Vec<ChannelAnnouncementMessage> {
+        self.node_channels
+            .get(node_id)
+            .map(|short_channel_ids| {
+                short_channel_ids
+                    .iter()
+                    .filter_map(|short_channel_id| self.store.get_channel(short_channel_id))
+                    .map(|edge| edge.announcement)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.store.num_channels()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.store.num_nodes()
+    }
+
+    // without copying the edge out of the store
+    pub fn has_channel(&self, short_channel_id: &ShortChannelIDElement) -> bool {
+        self.scid_index.contains_key(&short_channel_id.to_u64())
+    }
+
+    // whether we hold an announcement for the node, not just channels it is an endpoint of
+    pub fn has_node(&self, node_id: &PointElement) -> bool {
+        self.node_timestamps.contains_key(&node_id.value)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_channel(&self, short_channel_id: &ShortChannelIDElement) -> Option<ChannelEdge> {
+        self.store.get_channel(short_channel_id)
+    }
+
+    pub fn get_node(&self, node_id: &PointElement) -> Option<NodeAnnouncementMessage> {
+        self.store.get_node(node_id)
+    }
+
+    // channels funded in blocks first_blocknum up to but not including end_blocknum,
+    // in scid order
+    pub fn channels_in_blocks(&self, first_blocknum: u32, end_blocknum: u64) -> Vec<ChannelEdge> {
+        // a scid has 3 bytes of height, nothing is funded beyond that
+        let first = (first_blocknum as u64).min(SCID_BLOCK_LIMIT);
+        let end = end_blocknum.min(SCID_BLOCK_LIMIT);
+        if first >= end {
+            return Vec::new();
+        }
+        let upper = match end {
+            SCID_BLOCK_LIMIT => Bound::Unbounded,
+            end => Bound::Excluded(end << 40),
+        };
+        self.scid_index
+            .range((Bound::Included(first << 40), upper))
+            .filter_map(|(_, short_channel_id)| self.store.get_channel(short_channel_id))
+            .collect()
+    }
+
+    pub fn channels(&self) -> Vec<ChannelEdge> {
+        self.store.iter_channels().collect()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeAnnouncementMessage> {
+        self.store.iter_nodes().collect()
+    }
+
+    pub fn summary(&self) -> NetworkSummary {
+        let announced: HashSet<[u8; 33]> = self
+            .store
+            .iter_nodes()
+            .map(|node| node.node_id.value)
+            .collect();
+        let mut referenced = HashSet::new();
+        let mut total_channels = 0;
+        let mut total_inferred_capacity_msat = 0;
+        for edge in self.store.iter_channels() {
+            total_channels += 1;
+            referenced.insert(edge.announcement.node_id_1.value);
+            referenced.insert(edge.announcement.node_id_2.value);
+            total_inferred_capacity_msat += edge.inferred_capacity_msat().unwrap_or(0);
+        }
+        let referenced_only_nodes = referenced.difference(&announced).count();
+        NetworkSummary {
+            total_channels,
+            total_nodes: announced.len() + referenced_only_nodes,
+            announced_nodes: announced.len(),
+            referenced_only_nodes,
+            total_inferred_capacity_msat,
+        }
+    }
+
+    // channels per node id, over every node that is a channel endpoint
+    pub fn channel_counts(&self) -> HashMap<[u8; 33], usize> {
+        let mut counts = HashMap::new();
+        for edge in self.store.iter_channels() {
+            for node_id in [
+                edge.announcement.node_id_1.value,
+                edge.announcement.node_id_2.value,
+            ] {
+                *counts.entry(node_id).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    // Read-only audit of the stored gossip, mostly useful after a sync to see how
+    // complete it is. Channels come first in scid order, then nodes.
+    #[allow(dead_code)]
+    pub fn validate(&self) -> Vec<GraphInconsistency> {
+        let announced: HashSet<[u8; 33]> = self
+            .store
+            .iter_nodes()
+            .map(|node| node.node_id.value)
+            .collect();
+        let mut edges = self.channels();
+        edges.sort_by_key(|edge| {
+            let scid = &edge.announcement.short_channel_id;
+            (scid.block_height, scid.tx_index, scid.output_index)
+        });
+        let mut inconsistencies = Vec::new();
+        let mut with_channels = HashSet::new();
+        for edge in &edges {
+            let short_channel_id = &edge.announcement.short_channel_id;
+            for node_id in [
+                edge.announcement.node_id_1.value,
+                edge.announcement.node_id_2.value,
+            ] {
+                with_channels.insert(node_id);
+                if !announced.contains(&node_id) {
+                    inconsistencies.push(GraphInconsistency::ChannelWithoutNodeAnnouncement {
+                        short_channel_id: short_channel_id.clone(),
+                        node_id,
+                    });
+                }
+            }
+            for (direction, update) in edge.updates.iter().enumerate() {
+                let update = match update {
+                    Some(update) => update,
+                    None => continue,
+                };
+                if &update.short_channel_id != short_channel_id || update.direction() != direction {
+                    inconsistencies.push(GraphInconsistency::UpdateWithoutAnnouncement {
+                        short_channel_id: update.short_channel_id.clone(),
+                        direction: update.direction(),
+                    });
+                }
+            }
+        }
+        let mut lonely: Vec<[u8; 33]> = announced.difference(&with_channels).cloned().collect();
+        lonely.sort();
+        inconsistencies.extend(
+            lonely
+                .into_iter()
+                .map(|node_id| GraphInconsistency::NodeWithoutChannels { node_id }),
+        );
+        inconsistencies
+    }
+
+    // Answers a query_channel_range with replies of at most max_per_reply scids. Replies
+    // cover whole blocks where possible, tile the queried range without gaps and only the
+    // last one has sync_complete set, even when its batch is full.
+    pub fn channel_range_replies(
+        &self,
+        query: &QueryChannelRangeMessage,
+        max_per_reply: usize,
+    ) -> Vec<ReplyChannelRangeMessage> {
+        let end = query.end_blocknum();
+        let edges = self.channels_in_blocks(query.first_blocknum, end);
+
+        let mut batches: Vec<Vec<ChannelEdge>> = Vec::new();
+        for block in edges.chunk_by(|a, b| {
+            a.announcement.short_channel_id.block_height
+                == b.announcement.short_channel_id.block_height
+        }) {
+            // a block only gets split when it alone holds more than max_per_reply scids
+            for chunk in block.chunks(max_per_reply.max(1)) {
+                match batches.last_mut() {
+                    Some(batch) if batch.len() + chunk.len() <= max_per_reply => {
+                        batch.extend_from_slice(chunk)
+                    }
+                    _ => batches.push(chunk.to_vec()),
+                }
+            }
+        }
+        if batches.is_empty() {
+            batches.push(Vec::new());
+        }
+
+        let query_option = query.query_option();
+        let height = |edge: &ChannelEdge| edge.announcement.short_channel_id.block_height as u64;
+        let mut replies = Vec::new();
+        let mut first = query.first_blocknum as u64;
+        for (i, batch) in batches.iter().enumerate() {
+            let last = i == batches.len() - 1;
+            let next_first = match batches.get(i + 1) {
+                // the next batch may continue a split block, so never stop short of our own
+                Some(next) => height(&next[0]).max(batch.last().map_or(first, height) + 1),
+                None => end,
+            };
+            let short_channel_ids: Vec<ShortChannelIDElement> = batch
+                .iter()
+                .map(|edge| edge.announcement.short_channel_id.clone())
+                .collect();
+            let per_direction = |f: &dyn Fn(&ChannelUpdateMessage) -> u32| {
+                batch
+                    .iter()
+                    .map(|edge| {
+                        [
+                            edge.updates[0].as_ref().map_or(0, f),
+                            edge.updates[1].as_ref().map_or(0, f),
+                        ]
+                    })
+                    .collect::<Vec<[u32; 2]>>()
+            };
+            let timestamps = (query_option & QUERY_OPTION_TIMESTAMPS != 0)
+                .then(|| per_direction(&|update| update.timestamp));
+            let checksums = (query_option & QUERY_OPTION_CHECKSUMS != 0)
+                .then(|| per_direction(&|update| update.checksum()));
+            replies.push(ReplyChannelRangeMessage::new(
+                query.chain_hash.value,
+                first as u32,
+                (next_first - first) as u32,
+                last,
+                &short_channel_ids,
+                timestamps,
+                checksums,
+            ));
+            if let Some(next) = batches.get(i + 1) {
+                first = height(&next[0]);
+            }
+        }
+        replies
+    }
+
+    // returns true if the channel was not known before
+    pub fn add_channel_announcement(&mut self, msg: ChannelAnnouncementMessage) -> bool {
+        if self.store.get_channel(&msg.short_channel_id).is_some() {
+            return false;
+        }
+        self.index_channel(&msg);
+        self.store.put_channel(ChannelEdge::new(msg));
+        true
+    }
+
+    // returns true if the update was applied, updates for unknown channels or older
+    // than the one we already have are dropped
+    pub fn add_channel_update(&mut self, msg: ChannelUpdateMessage) -> bool {
+        let mut edge = match self.store.get_channel(&msg.short_channel_id) {
+            Some(edge) => edge,
+            None => return false,
+        };
+        let direction = msg.direction();
+        if let Some(existing) = &edge.updates[direction] {
+            if existing.timestamp >= msg.timestamp {
+                return false;
+            }
+        }
+        edge.updates[direction] = Some(msg);
+        self.store.put_channel(edge);
+        true
+    }
+
+    // true if we already hold an announcement for the node that is at least as recent
+    pub fn is_stale_node_announcement(&self, msg: &NodeAnnouncementMessage) -> bool {
+        self.node_timestamps
+            .get(&msg.node_id.value)
+            .is_some_and(|timestamp| *timestamp >= msg.timestamp)
+    }
+
+    // a newer announcement replaces the one we hold
+    pub fn add_node_announcement(&mut self, msg: NodeAnnouncementMessage) -> bool {
+        if self.is_stale_node_announcement(&msg) {
+            return false;
+        }
+        self.node_timestamps
+            .insert(msg.node_id.value, msg.timestamp);
+        self.store.put_node(msg);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_range_sync::ChannelRangeSync;
+    use crate::messages::MessageType;
+    use crate::serialization::SerializableToBytes;
+    use crate::test_util::example_message;
+
+    fn channel_announcement() -> ChannelAnnouncementMessage {
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        ChannelAnnouncementMessage::from_bytes(&bytes).unwrap().0
+    }
+
+    fn channel_update(channel_flags: u8, htlc_maximum_msat: u64) -> ChannelUpdateMessage {
+        let bytes = example_message(MessageType::ChannelUpdate);
+        let mut update = ChannelUpdateMessage::from_bytes(&bytes).unwrap().0;
+        update.channel_flags = channel_flags;
+        update.htlc_maximum_msat = Some(htlc_maximum_msat);
+        update
+    }
+
+    #[test]
+    fn test_inferred_capacity_takes_max_direction() {
+        let mut graph = ChannelGraph::new();
+        let announcement = channel_announcement();
+        let scid = announcement.short_channel_id.clone();
+        assert!(graph.add_channel_announcement(announcement));
+        assert_eq!(
+            graph.get_channel(&scid).unwrap().inferred_capacity_msat(),
+            None
+        );
+
+        assert!(graph.add_channel_update(channel_update(0, 5_000_000_000)));
+        assert!(graph.add_channel_update(channel_update(1, 10_000_000_000)));
+        assert_eq!(
+            graph.get_channel(&scid).unwrap().inferred_capacity_msat(),
+            Some(10_000_000_000)
+        );
+    }
+
+    #[test]
+    fn test_network_summary() {
+        let mut graph = ChannelGraph::new();
+        let first = channel_announcement();
+        let mut second = first.clone();
+        second.short_channel_id.block_height += 1;
+        second.node_id_2 = PointElement { value: [3; 33] };
+        assert!(graph.add_channel_announcement(first));
+        assert!(graph.add_channel_announcement(second));
+        assert!(graph.add_channel_update(channel_update(0, 5_000_000_000)));
+        assert!(graph.add_channel_update(channel_update(1, 10_000_000_000)));
+
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        let node = NodeAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+        let node_id = node.node_id.clone();
+        assert!(!graph.has_node(&node_id));
+        assert!(graph.add_node_announcement(node));
+        assert!(graph.has_node(&node_id));
+
+        assert_eq!(
+            graph.summary(),
+            NetworkSummary {
+                total_channels: 2,
+                total_nodes: 3,
+                announced_nodes: 1,
+                referenced_only_nodes: 2,
+                total_inferred_capacity_msat: 10_000_000_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_channel_range_replies_fragments() {
+        let mut graph = ChannelGraph::new();
+        for (block_height, tx_index) in [(103, 2), (100, 1), (101, 1), (100, 2), (103, 1), (90, 1)]
+        {
+            let mut announcement = channel_announcement();
+            announcement.short_channel_id.block_height = block_height;
+            announcement.short_channel_id.tx_index = tx_index;
+            assert!(graph.add_channel_announcement(announcement));
+        }
+        let mut update = channel_update(1, 10_000_000_000);
+        update.short_channel_id.block_height = 100;
+        update.short_channel_id.tx_index = 1;
+        let checksum = update.checksum();
+        let timestamp = update.timestamp;
+        assert!(graph.add_channel_update(update));
+
+        let chain_hash = [6; 32];
+        let query = QueryChannelRangeMessage::new(chain_hash, 100, 10)
+            .with_query_option(QUERY_OPTION_TIMESTAMPS | QUERY_OPTION_CHECKSUMS);
+        let replies = graph.channel_range_replies(&query, 2);
+        // (first_blocknum, number_of_blocks, sync_complete, scids), the last batch is
+        // full and still ends the sync
+        let expected = [
+            (100, 1, 0, vec![(100, 1), (100, 2)]),
+            (101, 2, 0, vec![(101, 1)]),
+            (103, 7, 1, vec![(103, 1), (103, 2)]),
+        ];
+        assert_eq!(replies.len(), expected.len());
+        for (reply, (first, number, sync_complete, scids)) in replies.iter().zip(expected) {
+            // roundtrip through the wire format so the encoding is checked too
+            let bytes = reply.to_bytes();
+            let reply = ReplyChannelRangeMessage::from_bytes(&bytes).unwrap().0;
+            assert_eq!(reply.first_blocknum, first);
+            assert_eq!(reply.number_of_blocks, number);
+            assert_eq!(reply.sync_complete, sync_complete);
+            let got: Vec<(u32, u32)> = reply
+                .short_channel_ids()
+                .unwrap()
+                .iter()
+                .map(|scid| (scid.block_height, scid.tx_index))
+                .collect();
+            assert_eq!(got, scids);
+            assert_eq!(reply.timestamps().unwrap().len(), scids.len());
+            assert_eq!(reply.checksums().unwrap().len(), scids.len());
+        }
+        assert_eq!(replies[0].timestamps().unwrap()[0], [0, timestamp]);
+        assert_eq!(replies[0].checksums().unwrap()[0], [0, checksum]);
+        // and they pass our own continuity checks
+        let mut sync = ChannelRangeSync::new(&query);
+        let done: Vec<bool> = replies
+            .iter()
+            .map(|reply| sync.add_reply(reply).unwrap())
+            .collect();
+        assert_eq!(done, vec![false, false, true]);
+        assert_eq!(sync.short_channel_ids().len(), 5);
+
+        // without query_option there are no tlvs, and an empty range still gets one
+        // reply covering all of it
+        let replies =
+            graph.channel_range_replies(&QueryChannelRangeMessage::new(chain_hash, 0, 50), 2);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].first_blocknum, 0);
+        assert_eq!(replies[0].number_of_blocks, 50);
+        assert_eq!(replies[0].sync_complete, 1);
+        assert_eq!(replies[0].short_channel_ids().unwrap().len(), 0);
+        assert!(replies[0].timestamps().is_none());
+        assert!(replies[0].checksums().is_none());
+    }
+
+    #[test]
+    fn test_channels_in_blocks() {
+        let mut graph = ChannelGraph::new();
+        for (block_height, tx_index) in [(102, 1), (100, 2), (101, 1), (100, 1), (99, 9)] {
+            let mut announcement = channel_announcement();
+            announcement.short_channel_id.block_height = block_height;
+            announcement.short_channel_id.tx_index = tx_index;
+            assert!(graph.add_channel_announcement(announcement));
+        }
+        let scids = |first, end| -> Vec<(u32, u32)> {
+            graph
+                .channels_in_blocks(first, end)
+                .iter()
+                .map(|edge| {
+                    let scid = &edge.announcement.short_channel_id;
+                    (scid.block_height, scid.tx_index)
+                })
+                .collect()
+        };
+        assert_eq!(scids(100, 102), vec![(100, 1), (100, 2), (101, 1)]);
+        assert_eq!(scids(102, 103), vec![(102, 1)]);
+        assert_eq!(scids(0, u32::MAX as u64 + 1).len(), 5);
+        assert!(scids(101, 101).is_empty());
+        assert!(scids(u32::MAX, u32::MAX as u64 + 1).is_empty());
+    }
+
+    #[test]
+    fn test_channel_update_checksum_ignores_timestamp() {
+        let update = channel_update(1, 10_000_000_000);
+        let mut later = update.clone();
+        later.timestamp += 1;
+        assert_eq!(update.checksum(), later.checksum());
+        later.htlc_maximum_msat = Some(10_000_000_001);
+        assert_ne!(update.checksum(), later.checksum());
+    }
+
+    #[derive(Default)]
+    struct MockStore {
+        channels: Vec<ChannelEdge>,
+        nodes: Vec<NodeAnnouncementMessage>,
+        channel_puts: usize,
+    }
+
+    impl GossipStore for MockStore {
+        fn put_channel(&mut self, edge: ChannelEdge) {
+            self.channel_puts += 1;
+            let scid = edge.announcement.short_channel_id.clone();
+            self.channels
+                .retain(|e| e.announcement.short_channel_id != scid);
+            self.channels.push(edge);
+        }
+
+        fn get_channel(&self, short_channel_id: &ShortChannelIDElement) -> Option<ChannelEdge> {
+            self.channels
+                .iter()
+                .find(|e| &e.announcement.short_channel_id == short_channel_id)
+                .cloned()
+        }
+
+        fn iter_channels(&self) -> Box<dyn Iterator<Item = ChannelEdge> + '_> {
+            Box::new(self.channels.iter().cloned())
+        }
+
+        fn num_channels(&self) -> usize {
+            self.channels.len()
+        }
+
+        fn put_node(&mut self, node: NodeAnnouncementMessage) {
+            self.nodes.push(node);
+        }
+
+        fn get_node(&self, node_id: &PointElement) -> Option<NodeAnnouncementMessage> {
+            self.nodes.iter().find(|n| &n.node_id == node_id).cloned()
+        }
+
+        fn iter_nodes(&self) -> Box<dyn Iterator<Item = NodeAnnouncementMessage> + '_> {
+            Box::new(self.nodes.iter().cloned())
+        }
+
+        fn num_nodes(&self) -> usize {
+            self.nodes.len()
+        }
+    }
+
+    #[test]
+    fn test_validate_inconsistent_store() {
+        let mut store = MockStore::default();
+        let announcement = channel_announcement();
+        let node_id_2 = announcement.node_id_2.value;
+        // an update for another channel, written straight to the store behind the
+        // graph's back
+        let mut stray = channel_update(0, 1);
+        stray.short_channel_id.block_height += 1;
+        let stray_scid = stray.short_channel_id.clone();
+        let mut edge = ChannelEdge::new(announcement.clone());
+        edge.updates[0] = Some(stray);
+        store.put_channel(edge);
+        // the fixture announces node_id_1, plus a node with no channels
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        let node = NodeAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+        let mut lonely = node.clone();
+        lonely.node_id = PointElement { value: [3; 33] };
+        store.put_node(node);
+        store.put_node(lonely);
+        let graph = ChannelGraph::with_store(Box::new(store));
+
+        assert_eq!(
+            graph.validate(),
+            vec![
+                GraphInconsistency::ChannelWithoutNodeAnnouncement {
+                    short_channel_id: announcement.short_channel_id.clone(),
+                    node_id: node_id_2,
+                },
+                GraphInconsistency::UpdateWithoutAnnouncement {
+                    short_channel_id: stray_scid,
+                    direction: 0,
+                },
+                GraphInconsistency::NodeWithoutChannels { node_id: [3; 33] },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_node_channels() {
+        let first = channel_announcement();
+        let node_id = first.node_id_1.value;
+        let mut second = first.clone();
+        second.short_channel_id.tx_index += 1;
+        second.node_id_2.value = [3; 33];
+
+        let mut graph = ChannelGraph::new();
+        assert!(graph.node_channels(&node_id).is_empty());
+        graph.add_channel_announcement(first.clone());
+        graph.add_channel_announcement(second.clone());
+        // a duplicate announcement must not be indexed twice
+        graph.add_channel_announcement(first.clone());
+        let scids: Vec<ShortChannelIDElement> = graph
+            .node_channels(&node_id)
+            .into_iter()
+            .map(|announcement| announcement.short_channel_id)
+            .collect();
+        assert_eq!(
+            scids,
+            vec![
+                first.short_channel_id.clone(),
+                second.short_channel_id.clone()
+            ]
+        );
+        assert_eq!(graph.node_channels(&first.node_id_2.value).len(), 1);
+        assert_eq!(graph.node_channels(&[3; 33]).len(), 1);
+
+        // channels already in the store are indexed too
+        let store = MockStore {
+            channels: vec![ChannelEdge::new(first), ChannelEdge::new(second)],
+            ..MockStore::default()
+        };
+        let graph = ChannelGraph::with_store(Box::new(store));
+        assert_eq!(graph.node_channels(&node_id).len(), 2);
+    }
+
+    #[test]
+    fn test_graph_with_custom_store() {
+        let mut graph = ChannelGraph::with_store(Box::new(MockStore::default()));
+        let announcement = channel_announcement();
+        let scid = announcement.short_channel_id.clone();
+        assert!(graph.add_channel_announcement(announcement.clone()));
+        assert!(!graph.add_channel_announcement(announcement));
+        assert!(graph.add_channel_update(channel_update(1, 10_000_000_000)));
+        // an older update for the same direction is ignored
+        let mut stale = channel_update(1, 1);
+        stale.timestamp -= 1;
+        assert!(!graph.add_channel_update(stale));
+        assert_eq!(graph.num_channels(), 1);
+        assert_eq!(
+            graph.get_channel(&scid).unwrap().inferred_capacity_msat(),
+            Some(10_000_000_000)
+        );
+    }
+}
+
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use super::*;
+    use crate::messages::MessageType;
+    use crate::serialization::SerializableToBytes;
+    use crate::test_util::example_message;
+    use test::Bencher;
+
+    // roughly mainnet sized: 10 channels in each of 10_000 blocks
+    fn large_graph() -> ChannelGraph {
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let announcement = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+        let mut graph = ChannelGraph::new();
+        for block_height in 700_000..710_000 {
+            for tx_index in 0..10 {
+                let mut announcement = announcement.clone();
+                announcement.short_channel_id.block_height = block_height;
+                announcement.short_channel_id.tx_index = tx_index;
+                graph.add_channel_announcement(announcement);
+            }
+        }
+        graph
+    }
+
+    #[bench]
+    fn bench_channels_in_blocks(b: &mut Bencher) {
+        let graph = large_graph();
+        // 100 blocks out of the middle, 1000 channels
+        b.iter(|| graph.channels_in_blocks(705_000, 705_100).len());
+    }
+
+    #[bench]
+    fn bench_has_channel(b: &mut Bencher) {
+        let graph = large_graph();
+        let scid = graph.channels_in_blocks(705_000, 705_001)[0]
+            .announcement
+            .short_channel_id
+            .clone();
+        b.iter(|| graph.has_channel(test::black_box(&scid)));
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+
+use crate::{
+    messages::{QueryChannelRangeMessage, ReplyChannelRangeMessage},
+    serialization::ShortChannelIDElement,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum ChannelRangeSyncError {
+    WrongChain,
+    // compressed or malformed encoded_short_ids
+    InvalidEncoding,
+    // a reply starting past the end of what earlier replies covered
+    Gap { expected: u64, first_blocknum: u32 },
+    // a reply starting inside what earlier replies covered, or a first reply that ends
+    // before the query starts
+    Overlap { expected: u64, first_blocknum: u32 },
+}
+
+impl fmt::Display for ChannelRangeSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelRangeSyncError::WrongChain => write!(f, "reply for another chain"),
+            ChannelRangeSyncError::InvalidEncoding => {
+                write!(f, "unsupported short_channel_id encoding")
+            }
+            ChannelRangeSyncError::Gap {
+                expected,
+                first_blocknum,
+            } => write!(
+                f,
+                "gap in channel range, expected block {} got {}",
+                expected, first_blocknum
+            ),
+            ChannelRangeSyncError::Overlap {
+                expected,
+                first_blocknum,
+            } => write!(
+                f,
+                "overlap in channel range, expected block {} got {}",
+                expected, first_blocknum
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChannelRangeSyncError {}
+
+// Collects the reply_channel_range fragments answering one query_channel_range and
+// checks that they tile the queried range. A fragment may start on the last block of
+// the previous one, that is how a block with too many channels for one reply is split.
+pub struct ChannelRangeSync {
+    chain_hash: [u8; 32],
+    end: u64,
+    // one past the last block covered so far
+    covered_end: u64,
+    received_first: bool,
+    short_channel_ids: Vec<ShortChannelIDElement>,
+}
+
+impl ChannelRangeSync {
+    pub fn new(query: &QueryChannelRangeMessage) -> Self {
+        ChannelRangeSync {
+            chain_hash: query.chain_hash.value,
+            end: query.end_blocknum(),
+            covered_end: query.first_blocknum as u64,
+            received_first: false,
+            short_channel_ids: Vec::new(),
+        }
+    }
+
+    // returns true once the replies cover the queried range or the peer says it is done
+    pub fn add_reply(
+        &mut self,
+        reply: &ReplyChannelRangeMessage,
+    ) -> Result<bool, ChannelRangeSyncError> {
+        if reply.chain_hash.value != self.chain_hash {
+            return Err(ChannelRangeSyncError::WrongChain);
+        }
+        let first = reply.first_blocknum as u64;
+        let reply_end = first + reply.number_of_blocks as u64;
+        if first > self.covered_end {
+            return Err(ChannelRangeSyncError::Gap {
+                expected: self.covered_end,
+                first_blocknum: reply.first_blocknum,
+            });
+        }
+        // the first reply may start before the query, but has to reach into it
+        let overlaps = if self.received_first {
+            first + 1 < self.covered_end
+        } else {
+            reply_end <= self.covered_end
+        };
+        if overlaps {
+            return Err(ChannelRangeSyncError::Overlap {
+                expected: self.covered_end,
+                first_blocknum: reply.first_blocknum,
+            });
+        }
+        let short_channel_ids = reply
+            .short_channel_ids()
+            .ok_or(ChannelRangeSyncError::InvalidEncoding)?;
+        self.short_channel_ids.extend(short_channel_ids);
+        self.received_first = true;
+        self.covered_end = self.covered_end.max(reply_end);
+        Ok(reply.sync_complete == 1 || self.covered_end >= self.end)
+    }
+
+    pub fn short_channel_ids(&self) -> &[ShortChannelIDElement] {
+        &self.short_channel_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN_HASH: [u8; 32] = [6; 32];
+
+    fn scid(block_height: u32) -> ShortChannelIDElement {
+        ShortChannelIDElement {
+            block_height,
+            tx_index: 1,
+            output_index: 0,
+        }
+    }
+
+    fn reply(
+        first: u32,
+        number: u32,
+        sync_complete: bool,
+        blocks: &[u32],
+    ) -> ReplyChannelRangeMessage {
+        let short_channel_ids: Vec<ShortChannelIDElement> =
+            blocks.iter().map(|block| scid(*block)).collect();
+        ReplyChannelRangeMessage::new(
+            CHAIN_HASH,
+            first,
+            number,
+            sync_complete,
+            &short_channel_ids,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_fragments_tiling_the_range() {
+        let mut sync = ChannelRangeSync::new(&QueryChannelRangeMessage::new(CHAIN_HASH, 100, 10));
+        assert_eq!(sync.add_reply(&reply(90, 11, false, &[100])), Ok(false));
+        // continues the split block 100
+        assert_eq!(
+            sync.add_reply(&reply(100, 3, false, &[100, 102])),
+            Ok(false)
+        );
+        // covering the range ends the sync even without sync_complete
+        assert_eq!(sync.add_reply(&reply(103, 7, false, &[105])), Ok(true));
+        let blocks: Vec<u32> = sync
+            .short_channel_ids()
+            .iter()
+            .map(|scid| scid.block_height)
+            .collect();
+        assert_eq!(blocks, vec![100, 100, 102, 105]);
+    }
+
+    #[test]
+    fn test_gap_between_fragments() {
+        let mut sync = ChannelRangeSync::new(&QueryChannelRangeMessage::new(CHAIN_HASH, 100, 10));
+        assert_eq!(sync.add_reply(&reply(100, 2, false, &[101])), Ok(false));
+        assert_eq!(
+            sync.add_reply(&reply(104, 6, true, &[105])),
+            Err(ChannelRangeSyncError::Gap {
+                expected: 102,
+                first_blocknum: 104
+            })
+        );
+        assert_eq!(sync.short_channel_ids().len(), 1);
+    }
+
+    #[test]
+    fn test_overlapping_and_foreign_fragments() {
+        let mut sync = ChannelRangeSync::new(&QueryChannelRangeMessage::new(CHAIN_HASH, 100, 10));
+        // entirely before the queried range
+        assert!(matches!(
+            sync.add_reply(&reply(90, 10, false, &[])),
+            Err(ChannelRangeSyncError::Overlap { .. })
+        ));
+        assert_eq!(sync.add_reply(&reply(100, 5, false, &[])), Ok(false));
+        assert_eq!(
+            sync.add_reply(&reply(102, 8, true, &[])),
+            Err(ChannelRangeSyncError::Overlap {
+                expected: 105,
+                first_blocknum: 102
+            })
+        );
+        let other_chain = ReplyChannelRangeMessage::new([7; 32], 105, 5, true, &[], None, None);
+        assert_eq!(
+            sync.add_reply(&other_chain),
+            Err(ChannelRangeSyncError::WrongChain)
+        );
+        assert_eq!(sync.add_reply(&reply(105, 5, true, &[])), Ok(true));
+    }
+}
@@ -1,2 +1,147 @@
+use std::collections::HashSet;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::messages::MessageType;
+
 pub const DO_CONNECT_TO_NEW_NODES: bool = false;
 pub const PING_INTERVAL: u64 = 60;
+// node_announcement addresses parsed per address type, anything beyond is rejected
+pub const MAX_ADDRESSES_PER_TYPE: usize = 100;
+// a reply_channel_range with both tlvs is 55 bytes plus 24 per scid (the scid, two
+// timestamps and two checksums), the default is as many as fit in one 65535 byte message
+const REPLY_CHANNEL_RANGE_OVERHEAD: usize = 46 + 5 + 4;
+const REPLY_CHANNEL_RANGE_BYTES_PER_SCID: usize = 8 + 8 + 8;
+pub const MAX_SHORT_CHANNEL_IDS_PER_REPLY: usize =
+    (65535 - REPLY_CHANNEL_RANGE_OVERHEAD) / REPLY_CHANNEL_RANGE_BYTES_PER_SCID;
+// regtest genesis hash, as used by polar
+pub const CHAIN_HASH: &str = "06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f";
+// comma separated wire names, e.g. channel_update,node_announcement
+pub const LOG_TYPES_ENV: &str = "LNMINIPEER_LOG_TYPES";
+// if set, points that aren't valid public keys fail to decode instead of failing later
+pub const STRICT_POINTS_ENV: &str = "LNMINIPEER_STRICT_POINTS";
+
+// where PeerConfig::json_stream writes its lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonStream {
+    Stdout,
+    // appended to, so restarts keep earlier lines
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    // features and networks we advertise in our init message
+    pub global_features: Vec<u8>,
+    pub local_features: Vec<u8>,
+    pub networks: Vec<[u8; 32]>,
+    // the chain we want gossip for
+    pub chain_hash: [u8; 32],
+    // drop peers whose init networks do not include chain_hash, instead of just not querying them
+    pub disconnect_other_chains: bool,
+    // if set, inbound peers whose static key is not listed are rejected
+    pub allowed_peers: Option<HashSet<[u8; 33]>>,
+    // if set, peers that advertised gossip_queries and have sent no gossip for this long
+    // are asked for their full channel range again
+    pub gossip_resync_interval: Option<Duration>,
+    // set TCP_NODELAY on every connection
+    pub tcp_nodelay: bool,
+    // keep announcements and updates in the graph, off for probes that only connect and
+    // ping so memory stays flat
+    pub store_gossip: bool,
+    // query the full channel range of every gossip_queries peer as soon as its init
+    // arrives, instead of waiting for it to send us gossip
+    pub proactive_sync: bool,
+    // the gossip window we ask every gossip_queries peer for right after init, as
+    // (first_timestamp, timestamp_range). BOLT 7 peers send no gossip until they get a
+    // filter, so None means we receive none
+    pub gossip_timestamp_filter: Option<(u32, u32)>,
+    // gossip sink: only record what peers send us. Nothing but init is ever sent
+    // unprompted, no filters, queries, resyncs or dials of announced nodes
+    pub passive_mirror: bool,
+    // most scids per reply_channel_range we send, larger than the default risks replies
+    // over the message size limit
+    pub max_short_channel_ids_per_reply: usize,
+    // decode points strictly, messages with a point that isn't a valid public key fail to
+    // decode instead of failing wherever the point is used
+    pub strict_points: bool,
+    // if set, only these inbound message types are logged, all of them are still handled
+    pub log_message_types: Option<HashSet<MessageType>>,
+    // if set, every decoded inbound message is also written as one json object per line
+    pub json_stream: Option<JsonStream>,
+    // total time a connection attempt gets for dialing, the handshake and the init
+    // exchange before it is abandoned
+    pub connect_budget: Duration,
+    // ping a connection that has been quiet this long
+    pub ping_interval: Duration,
+    // drop a connection whose ping has gone unanswered this long
+    pub ping_timeout: Duration,
+    // sync_channel_range gives up once the peer has sent nothing for this long
+    pub channel_range_reply_timeout: Duration,
+    // how long a channel_update waits for its channel_announcement before it is dropped
+    pub orphan_update_window: Duration,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        PeerConfig {
+            global_features: Vec::new(),
+            // optional data_loss_protect, initial_routing_sync, upfront_shutdown_script, gossip_queries
+            local_features: vec![0xaa],
+            networks: Vec::new(),
+            chain_hash: hex::decode(CHAIN_HASH).unwrap().try_into().unwrap(),
+            disconnect_other_chains: false,
+            allowed_peers: None,
+            gossip_resync_interval: None,
+            tcp_nodelay: true,
+            store_gossip: true,
+            proactive_sync: false,
+            gossip_timestamp_filter: Some((0, u32::MAX)),
+            max_short_channel_ids_per_reply: MAX_SHORT_CHANNEL_IDS_PER_REPLY,
+            passive_mirror: false,
+            strict_points: env::var(STRICT_POINTS_ENV).is_ok(),
+            log_message_types: env::var(LOG_TYPES_ENV)
+                .ok()
+                .map(|types| parse_message_types(&types)),
+            json_stream: None,
+            connect_budget: Duration::from_secs(15),
+            ping_interval: Duration::from_secs(PING_INTERVAL),
+            ping_timeout: Duration::from_secs(30),
+            channel_range_reply_timeout: Duration::from_secs(30),
+            orphan_update_window: Duration::from_secs(600),
+        }
+    }
+}
+
+// unknown names are reported and skipped rather than failing startup
+pub fn parse_message_types(types: &str) -> HashSet<MessageType> {
+    types
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let message_type = MessageType::from_wire_name(name);
+            if message_type.is_none() {
+                eprintln!(
+                    "Ignoring unknown message type in {}: {}",
+                    LOG_TYPES_ENV, name
+                );
+            }
+            message_type
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message_types() {
+        assert_eq!(
+            parse_message_types("channel_update, node_announcement,,bogus"),
+            HashSet::from([MessageType::ChannelUpdate, MessageType::NodeAnnouncement])
+        );
+    }
+}
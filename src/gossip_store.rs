@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::{
+    channel_graph::ChannelEdge,
+    messages::NodeAnnouncementMessage,
+    serialization::{PointElement, ShortChannelIDElement},
+};
+
+// Storage backend for gossip. Values are returned owned so that backends which
+// serialize to disk (sled, rocksdb, ...) can implement this without holding borrows.
+pub trait GossipStore: Send {
+    fn put_channel(&mut self, edge: ChannelEdge);
+    fn get_channel(&self, short_channel_id: &ShortChannelIDElement) -> Option<ChannelEdge>;
+    fn iter_channels(&self) -> Box<dyn Iterator<Item = ChannelEdge> + '_>;
+    fn num_channels(&self) -> usize;
+    fn put_node(&mut self, node: NodeAnnouncementMessage);
+    fn get_node(&self, node_id: &PointElement) -> Option<NodeAnnouncementMessage>;
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = NodeAnnouncementMessage> + '_>;
+    fn num_nodes(&self) -> usize;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryGossipStore {
+    channels: HashMap<ShortChannelIDElement, ChannelEdge>,
+    nodes: HashMap<PointElement, NodeAnnouncementMessage>,
+}
+
+impl MemoryGossipStore {
+    pub fn new() -> Self {
+        MemoryGossipStore::default()
+    }
+}
+
+impl GossipStore for MemoryGossipStore {
+    fn put_channel(&mut self, edge: ChannelEdge) {
+        self.channels
+            .insert(edge.announcement.short_channel_id.clone(), edge);
+    }
+
+    fn get_channel(&self, short_channel_id: &ShortChannelIDElement) -> Option<ChannelEdge> {
+        self.channels.get(short_channel_id).cloned()
+    }
+
+    fn iter_channels(&self) -> Box<dyn Iterator<Item = ChannelEdge> + '_> {
+        Box::new(self.channels.values().cloned())
+    }
+
+    fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn put_node(&mut self, node: NodeAnnouncementMessage) {
+        self.nodes.insert(node.node_id.clone(), node);
+    }
+
+    fn get_node(&self, node_id: &PointElement) -> Option<NodeAnnouncementMessage> {
+        self.nodes.get(node_id).cloned()
+    }
+
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = NodeAnnouncementMessage> + '_> {
+        Box::new(self.nodes.values().cloned())
+    }
+
+    fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+}
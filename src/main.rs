@@ -1,30 +1,65 @@
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
+use bitcoin::secp256k1::PublicKey;
+use config::PeerConfig;
 use node::Node;
 use peer::MiniPeer;
+use tokio::net::TcpListener;
 
-use crate::util::new_random_secret_key;
+use crate::util::{new_random_secret_key, SECP};
 
 use std::env;
 
+mod channel_graph;
+mod channel_range_sync;
 mod config;
+mod gossip_store;
 mod message_decoder;
 mod messages;
 mod node;
 mod node_connection;
+mod node_profile;
 mod peer;
+mod peer_handle;
+mod repl;
 mod serialization;
+#[cfg(test)]
+mod test_util;
 mod util;
 mod vendor;
 
 #[tokio::main]
 async fn main() {
-    let mut peer = MiniPeer::new(new_random_secret_key());
-
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: lmprs2 <node_address_1> ... <node_address_n>");
+    let mut args: Vec<String> = env::args().collect();
+    let interactive = args.iter().any(|arg| arg == "--interactive");
+    let passive = args.iter().any(|arg| arg == "--passive");
+    args.retain(|arg| arg != "--interactive" && arg != "--passive");
+    let listen = match args.iter().position(|arg| arg == "--listen") {
+        Some(index) if index + 1 < args.len() => {
+            let address = args.remove(index + 1);
+            args.remove(index);
+            Some(address)
+        }
+        Some(_) => {
+            eprintln!("--listen needs an address, e.g. --listen 0.0.0.0:9735");
+            return;
+        }
+        None => None,
+    };
+    if args.len() < 2 && !interactive && listen.is_none() {
+        println!(
+            "Usage: lmprs2 [--interactive] [--passive] [--listen <address>] <node_address_1> ... <node_address_n>"
+        );
         return;
     }
 
+    let secret_key = new_random_secret_key();
+    let config = PeerConfig {
+        passive_mirror: passive,
+        ..PeerConfig::default()
+    };
+    let mut peer = MiniPeer::with_config(secret_key, config);
+
     let mut nodes = Vec::new();
     for arg in args.iter().skip(1) {
         let node_str = arg;
@@ -35,6 +70,10 @@ async fn main() {
                 continue;
             }
         };
+        if nodes.contains(&node) {
+            println!("Skipping duplicate node {}", node.display_str());
+            continue;
+        }
         nodes.push(node);
     }
 
@@ -45,9 +84,26 @@ async fn main() {
             Err(e) => eprintln!("Error connecting to node {:?}: {:?}", node.display_str(), e),
         }
     }
+    if interactive {
+        repl::run(peer).await;
+        return;
+    }
     if peer.num_connections() == nodes.len() {
         println!("Successfully connected to all nodes");
     }
+    if let Some(address) = listen {
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error listening on {}: {:?}", address, e);
+                return;
+            }
+        };
+        let public_key = PublicKey::from_secret_key(&*SECP, &secret_key).serialize();
+        println!("Listening on {} as {}", address, hex::encode(public_key));
+        peer.serve(listener).await;
+        return;
+    }
     if peer.num_connections() > 0 {
         println!("Connected to {} nodes", peer.num_connections());
         peer.event_loop().await;
@@ -1,149 +1,400 @@
 use crate::messages::{
-    ChannelAnnouncementMessage, ChannelUpdateMessage, GossipTimestampFilterMessage, InitMessage,
-    MessageType, NodeAnnouncementMessage, PingMessage, PongMessage, QueryChannelRangeMessage,
-    ReplyChannelRangeMessage, UnknownMessage,
+    AcceptChannelMessage, ChannelAnnouncementMessage, ChannelReadyMessage, ChannelUpdateMessage,
+    ClosingSignedMessage, ErrorMessage, FundingCreatedMessage, FundingSignedMessage,
+    GossipTimestampFilterMessage, InitMessage, MessageType, NodeAnnouncementMessage,
+    OpenChannelMessage, PingMessage, PongMessage, QueryChannelRangeMessage,
+    QueryShortChannelIdsMessage, ReplyChannelRangeMessage, ReplyShortChannelIdsEndMessage,
+    StfuMessage, TLVStreamMessage, TxAckRbfMessage, TxInitRbfMessage, UnknownMessage,
+    UpdateAddHTLCMessage, UpdateFailHTLCMessage, UpdateFailMalformedHTLCMessage,
+    UpdateFulfillHTLCMessage, WarningMessage,
 };
 use crate::serialization::MessageTypeElement;
-use crate::serialization::SerializableToBytes;
+use crate::serialization::{SerializableToBytes, SerializationError, TLVRecord};
+use std::fmt;
+use strum::IntoEnumIterator;
 
 #[derive(Debug)]
 pub enum MessageDecoderError {
-    Error,
+    InvalidHex(hex::FromHexError),
+    SerializationError(SerializationError),
+    TooFewBytes {
+        message_type: MessageType,
+        min_size: usize,
+        actual: usize,
+    },
 }
 
-#[derive(Debug)]
+impl From<SerializationError> for MessageDecoderError {
+    fn from(err: SerializationError) -> Self {
+        MessageDecoderError::SerializationError(err)
+    }
+}
+
+impl fmt::Display for MessageDecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageDecoderError::InvalidHex(_) => write!(f, "invalid hex"),
+            MessageDecoderError::SerializationError(_) => write!(f, "failed to decode message"),
+            MessageDecoderError::TooFewBytes {
+                message_type,
+                min_size,
+                actual,
+            } => write!(
+                f,
+                "{} needs at least {} bytes, got {}",
+                message_type.wire_name(),
+                min_size,
+                actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MessageDecoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MessageDecoderError::InvalidHex(err) => Some(err),
+            MessageDecoderError::SerializationError(err) => Some(err),
+            MessageDecoderError::TooFewBytes { .. } => None,
+        }
+    }
+}
+
+// smallest valid encoding of each implemented type, including the 2-byte type
+const MIN_MESSAGE_SIZES: &[(MessageType, usize)] = &[
+    (MessageType::Init, 6),
+    (MessageType::Warning, 36),
+    (MessageType::Error, 36),
+    (MessageType::Ping, 6),
+    (MessageType::Pong, 4),
+    (MessageType::OpenChannel, 321),
+    (MessageType::AcceptChannel, 272),
+    (MessageType::FundingCreated, 132),
+    (MessageType::FundingSigned, 98),
+    (MessageType::ChannelReady, 67),
+    (MessageType::ClosingSigned, 106),
+    (MessageType::Stfu, 35),
+    (MessageType::TxInitRbf, 42),
+    (MessageType::TxAckRbf, 34),
+    (MessageType::UpdateAddHTLC, 1452),
+    (MessageType::UpdateFulfillHTLC, 74),
+    (MessageType::UpdateFailHTLC, 44),
+    (MessageType::UpdateFailMalformedHTLC, 76),
+    (MessageType::ChannelAnnouncement, 432),
+    (MessageType::NodeAnnouncement, 142),
+    // htlc_maximum_msat is optional
+    (MessageType::ChannelUpdate, 130),
+    (MessageType::QueryChannelRange, 42),
+    (MessageType::ReplyChannelRange, 45),
+    (MessageType::QueryShortChannelIds, 36),
+    (MessageType::ReplyShortChannelIdsEnd, 35),
+    (MessageType::GossipTimestampFilter, 42),
+];
+
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub enum MessageContainer {
+    Warning(WarningMessage),
     Init(InitMessage),
+    Error(ErrorMessage),
     Ping(PingMessage),
     Pong(PongMessage),
+    OpenChannel(OpenChannelMessage),
+    AcceptChannel(AcceptChannelMessage),
+    FundingCreated(FundingCreatedMessage),
+    FundingSigned(FundingSignedMessage),
+    ChannelReady(ChannelReadyMessage),
+    ClosingSigned(ClosingSignedMessage),
+    Stfu(StfuMessage),
+    TxInitRbf(TxInitRbfMessage),
+    TxAckRbf(TxAckRbfMessage),
+    UpdateAddHTLC(UpdateAddHTLCMessage),
+    UpdateFulfillHTLC(UpdateFulfillHTLCMessage),
+    UpdateFailHTLC(UpdateFailHTLCMessage),
+    UpdateFailMalformedHTLC(UpdateFailMalformedHTLCMessage),
     ChannelAnnouncement(ChannelAnnouncementMessage),
     ChannelUpdate(ChannelUpdateMessage),
     NodeAnnouncement(NodeAnnouncementMessage),
     GossipTimestampFilter(GossipTimestampFilterMessage),
     QueryChannelRange(QueryChannelRangeMessage),
     ReplyChannelRange(ReplyChannelRangeMessage),
+    QueryShortChannelIds(QueryShortChannelIdsMessage),
+    ReplyShortChannelIdsEnd(ReplyShortChannelIdsEndMessage),
     Unknown(UnknownMessage),
 }
 
 impl MessageContainer {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            MessageContainer::Warning(_) => MessageType::Warning,
+            MessageContainer::Init(_) => MessageType::Init,
+            MessageContainer::Error(_) => MessageType::Error,
+            MessageContainer::Ping(_) => MessageType::Ping,
+            MessageContainer::Pong(_) => MessageType::Pong,
+            MessageContainer::OpenChannel(_) => MessageType::OpenChannel,
+            MessageContainer::AcceptChannel(_) => MessageType::AcceptChannel,
+            MessageContainer::FundingCreated(_) => MessageType::FundingCreated,
+            MessageContainer::FundingSigned(_) => MessageType::FundingSigned,
+            MessageContainer::ChannelReady(_) => MessageType::ChannelReady,
+            MessageContainer::ClosingSigned(_) => MessageType::ClosingSigned,
+            MessageContainer::Stfu(_) => MessageType::Stfu,
+            MessageContainer::TxInitRbf(_) => MessageType::TxInitRbf,
+            MessageContainer::TxAckRbf(_) => MessageType::TxAckRbf,
+            MessageContainer::UpdateAddHTLC(_) => MessageType::UpdateAddHTLC,
+            MessageContainer::UpdateFulfillHTLC(_) => MessageType::UpdateFulfillHTLC,
+            MessageContainer::UpdateFailHTLC(_) => MessageType::UpdateFailHTLC,
+            MessageContainer::UpdateFailMalformedHTLC(_) => MessageType::UpdateFailMalformedHTLC,
+            MessageContainer::ChannelAnnouncement(_) => MessageType::ChannelAnnouncement,
+            MessageContainer::NodeAnnouncement(_) => MessageType::NodeAnnouncement,
+            MessageContainer::GossipTimestampFilter(_) => MessageType::GossipTimestampFilter,
+            MessageContainer::QueryChannelRange(_) => MessageType::QueryChannelRange,
+            MessageContainer::ReplyChannelRange(_) => MessageType::ReplyChannelRange,
+            MessageContainer::QueryShortChannelIds(_) => MessageType::QueryShortChannelIds,
+            MessageContainer::ReplyShortChannelIdsEnd(_) => MessageType::ReplyShortChannelIdsEnd,
+            MessageContainer::ChannelUpdate(_) => MessageType::ChannelUpdate,
+            MessageContainer::Unknown(message) => {
+                MessageType::from_int(message.type_id).unwrap_or(MessageType::Unknown)
+            }
+        }
+    }
+
+    // TLV records of types the message doesn't define, empty for messages without a
+    // TLV stream
+    pub fn unknown_tlvs(&self) -> Vec<TLVRecord> {
+        match self {
+            MessageContainer::Init(message) => message.unknown_tlvs(),
+            MessageContainer::OpenChannel(message) => message.unknown_tlvs(),
+            MessageContainer::AcceptChannel(message) => message.unknown_tlvs(),
+            MessageContainer::ChannelReady(message) => message.unknown_tlvs(),
+            MessageContainer::ClosingSigned(message) => message.unknown_tlvs(),
+            MessageContainer::TxInitRbf(message) => message.unknown_tlvs(),
+            MessageContainer::TxAckRbf(message) => message.unknown_tlvs(),
+            MessageContainer::UpdateAddHTLC(message) => message.unknown_tlvs(),
+            MessageContainer::QueryChannelRange(message) => message.unknown_tlvs(),
+            MessageContainer::ReplyChannelRange(message) => message.unknown_tlvs(),
+            MessageContainer::QueryShortChannelIds(message) => message.unknown_tlvs(),
+            _ => Vec::new(),
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             MessageContainer::Init(message) => message.to_bytes(),
+            MessageContainer::Warning(message) => message.to_bytes(),
+            MessageContainer::Error(message) => message.to_bytes(),
             MessageContainer::Ping(message) => message.to_bytes(),
             MessageContainer::Pong(message) => message.to_bytes(),
+            MessageContainer::OpenChannel(message) => message.to_bytes(),
+            MessageContainer::AcceptChannel(message) => message.to_bytes(),
+            MessageContainer::FundingCreated(message) => message.to_bytes(),
+            MessageContainer::FundingSigned(message) => message.to_bytes(),
+            MessageContainer::ChannelReady(message) => message.to_bytes(),
+            MessageContainer::ClosingSigned(message) => message.to_bytes(),
+            MessageContainer::Stfu(message) => message.to_bytes(),
+            MessageContainer::TxInitRbf(message) => message.to_bytes(),
+            MessageContainer::TxAckRbf(message) => message.to_bytes(),
+            MessageContainer::UpdateAddHTLC(message) => message.to_bytes(),
+            MessageContainer::UpdateFulfillHTLC(message) => message.to_bytes(),
+            MessageContainer::UpdateFailHTLC(message) => message.to_bytes(),
+            MessageContainer::UpdateFailMalformedHTLC(message) => message.to_bytes(),
             MessageContainer::ChannelAnnouncement(message) => message.to_bytes(),
             MessageContainer::NodeAnnouncement(message) => message.to_bytes(),
             MessageContainer::GossipTimestampFilter(message) => message.to_bytes(),
             MessageContainer::QueryChannelRange(message) => message.to_bytes(),
             MessageContainer::ReplyChannelRange(message) => message.to_bytes(),
+            MessageContainer::QueryShortChannelIds(message) => message.to_bytes(),
+            MessageContainer::ReplyShortChannelIdsEnd(message) => message.to_bytes(),
             MessageContainer::ChannelUpdate(message) => message.to_bytes(),
             MessageContainer::Unknown(message) => message.to_bytes(),
         }
     }
 }
 
+type DecodeFn = fn(&[u8]) -> Result<(MessageContainer, &[u8]), SerializationError>;
+
 pub struct MessageDecoder {}
 
 impl MessageDecoder {
-    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageContainer, &[u8]), MessageDecoderError> {
-        let (message_type_struct, _) = match MessageTypeElement::from_bytes(bytes) {
-            Ok(message_type) => message_type,
-            Err(_) => return Err(MessageDecoderError::Error),
-        };
-        let message_type = MessageType::from_int(message_type_struct.id).unwrap();
+    // single source of truth for which message types have a real parser
+    fn decode_fn(message_type: MessageType) -> Option<DecodeFn> {
         match message_type {
-            MessageType::Init => {
-                let (message, data) = match InitMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            MessageType::Warning => Some(|bytes| {
+                let (message, data) = WarningMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::Warning(message), data))
+            }),
+            MessageType::Init => Some(|bytes| {
+                let (message, data) = InitMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::Init(message), data))
-            }
-            MessageType::Ping => {
-                let (message, data) = match PingMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::Error => Some(|bytes| {
+                let (message, data) = ErrorMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::Error(message), data))
+            }),
+            MessageType::Ping => Some(|bytes| {
+                let (message, data) = PingMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::Ping(message), data))
-            }
-            MessageType::Pong => {
-                let (message, data) = match PongMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::Pong => Some(|bytes| {
+                let (message, data) = PongMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::Pong(message), data))
-            }
-            MessageType::ChannelAnnouncement => {
-                let (message, data) = match ChannelAnnouncementMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::OpenChannel => Some(|bytes| {
+                let (message, data) = OpenChannelMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::OpenChannel(message), data))
+            }),
+            MessageType::AcceptChannel => Some(|bytes| {
+                let (message, data) = AcceptChannelMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::AcceptChannel(message), data))
+            }),
+            MessageType::FundingCreated => Some(|bytes| {
+                let (message, data) = FundingCreatedMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::FundingCreated(message), data))
+            }),
+            MessageType::FundingSigned => Some(|bytes| {
+                let (message, data) = FundingSignedMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::FundingSigned(message), data))
+            }),
+            MessageType::ChannelReady => Some(|bytes| {
+                let (message, data) = ChannelReadyMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::ChannelReady(message), data))
+            }),
+            MessageType::ClosingSigned => Some(|bytes| {
+                let (message, data) = ClosingSignedMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::ClosingSigned(message), data))
+            }),
+            MessageType::Stfu => Some(|bytes| {
+                let (message, data) = StfuMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::Stfu(message), data))
+            }),
+            MessageType::TxInitRbf => Some(|bytes| {
+                let (message, data) = TxInitRbfMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::TxInitRbf(message), data))
+            }),
+            MessageType::TxAckRbf => Some(|bytes| {
+                let (message, data) = TxAckRbfMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::TxAckRbf(message), data))
+            }),
+            MessageType::UpdateAddHTLC => Some(|bytes| {
+                let (message, data) = UpdateAddHTLCMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::UpdateAddHTLC(message), data))
+            }),
+            MessageType::UpdateFulfillHTLC => Some(|bytes| {
+                let (message, data) = UpdateFulfillHTLCMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::UpdateFulfillHTLC(message), data))
+            }),
+            MessageType::UpdateFailHTLC => Some(|bytes| {
+                let (message, data) = UpdateFailHTLCMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::UpdateFailHTLC(message), data))
+            }),
+            MessageType::UpdateFailMalformedHTLC => Some(|bytes| {
+                let (message, data) = UpdateFailMalformedHTLCMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::UpdateFailMalformedHTLC(message), data))
+            }),
+            MessageType::ChannelAnnouncement => Some(|bytes| {
+                let (message, data) = ChannelAnnouncementMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::ChannelAnnouncement(message), data))
-            }
-            MessageType::NodeAnnouncement => {
-                let (message, data) = match NodeAnnouncementMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::NodeAnnouncement => Some(|bytes| {
+                let (message, data) = NodeAnnouncementMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::NodeAnnouncement(message), data))
-            }
-            MessageType::GossipTimestampFilter => {
-                let (message, data) = match GossipTimestampFilterMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::GossipTimestampFilter => Some(|bytes| {
+                let (message, data) = GossipTimestampFilterMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::GossipTimestampFilter(message), data))
-            }
-            MessageType::ReplyChannelRange => {
-                let (message, data) = match ReplyChannelRangeMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::ReplyChannelRange => Some(|bytes| {
+                let (message, data) = ReplyChannelRangeMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::ReplyChannelRange(message), data))
-            }
-            MessageType::QueryChannelRange => {
-                let (message, data) = match QueryChannelRangeMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::QueryChannelRange => Some(|bytes| {
+                let (message, data) = QueryChannelRangeMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::QueryChannelRange(message), data))
-            }
-            MessageType::ChannelUpdate => {
-                let (message, data) = match ChannelUpdateMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
+            }),
+            MessageType::QueryShortChannelIds => Some(|bytes| {
+                let (message, data) = QueryShortChannelIdsMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::QueryShortChannelIds(message), data))
+            }),
+            MessageType::ReplyShortChannelIdsEnd => Some(|bytes| {
+                let (message, data) = ReplyShortChannelIdsEndMessage::from_bytes(bytes)?;
+                Ok((MessageContainer::ReplyShortChannelIdsEnd(message), data))
+            }),
+            MessageType::ChannelUpdate => Some(|bytes| {
+                let (message, data) = ChannelUpdateMessage::from_bytes(bytes)?;
                 Ok((MessageContainer::ChannelUpdate(message), data))
+            }),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn supported_types() -> Vec<MessageType> {
+        MessageType::iter()
+            .filter(|message_type| Self::decode_fn(*message_type).is_some())
+            .collect()
+    }
+
+    pub fn min_size(message_type: MessageType) -> Option<usize> {
+        MIN_MESSAGE_SIZES
+            .iter()
+            .find(|(t, _)| *t == message_type)
+            .map(|(_, size)| *size)
+    }
+
+    // the type from the first two bytes without decoding the body, Unknown for ids the
+    // spec doesn't assign
+    pub fn peek_type(bytes: &[u8]) -> Result<MessageType, MessageDecoderError> {
+        let (message_type_struct, _) = MessageTypeElement::from_bytes(bytes)?;
+        Ok(MessageType::from_int(message_type_struct.id).unwrap_or(MessageType::Unknown))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageContainer, &[u8]), MessageDecoderError> {
+        let message_type = Self::peek_type(bytes)?;
+        let decode = match Self::decode_fn(message_type) {
+            Some(decode) => decode,
+            None => {
+                let (message, data) = UnknownMessage::from_bytes(bytes)?;
+                return Ok((MessageContainer::Unknown(message), data));
             }
-            _ => {
-                let (message, data) = match UnknownMessage::from_bytes(bytes) {
-                    Ok(x) => x,
-                    Err(_) => return Err(MessageDecoderError::Error),
-                };
-                Ok((MessageContainer::Unknown(message), data))
+        };
+        if let Some(min_size) = Self::min_size(message_type) {
+            if bytes.len() < min_size {
+                return Err(MessageDecoderError::TooFewBytes {
+                    message_type,
+                    min_size,
+                    actual: bytes.len(),
+                });
             }
         }
+        Ok(decode(bytes)?)
     }
 }
 
+// MessageDecoder::peek_type as an Option, None for fewer than two bytes and for ids the
+// spec doesn't assign.
+pub fn peek_message_type(bytes: &[u8]) -> Option<MessageType> {
+    MessageDecoder::peek_type(bytes)
+        .ok()
+        .filter(|message_type| *message_type != MessageType::Unknown)
+}
+
+// For pasting messages out of logs. Bytes past the end of the message are ignored.
+#[allow(dead_code)]
+pub fn decode_hex(s: &str) -> Result<MessageContainer, MessageDecoderError> {
+    let bytes = hex::decode(s.trim()).map_err(MessageDecoderError::InvalidHex)?;
+    let (message, _) = MessageDecoder::from_bytes(&bytes)?;
+    Ok(message)
+}
+
+#[allow(dead_code)]
+pub fn encode_hex(message: &MessageContainer) -> String {
+    hex::encode(message.to_bytes())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{
-        fs::File,
-        io::{BufRead, BufReader},
-    };
-
     use super::*;
-
-    fn read_example_messages() -> Vec<String> {
-        // open examples file
-        let f = File::open("test/examples").unwrap();
-        let reader = BufReader::new(f);
-        let lines = reader.lines();
-        // return a vec of strings
-        lines.map(|line| line.unwrap()).collect()
-    }
+    use crate::test_util::read_example_messages;
+    use std::collections::HashSet;
 
     #[test]
     fn test_messages_deserialize_and_serialize() {
@@ -156,4 +407,104 @@ mod tests {
             assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
         }
     }
+
+    #[test]
+    fn test_container_type_matches_wire_type() {
+        let mut seen = HashSet::new();
+        for line in read_example_messages() {
+            let bytes = hex::decode(line).unwrap();
+            let (msg, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            assert_eq!(
+                msg.message_type(),
+                MessageDecoder::peek_type(&bytes).unwrap()
+            );
+            seen.insert(msg.message_type());
+        }
+        // every variant with a parser has an example
+        for message_type in MessageDecoder::supported_types() {
+            assert!(
+                seen.contains(&message_type),
+                "no example for {:?}",
+                message_type
+            );
+        }
+
+        // commitment_signed has no parser, an unassigned id isn't a type at all
+        for (bytes, message_type) in [
+            (vec![0x00, 0x84, 0x01], MessageType::CommitmentSigned),
+            (vec![0x80, 0x01, 0x01], MessageType::Unknown),
+        ] {
+            assert_eq!(MessageDecoder::peek_type(&bytes).unwrap(), message_type);
+            let (msg, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            assert!(matches!(msg, MessageContainer::Unknown(_)));
+            assert_eq!(msg.message_type(), message_type);
+        }
+    }
+
+    #[test]
+    fn test_peek_message_type() {
+        for line in read_example_messages() {
+            let bytes = hex::decode(line).unwrap();
+            let (msg, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            assert_eq!(peek_message_type(&bytes), Some(msg.message_type()));
+        }
+        // the body is never looked at
+        assert_eq!(peek_message_type(&[0x00, 0x12]), Some(MessageType::Ping));
+        assert_eq!(peek_message_type(&[0x00]), None);
+        assert_eq!(peek_message_type(&[0x80, 0x01]), None);
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let line = read_example_messages()
+            .into_iter()
+            .find(|line| line.starts_with("0010"))
+            .unwrap();
+        let message = decode_hex(&line).unwrap();
+        assert!(matches!(message, MessageContainer::Init(_)));
+        assert_eq!(encode_hex(&message), line);
+        assert!(matches!(
+            decode_hex("00zz"),
+            Err(MessageDecoderError::InvalidHex(_))
+        ));
+    }
+
+    #[test]
+    fn test_min_size_short_channel_update() {
+        let line = read_example_messages()
+            .into_iter()
+            .find(|line| line.starts_with("0102"))
+            .unwrap();
+        let bytes = hex::decode(line).unwrap();
+        assert!(MessageDecoder::from_bytes(&bytes).is_ok());
+        let short = &bytes[..MessageDecoder::min_size(MessageType::ChannelUpdate).unwrap() - 1];
+        assert!(matches!(
+            MessageDecoder::from_bytes(short),
+            Err(MessageDecoderError::TooFewBytes {
+                message_type: MessageType::ChannelUpdate,
+                min_size: 130,
+                actual: 129,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_min_size_covers_supported_types() {
+        for message_type in MessageDecoder::supported_types() {
+            assert!(
+                MessageDecoder::min_size(message_type).is_some(),
+                "{:?} has no minimum size",
+                message_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_supported_types() {
+        let supported = MessageDecoder::supported_types();
+        assert!(supported.contains(&MessageType::Ping));
+        assert!(supported.contains(&MessageType::UpdateAddHTLC));
+        assert!(!supported.contains(&MessageType::CommitmentSigned));
+        assert!(!supported.contains(&MessageType::Unknown));
+    }
 }
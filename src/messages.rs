@@ -1,15 +1,22 @@
 use crate::{
+    config::PeerConfig,
     node::Node,
     serialization::{
-        ChainHashElement, FeaturesElement, IgnoredBytesElement, MessageTypeElement,
-        NodeAddressesElement, NodeAliasElement, NumPongBytesElement, PointElement,
-        SerializableToBytes, SerializationError, ShortChannelIDElement, SignatureElement,
-        TLVStreamElement, TimestampElement, TimestampRangeElement, Wire1Byte, Wire3Bytes,
-        WireU16Int, WireU16SizedBytes, WireU32Int, WireU64Int,
+        BigSizeElement, ChainHashElement, ChannelIDElement, FeatureFlag, Features, FeaturesElement,
+        IgnoredBytesElement, MessageTypeElement, NodeAddressesElement, NodeAliasElement,
+        NumPongBytesElement, PointElement, SerializableToBytes, SerializationError,
+        ShortChannelIDElement, SignatureElement, TLVRecord, TLVStreamElement, TimestampElement,
+        TimestampRangeElement, Wire1Byte, Wire32Bytes, Wire3Bytes, WireBytes, WireU16Int,
+        WireU16SizedBytes, WireU32Int, WireU64Int,
     },
+    util::crc32c,
 };
 
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{self, ecdsa::Signature};
 use num_enum::TryFromPrimitive;
+use std::collections::HashSet;
+use std::fmt;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, IntoStaticStr};
 
@@ -76,6 +83,79 @@ impl MessageType {
     pub fn from_int(n: u16) -> Option<Self> {
         MessageType::iter().find(|&variant| variant as u16 == n)
     }
+
+    // the message name as written in the BOLTs
+    pub fn wire_name(&self) -> &'static str {
+        match self {
+            MessageType::Unknown => "unknown",
+            MessageType::Warning => "warning",
+            MessageType::Stfu => "stfu",
+            MessageType::Init => "init",
+            MessageType::Error => "error",
+            MessageType::Ping => "ping",
+            MessageType::Pong => "pong",
+            MessageType::OpenChannel => "open_channel",
+            MessageType::AcceptChannel => "accept_channel",
+            MessageType::FundingCreated => "funding_created",
+            MessageType::FundingSigned => "funding_signed",
+            MessageType::ChannelReady => "channel_ready",
+            MessageType::Shutdown => "shutdown",
+            MessageType::ClosingSigned => "closing_signed",
+            MessageType::ClosingComplete => "closing_complete",
+            MessageType::ClosingSig => "closing_sig",
+            MessageType::OpenChannel2 => "open_channel2",
+            MessageType::AcceptChannel2 => "accept_channel2",
+            MessageType::TxAddInput => "tx_add_input",
+            MessageType::TxAddOutput => "tx_add_output",
+            MessageType::TxRemoveInput => "tx_remove_input",
+            MessageType::TxRemoveOutput => "tx_remove_output",
+            MessageType::TxComplete => "tx_complete",
+            MessageType::TxSignatures => "tx_signatures",
+            MessageType::TxInitRbf => "tx_init_rbf",
+            MessageType::TxAckRbf => "tx_ack_rbf",
+            MessageType::TxAbort => "tx_abort",
+            MessageType::UpdateAddHTLC => "update_add_htlc",
+            MessageType::UpdateFulfillHTLC => "update_fulfill_htlc",
+            MessageType::UpdateFailHTLC => "update_fail_htlc",
+            MessageType::CommitmentSigned => "commitment_signed",
+            MessageType::RevokeAndAck => "revoke_and_ack",
+            MessageType::UpdateFee => "update_fee",
+            MessageType::UpdateFailMalformedHTLC => "update_fail_malformed_htlc",
+            MessageType::ChannelReestablish => "channel_reestablish",
+            MessageType::ChannelAnnouncement => "channel_announcement",
+            MessageType::NodeAnnouncement => "node_announcement",
+            MessageType::ChannelUpdate => "channel_update",
+            MessageType::AnnouncementSignatures => "announcement_signatures",
+            MessageType::QueryShortChannelIds => "query_short_channel_ids",
+            MessageType::ReplyShortChannelIdsEnd => "reply_short_channel_ids_end",
+            MessageType::QueryChannelRange => "query_channel_range",
+            MessageType::ReplyChannelRange => "reply_channel_range",
+            MessageType::GossipTimestampFilter => "gossip_timestamp_filter",
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn from_wire_name(name: &str) -> Option<Self> {
+        MessageType::iter().find(|variant| variant.wire_name() == name)
+    }
+}
+
+// Messages ending in a TLV stream. The stream is kept as received, so records of
+// types we don't know survive re-serialization and can be listed to spot extensions
+// peers are experimenting with.
+pub trait TLVStreamMessage {
+    // types the spec defines for this message, whether or not we parse them
+    const KNOWN_TLV_TYPES: &'static [u64];
+
+    fn tlv_stream(&self) -> &[u8];
+
+    fn unknown_tlvs(&self) -> Vec<TLVRecord> {
+        TLVRecord::parse_stream(self.tlv_stream())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|record| !Self::KNOWN_TLV_TYPES.contains(&record.record_type))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -85,6 +165,88 @@ pub struct InitMessage {
     tlv: Vec<u8>,
 }
 
+const INIT_TLV_NETWORKS: u64 = 1;
+
+impl InitMessage {
+    pub fn new(global_features: Vec<u8>, local_features: Vec<u8>, networks: &[[u8; 32]]) -> Self {
+        let mut tlv = Vec::new();
+        if !networks.is_empty() {
+            tlv.extend(BigSizeElement::new(INIT_TLV_NETWORKS).to_bytes());
+            tlv.extend(BigSizeElement::new(32 * networks.len() as u64).to_bytes());
+            for network in networks {
+                tlv.extend(network);
+            }
+        }
+        InitMessage {
+            global_features: FeaturesElement::new(global_features),
+            local_features: FeaturesElement::new(local_features),
+            tlv,
+        }
+    }
+
+    // None if the peer did not send a networks tlv, meaning it did not restrict its chains
+    pub fn networks(&self) -> Option<Vec<[u8; 32]>> {
+        let records = TLVRecord::parse_stream(&self.tlv).ok()?;
+        let record = TLVRecord::find(&records, INIT_TLV_NETWORKS)?;
+        Some(
+            record
+                .value
+                .chunks_exact(32)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect(),
+        )
+    }
+
+    // adds a record to the tlv stream, keeping record types in increasing order.
+    // An existing record of the same type is replaced.
+    #[allow(dead_code)]
+    pub fn with_custom_tlv(mut self, type_id: u64, value: Vec<u8>) -> Self {
+        let mut records = TLVRecord::parse_stream(&self.tlv).unwrap_or_default();
+        records.retain(|record| record.record_type != type_id);
+        records.push(TLVRecord::new(type_id, value));
+        records.sort_by_key(|record| record.record_type);
+        self.tlv = records
+            .iter()
+            .flat_map(|record| record.to_bytes())
+            .collect();
+        self
+    }
+
+    // BOLT 9: global features are legacy, what a node supports is both fields ORed
+    pub fn features(&self) -> FeaturesElement {
+        self.global_features.merged(&self.local_features)
+    }
+
+    pub fn supports_feature(&self, feature: &Features) -> bool {
+        !matches!(self.features().feature_status(feature), FeatureFlag::Unset)
+    }
+
+    pub fn supported_features(&self) -> HashSet<Features> {
+        self.features()
+            .set_features()
+            .into_iter()
+            .map(|(feature, _)| feature)
+            .collect()
+    }
+
+    pub fn from_config(config: &PeerConfig) -> Self {
+        InitMessage::new(
+            config.global_features.clone(),
+            config.local_features.clone(),
+            &config.networks,
+        )
+    }
+}
+
+impl TLVStreamMessage for InitMessage {
+    // networks, remote_addr
+    const KNOWN_TLV_TYPES: &'static [u64] = &[INIT_TLV_NETWORKS, 3];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
 impl SerializableToBytes for InitMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_message, data) = MessageTypeElement::from_bytes(data)?;
@@ -111,7 +273,96 @@ impl SerializableToBytes for InitMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct ErrorMessage {
+    pub channel_id: ChannelIDElement,
+    pub data: Vec<u8>,
+}
+
+impl ErrorMessage {
+    // an all-zero channel_id means the error applies to the whole connection
+    pub fn for_connection(reason: &str) -> Self {
+        ErrorMessage {
+            channel_id: ChannelIDElement { value: [0; 32] },
+            data: reason.as_bytes().to_vec(),
+        }
+    }
+
+    // BOLT 1: an all-zero channel_id refers to all channels
+    pub fn applies_to_all_channels(&self) -> bool {
+        self.channel_id.value == [0; 32]
+    }
+
+    // peers are not required to send valid utf-8
+    pub fn data_as_string(&self) -> String {
+        String::from_utf8_lossy(&self.data).into_owned()
+    }
+}
+
+impl SerializableToBytes for ErrorMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (error_data, data) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((
+            ErrorMessage {
+                channel_id,
+                data: error_data.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::Error).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.data.clone()).to_bytes());
+        bytes
+    }
+}
+
+// same layout as error, but the connection stays up
+#[derive(Debug, Clone)]
+pub struct WarningMessage {
+    pub channel_id: ChannelIDElement,
+    pub data: Vec<u8>,
+}
+
+impl WarningMessage {
+    pub fn applies_to_all_channels(&self) -> bool {
+        self.channel_id.value == [0; 32]
+    }
+
+    pub fn data_as_string(&self) -> String {
+        String::from_utf8_lossy(&self.data).into_owned()
+    }
+}
+
+impl SerializableToBytes for WarningMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (warning_data, data) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((
+            WarningMessage {
+                channel_id,
+                data: warning_data.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::Warning).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.data.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PingMessage {
     pub num_pong_bytes: u16,
     pub ignored: IgnoredBytesElement,
@@ -140,7 +391,7 @@ impl SerializableToBytes for PingMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PongMessage {
     ignored: IgnoredBytesElement,
 }
@@ -151,19 +402,920 @@ impl PongMessage {
             ignored: IgnoredBytesElement::new(vec![0; ping.num_pong_bytes as usize]),
         }
     }
+
+    pub fn byteslen(&self) -> usize {
+        self.ignored.value.value.len()
+    }
+}
+
+impl SerializableToBytes for PongMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_message, data) = MessageTypeElement::from_bytes(data)?;
+        let (ignored, data) = IgnoredBytesElement::from_bytes(data)?;
+        Ok((PongMessage { ignored }, data))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::Pong).to_bytes());
+        bytes.extend(self.ignored.to_bytes());
+        bytes
+    }
+}
+
+const CHANNEL_TLV_UPFRONT_SHUTDOWN_SCRIPT: u64 = 0;
+const CHANNEL_TLV_CHANNEL_TYPE: u64 = 1;
+
+fn channel_type_from_tlv(tlv: &[u8]) -> Option<FeaturesElement> {
+    let records = TLVRecord::parse_stream(tlv).ok()?;
+    let record = TLVRecord::find(&records, CHANNEL_TLV_CHANNEL_TYPE)?;
+    Some(FeaturesElement::new(record.value.clone()))
+}
+
+// the script is kept as sent, empty means the sender chose not to commit to one
+fn upfront_shutdown_script_from_tlv(tlv: &[u8]) -> Option<Vec<u8>> {
+    let records = TLVRecord::parse_stream(tlv).ok()?;
+    let record = TLVRecord::find(&records, CHANNEL_TLV_UPFRONT_SHUTDOWN_SCRIPT)?;
+    Some(record.value.clone())
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenChannelMessage {
+    pub chain_hash: ChainHashElement,
+    pub temporary_channel_id: ChannelIDElement,
+    pub funding_satoshis: u64,
+    pub push_msat: u64,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub channel_reserve_satoshis: u64,
+    pub htlc_minimum_msat: u64,
+    pub feerate_per_kw: u32,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub funding_pubkey: PointElement,
+    pub revocation_basepoint: PointElement,
+    pub payment_basepoint: PointElement,
+    pub delayed_payment_basepoint: PointElement,
+    pub htlc_basepoint: PointElement,
+    pub first_per_commitment_point: PointElement,
+    pub channel_flags: u8,
+    tlv: Vec<u8>,
+}
+
+impl OpenChannelMessage {
+    #[allow(dead_code)]
+    pub fn channel_type(&self) -> Option<FeaturesElement> {
+        channel_type_from_tlv(&self.tlv)
+    }
+
+    #[allow(dead_code)]
+    pub fn upfront_shutdown_script(&self) -> Option<Vec<u8>> {
+        upfront_shutdown_script_from_tlv(&self.tlv)
+    }
+}
+
+impl TLVStreamMessage for OpenChannelMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[
+        CHANNEL_TLV_UPFRONT_SHUTDOWN_SCRIPT,
+        CHANNEL_TLV_CHANNEL_TYPE,
+    ];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for OpenChannelMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (temporary_channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (funding_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (push_msat, data) = WireU64Int::from_bytes(data)?;
+        let (dust_limit_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (max_htlc_value_in_flight_msat, data) = WireU64Int::from_bytes(data)?;
+        let (channel_reserve_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (htlc_minimum_msat, data) = WireU64Int::from_bytes(data)?;
+        let (feerate_per_kw, data) = WireU32Int::from_bytes(data)?;
+        let (to_self_delay, data) = WireU16Int::from_bytes(data)?;
+        let (max_accepted_htlcs, data) = WireU16Int::from_bytes(data)?;
+        let (funding_pubkey, data) = PointElement::from_bytes(data)?;
+        let (revocation_basepoint, data) = PointElement::from_bytes(data)?;
+        let (payment_basepoint, data) = PointElement::from_bytes(data)?;
+        let (delayed_payment_basepoint, data) = PointElement::from_bytes(data)?;
+        let (htlc_basepoint, data) = PointElement::from_bytes(data)?;
+        let (first_per_commitment_point, data) = PointElement::from_bytes(data)?;
+        let (channel_flags, data) = Wire1Byte::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+
+        Ok((
+            OpenChannelMessage {
+                chain_hash,
+                temporary_channel_id,
+                funding_satoshis: funding_satoshis.value,
+                push_msat: push_msat.value,
+                dust_limit_satoshis: dust_limit_satoshis.value,
+                max_htlc_value_in_flight_msat: max_htlc_value_in_flight_msat.value,
+                channel_reserve_satoshis: channel_reserve_satoshis.value,
+                htlc_minimum_msat: htlc_minimum_msat.value,
+                feerate_per_kw: feerate_per_kw.value,
+                to_self_delay: to_self_delay.value,
+                max_accepted_htlcs: max_accepted_htlcs.value,
+                funding_pubkey,
+                revocation_basepoint,
+                payment_basepoint,
+                delayed_payment_basepoint,
+                htlc_basepoint,
+                first_per_commitment_point,
+                channel_flags: channel_flags.value,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::OpenChannel).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(self.temporary_channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.funding_satoshis).to_bytes());
+        bytes.extend(WireU64Int::new(self.push_msat).to_bytes());
+        bytes.extend(WireU64Int::new(self.dust_limit_satoshis).to_bytes());
+        bytes.extend(WireU64Int::new(self.max_htlc_value_in_flight_msat).to_bytes());
+        bytes.extend(WireU64Int::new(self.channel_reserve_satoshis).to_bytes());
+        bytes.extend(WireU64Int::new(self.htlc_minimum_msat).to_bytes());
+        bytes.extend(WireU32Int::new(self.feerate_per_kw).to_bytes());
+        bytes.extend(WireU16Int::new(self.to_self_delay).to_bytes());
+        bytes.extend(WireU16Int::new(self.max_accepted_htlcs).to_bytes());
+        bytes.extend(self.funding_pubkey.to_bytes());
+        bytes.extend(self.revocation_basepoint.to_bytes());
+        bytes.extend(self.payment_basepoint.to_bytes());
+        bytes.extend(self.delayed_payment_basepoint.to_bytes());
+        bytes.extend(self.htlc_basepoint.to_bytes());
+        bytes.extend(self.first_per_commitment_point.to_bytes());
+        bytes.extend(Wire1Byte::new(self.channel_flags).to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcceptChannelMessage {
+    pub temporary_channel_id: ChannelIDElement,
+    pub dust_limit_satoshis: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub channel_reserve_satoshis: u64,
+    pub htlc_minimum_msat: u64,
+    pub minimum_depth: u32,
+    pub to_self_delay: u16,
+    pub max_accepted_htlcs: u16,
+    pub funding_pubkey: PointElement,
+    pub revocation_basepoint: PointElement,
+    pub payment_basepoint: PointElement,
+    pub delayed_payment_basepoint: PointElement,
+    pub htlc_basepoint: PointElement,
+    pub first_per_commitment_point: PointElement,
+    tlv: Vec<u8>,
+}
+
+impl AcceptChannelMessage {
+    #[allow(dead_code)]
+    pub fn channel_type(&self) -> Option<FeaturesElement> {
+        channel_type_from_tlv(&self.tlv)
+    }
+
+    #[allow(dead_code)]
+    pub fn upfront_shutdown_script(&self) -> Option<Vec<u8>> {
+        upfront_shutdown_script_from_tlv(&self.tlv)
+    }
+}
+
+impl TLVStreamMessage for AcceptChannelMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[
+        CHANNEL_TLV_UPFRONT_SHUTDOWN_SCRIPT,
+        CHANNEL_TLV_CHANNEL_TYPE,
+    ];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for AcceptChannelMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (temporary_channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (dust_limit_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (max_htlc_value_in_flight_msat, data) = WireU64Int::from_bytes(data)?;
+        let (channel_reserve_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (htlc_minimum_msat, data) = WireU64Int::from_bytes(data)?;
+        let (minimum_depth, data) = WireU32Int::from_bytes(data)?;
+        let (to_self_delay, data) = WireU16Int::from_bytes(data)?;
+        let (max_accepted_htlcs, data) = WireU16Int::from_bytes(data)?;
+        let (funding_pubkey, data) = PointElement::from_bytes(data)?;
+        let (revocation_basepoint, data) = PointElement::from_bytes(data)?;
+        let (payment_basepoint, data) = PointElement::from_bytes(data)?;
+        let (delayed_payment_basepoint, data) = PointElement::from_bytes(data)?;
+        let (htlc_basepoint, data) = PointElement::from_bytes(data)?;
+        let (first_per_commitment_point, data) = PointElement::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+
+        Ok((
+            AcceptChannelMessage {
+                temporary_channel_id,
+                dust_limit_satoshis: dust_limit_satoshis.value,
+                max_htlc_value_in_flight_msat: max_htlc_value_in_flight_msat.value,
+                channel_reserve_satoshis: channel_reserve_satoshis.value,
+                htlc_minimum_msat: htlc_minimum_msat.value,
+                minimum_depth: minimum_depth.value,
+                to_self_delay: to_self_delay.value,
+                max_accepted_htlcs: max_accepted_htlcs.value,
+                funding_pubkey,
+                revocation_basepoint,
+                payment_basepoint,
+                delayed_payment_basepoint,
+                htlc_basepoint,
+                first_per_commitment_point,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::AcceptChannel).to_bytes());
+        bytes.extend(self.temporary_channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.dust_limit_satoshis).to_bytes());
+        bytes.extend(WireU64Int::new(self.max_htlc_value_in_flight_msat).to_bytes());
+        bytes.extend(WireU64Int::new(self.channel_reserve_satoshis).to_bytes());
+        bytes.extend(WireU64Int::new(self.htlc_minimum_msat).to_bytes());
+        bytes.extend(WireU32Int::new(self.minimum_depth).to_bytes());
+        bytes.extend(WireU16Int::new(self.to_self_delay).to_bytes());
+        bytes.extend(WireU16Int::new(self.max_accepted_htlcs).to_bytes());
+        bytes.extend(self.funding_pubkey.to_bytes());
+        bytes.extend(self.revocation_basepoint.to_bytes());
+        bytes.extend(self.payment_basepoint.to_bytes());
+        bytes.extend(self.delayed_payment_basepoint.to_bytes());
+        bytes.extend(self.htlc_basepoint.to_bytes());
+        bytes.extend(self.first_per_commitment_point.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelReadyMessage {
+    pub channel_id: ChannelIDElement,
+    pub second_per_commitment_point: PointElement,
+    tlv: Vec<u8>,
+}
+
+const CHANNEL_READY_TLV_SHORT_CHANNEL_ID: u64 = 1;
+
+impl ChannelReadyMessage {
+    // the alias scid a zero-conf / scid_alias peer wants us to route over
+    pub fn scid_alias(&self) -> Option<ShortChannelIDElement> {
+        let records = TLVRecord::parse_stream(&self.tlv).ok()?;
+        let record = TLVRecord::find(&records, CHANNEL_READY_TLV_SHORT_CHANNEL_ID)?;
+        match ShortChannelIDElement::from_bytes(&record.value) {
+            Ok((short_channel_id, _)) => Some(short_channel_id),
+            Err(_) => None,
+        }
+    }
+}
+
+impl TLVStreamMessage for ChannelReadyMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[CHANNEL_READY_TLV_SHORT_CHANNEL_ID];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for ChannelReadyMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (second_per_commitment_point, data) = PointElement::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            ChannelReadyMessage {
+                channel_id,
+                second_per_commitment_point,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::ChannelReady).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(self.second_per_commitment_point.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FundingCreatedMessage {
+    pub temporary_channel_id: ChannelIDElement,
+    pub funding_txid: Wire32Bytes,
+    // a u16 on the wire, so any decoded value is a possible output index
+    pub funding_output_index: u16,
+    pub signature: SignatureElement,
+}
+
+impl SerializableToBytes for FundingCreatedMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (temporary_channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (funding_txid, data) = Wire32Bytes::from_bytes(data)?;
+        let (funding_output_index, data) = WireU16Int::from_bytes(data)?;
+        let (signature, data) = SignatureElement::from_bytes(data)?;
+        Ok((
+            FundingCreatedMessage {
+                temporary_channel_id,
+                funding_txid,
+                funding_output_index: funding_output_index.value,
+                signature,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::FundingCreated).to_bytes());
+        bytes.extend(self.temporary_channel_id.to_bytes());
+        bytes.extend(self.funding_txid.to_bytes());
+        bytes.extend(WireU16Int::new(self.funding_output_index).to_bytes());
+        bytes.extend(self.signature.to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FundingSignedMessage {
+    pub channel_id: ChannelIDElement,
+    pub signature: SignatureElement,
+}
+
+impl FundingSignedMessage {
+    // We never build the commitment transaction, so this cannot be verified here, but
+    // it lets an observer check it against one built elsewhere.
+    #[allow(dead_code)]
+    pub fn as_secp_signature(&self) -> Result<Signature, secp256k1::Error> {
+        self.signature.as_secp_signature()
+    }
+}
+
+impl SerializableToBytes for FundingSignedMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (signature, data) = SignatureElement::from_bytes(data)?;
+        Ok((
+            FundingSignedMessage {
+                channel_id,
+                signature,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::FundingSigned).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(self.signature.to_bytes());
+        bytes
+    }
+}
+
+// BOLT 4 failure codes, the high bits are the BADONION/PERM/NODE/UPDATE flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCode {
+    InvalidRealm,
+    TemporaryNodeFailure,
+    PermanentNodeFailure,
+    RequiredNodeFeatureMissing,
+    InvalidOnionVersion,
+    InvalidOnionHmac,
+    InvalidOnionKey,
+    TemporaryChannelFailure,
+    PermanentChannelFailure,
+    RequiredChannelFeatureMissing,
+    UnknownNextPeer,
+    AmountBelowMinimum,
+    FeeInsufficient,
+    IncorrectCltvExpiry,
+    ExpiryTooSoon,
+    IncorrectOrUnknownPaymentDetails,
+    FinalIncorrectCltvExpiry,
+    FinalIncorrectHtlcAmount,
+    ChannelDisabled,
+    ExpiryTooFar,
+    InvalidOnionPayload,
+    MppTimeout,
+    InvalidOnionBlinding,
+    Unknown(u16),
+}
+
+const FAILURE_CODES: &[(u16, FailureCode, &str)] = &[
+    (0x4001, FailureCode::InvalidRealm, "invalid_realm"),
+    (
+        0x2002,
+        FailureCode::TemporaryNodeFailure,
+        "temporary_node_failure",
+    ),
+    (
+        0x6002,
+        FailureCode::PermanentNodeFailure,
+        "permanent_node_failure",
+    ),
+    (
+        0x6003,
+        FailureCode::RequiredNodeFeatureMissing,
+        "required_node_feature_missing",
+    ),
+    (
+        0xc004,
+        FailureCode::InvalidOnionVersion,
+        "invalid_onion_version",
+    ),
+    (0xc005, FailureCode::InvalidOnionHmac, "invalid_onion_hmac"),
+    (0xc006, FailureCode::InvalidOnionKey, "invalid_onion_key"),
+    (
+        0x1007,
+        FailureCode::TemporaryChannelFailure,
+        "temporary_channel_failure",
+    ),
+    (
+        0x4008,
+        FailureCode::PermanentChannelFailure,
+        "permanent_channel_failure",
+    ),
+    (
+        0x4009,
+        FailureCode::RequiredChannelFeatureMissing,
+        "required_channel_feature_missing",
+    ),
+    (0x400a, FailureCode::UnknownNextPeer, "unknown_next_peer"),
+    (
+        0x100b,
+        FailureCode::AmountBelowMinimum,
+        "amount_below_minimum",
+    ),
+    (0x100c, FailureCode::FeeInsufficient, "fee_insufficient"),
+    (
+        0x100d,
+        FailureCode::IncorrectCltvExpiry,
+        "incorrect_cltv_expiry",
+    ),
+    (0x100e, FailureCode::ExpiryTooSoon, "expiry_too_soon"),
+    (
+        0x400f,
+        FailureCode::IncorrectOrUnknownPaymentDetails,
+        "incorrect_or_unknown_payment_details",
+    ),
+    (
+        0x0012,
+        FailureCode::FinalIncorrectCltvExpiry,
+        "final_incorrect_cltv_expiry",
+    ),
+    (
+        0x0013,
+        FailureCode::FinalIncorrectHtlcAmount,
+        "final_incorrect_htlc_amount",
+    ),
+    (0x1014, FailureCode::ChannelDisabled, "channel_disabled"),
+    (0x0015, FailureCode::ExpiryTooFar, "expiry_too_far"),
+    (
+        0x4016,
+        FailureCode::InvalidOnionPayload,
+        "invalid_onion_payload",
+    ),
+    (0x0017, FailureCode::MppTimeout, "mpp_timeout"),
+    (
+        0xc018,
+        FailureCode::InvalidOnionBlinding,
+        "invalid_onion_blinding",
+    ),
+];
+
+impl FailureCode {
+    pub fn from_u16(code: u16) -> Self {
+        FAILURE_CODES
+            .iter()
+            .find(|(value, _, _)| *value == code)
+            .map(|(_, failure_code, _)| *failure_code)
+            .unwrap_or(FailureCode::Unknown(code))
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            FailureCode::Unknown(code) => *code,
+            _ => {
+                FAILURE_CODES
+                    .iter()
+                    .find(|(_, failure_code, _)| failure_code == self)
+                    .unwrap()
+                    .0
+            }
+        }
+    }
+}
+
+impl fmt::Display for FailureCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match FAILURE_CODES
+            .iter()
+            .find(|(_, failure_code, _)| failure_code == self)
+        {
+            Some((_, _, name)) => write!(f, "{}", name),
+            None => write!(f, "unknown_failure_code({:#06x})", self.to_u16()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateFailMalformedHTLCMessage {
+    pub channel_id: ChannelIDElement,
+    pub id: u64,
+    sha256_of_onion: Wire32Bytes,
+    pub failure_code: FailureCode,
+}
+
+impl SerializableToBytes for UpdateFailMalformedHTLCMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (id, data) = WireU64Int::from_bytes(data)?;
+        let (sha256_of_onion, data) = Wire32Bytes::from_bytes(data)?;
+        let (failure_code, data) = WireU16Int::from_bytes(data)?;
+        Ok((
+            UpdateFailMalformedHTLCMessage {
+                channel_id,
+                id: id.value,
+                sha256_of_onion,
+                failure_code: FailureCode::from_u16(failure_code.value),
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::UpdateFailMalformedHTLC).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.id).to_bytes());
+        bytes.extend(self.sha256_of_onion.to_bytes());
+        bytes.extend(WireU16Int::new(self.failure_code.to_u16()).to_bytes());
+        bytes
+    }
+}
+
+const CLOSING_SIGNED_TLV_FEE_RANGE: u64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ClosingSignedMessage {
+    pub channel_id: ChannelIDElement,
+    pub fee_satoshis: u64,
+    pub signature: SignatureElement,
+    tlv: Vec<u8>,
+}
+
+impl ClosingSignedMessage {
+    // (min_fee_satoshis, max_fee_satoshis) the sender will accept
+    #[allow(dead_code)]
+    pub fn fee_range(&self) -> Option<(u64, u64)> {
+        let records = TLVRecord::parse_stream(&self.tlv).ok()?;
+        let record = TLVRecord::find(&records, CLOSING_SIGNED_TLV_FEE_RANGE)?;
+        let (min_fee_satoshis, data) = WireU64Int::from_bytes(&record.value).ok()?;
+        let (max_fee_satoshis, _) = WireU64Int::from_bytes(data).ok()?;
+        Some((min_fee_satoshis.value, max_fee_satoshis.value))
+    }
+}
+
+impl TLVStreamMessage for ClosingSignedMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[CLOSING_SIGNED_TLV_FEE_RANGE];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for ClosingSignedMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (fee_satoshis, data) = WireU64Int::from_bytes(data)?;
+        let (signature, data) = SignatureElement::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            ClosingSignedMessage {
+                channel_id,
+                fee_satoshis: fee_satoshis.value,
+                signature,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::ClosingSigned).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.fee_satoshis).to_bytes());
+        bytes.extend(self.signature.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StfuMessage {
+    pub channel_id: ChannelIDElement,
+    // 1 if the sender wants to initiate the quiescent operation
+    pub initiator: u8,
+}
+
+impl SerializableToBytes for StfuMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (initiator, data) = Wire1Byte::from_bytes(data)?;
+        Ok((
+            StfuMessage {
+                channel_id,
+                initiator: initiator.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::Stfu).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(Wire1Byte::new(self.initiator).to_bytes());
+        bytes
+    }
+}
+
+// shared by tx_init_rbf and tx_ack_rbf
+const TX_RBF_TLV_FUNDING_OUTPUT_CONTRIBUTION: u64 = 0;
+const TX_RBF_TLV_REQUIRE_CONFIRMED_INPUTS: u64 = 2;
+
+// signed satoshis the sender adds to (or takes from) the shared funding output
+fn funding_output_contribution(tlv: &[u8]) -> Option<i64> {
+    let records = TLVRecord::parse_stream(tlv).ok()?;
+    let record = TLVRecord::find(&records, TX_RBF_TLV_FUNDING_OUTPUT_CONTRIBUTION)?;
+    let (contribution, _) = WireU64Int::from_bytes(&record.value).ok()?;
+    Some(contribution.value as i64)
+}
+
+#[derive(Debug, Clone)]
+pub struct TxInitRbfMessage {
+    pub channel_id: ChannelIDElement,
+    pub locktime: u32,
+    pub feerate: u32,
+    tlv: Vec<u8>,
+}
+
+impl TxInitRbfMessage {
+    pub fn funding_output_contribution(&self) -> Option<i64> {
+        funding_output_contribution(&self.tlv)
+    }
+}
+
+impl TLVStreamMessage for TxInitRbfMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[
+        TX_RBF_TLV_FUNDING_OUTPUT_CONTRIBUTION,
+        TX_RBF_TLV_REQUIRE_CONFIRMED_INPUTS,
+    ];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for TxInitRbfMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (locktime, data) = WireU32Int::from_bytes(data)?;
+        let (feerate, data) = WireU32Int::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            TxInitRbfMessage {
+                channel_id,
+                locktime: locktime.value,
+                feerate: feerate.value,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::TxInitRbf).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU32Int::new(self.locktime).to_bytes());
+        bytes.extend(WireU32Int::new(self.feerate).to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxAckRbfMessage {
+    pub channel_id: ChannelIDElement,
+    tlv: Vec<u8>,
+}
+
+impl TxAckRbfMessage {
+    pub fn funding_output_contribution(&self) -> Option<i64> {
+        funding_output_contribution(&self.tlv)
+    }
+}
+
+impl TLVStreamMessage for TxAckRbfMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[
+        TX_RBF_TLV_FUNDING_OUTPUT_CONTRIBUTION,
+        TX_RBF_TLV_REQUIRE_CONFIRMED_INPUTS,
+    ];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for TxAckRbfMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            TxAckRbfMessage {
+                channel_id,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::TxAckRbf).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+// BOLT 4 onion packet: version, ephemeral key, 1300 byte payload and hmac
+pub const ONION_PACKET_LEN: usize = 1366;
+
+#[derive(Debug, Clone)]
+pub struct UpdateAddHTLCMessage {
+    pub channel_id: ChannelIDElement,
+    pub id: u64,
+    pub amount_msat: u64,
+    pub payment_hash: Wire32Bytes,
+    pub cltv_expiry: u32,
+    pub onion_routing_packet: WireBytes<ONION_PACKET_LEN>,
+    tlv: Vec<u8>,
+}
+
+impl TLVStreamMessage for UpdateAddHTLCMessage {
+    // blinded_path
+    const KNOWN_TLV_TYPES: &'static [u64] = &[0];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.tlv
+    }
+}
+
+impl SerializableToBytes for UpdateAddHTLCMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (id, data) = WireU64Int::from_bytes(data)?;
+        let (amount_msat, data) = WireU64Int::from_bytes(data)?;
+        // BOLT 2: an htlc must offer something
+        if amount_msat.value == 0 {
+            return Err(SerializationError::InvalidValue);
+        }
+        let (payment_hash, data) = Wire32Bytes::from_bytes(data)?;
+        let (cltv_expiry, data) = WireU32Int::from_bytes(data)?;
+        let (onion_routing_packet, data) = WireBytes::from_bytes(data)?;
+        let (tlv, data) = TLVStreamElement::from_bytes(data)?;
+        Ok((
+            UpdateAddHTLCMessage {
+                channel_id,
+                id: id.value,
+                amount_msat: amount_msat.value,
+                payment_hash,
+                cltv_expiry: cltv_expiry.value,
+                onion_routing_packet,
+                tlv: tlv.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::UpdateAddHTLC).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.id).to_bytes());
+        bytes.extend(WireU64Int::new(self.amount_msat).to_bytes());
+        bytes.extend(self.payment_hash.to_bytes());
+        bytes.extend(WireU32Int::new(self.cltv_expiry).to_bytes());
+        bytes.extend(self.onion_routing_packet.to_bytes());
+        bytes.extend(TLVStreamElement::new(self.tlv.clone()).to_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateFulfillHTLCMessage {
+    pub channel_id: ChannelIDElement,
+    pub id: u64,
+    pub payment_preimage: Wire32Bytes,
+}
+
+impl UpdateFulfillHTLCMessage {
+    // matches the payment_hash of the update_add_htlc this settles
+    #[allow(dead_code)]
+    pub fn payment_hash(&self) -> [u8; 32] {
+        sha256::Hash::hash(&self.payment_preimage.value).to_byte_array()
+    }
+}
+
+impl SerializableToBytes for UpdateFulfillHTLCMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (id, data) = WireU64Int::from_bytes(data)?;
+        let (payment_preimage, data) = Wire32Bytes::from_bytes(data)?;
+        Ok((
+            UpdateFulfillHTLCMessage {
+                channel_id,
+                id: id.value,
+                payment_preimage,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::UpdateFulfillHTLC).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.id).to_bytes());
+        bytes.extend(self.payment_preimage.to_bytes());
+        bytes
+    }
 }
 
-impl SerializableToBytes for PongMessage {
+#[derive(Debug, Clone)]
+pub struct UpdateFailHTLCMessage {
+    pub channel_id: ChannelIDElement,
+    pub id: u64,
+    // onion-encrypted for the origin node, opaque to us
+    pub reason: Vec<u8>,
+}
+
+impl SerializableToBytes for UpdateFailHTLCMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        let (_message, data) = MessageTypeElement::from_bytes(data)?;
-        let (ignored, data) = IgnoredBytesElement::from_bytes(data)?;
-        Ok((PongMessage { ignored }, data))
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (channel_id, data) = ChannelIDElement::from_bytes(data)?;
+        let (id, data) = WireU64Int::from_bytes(data)?;
+        let (reason, data) = WireU16SizedBytes::from_bytes(data)?;
+        Ok((
+            UpdateFailHTLCMessage {
+                channel_id,
+                id: id.value,
+                reason: reason.value,
+            },
+            data,
+        ))
     }
 
     fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        bytes.extend(MessageTypeElement::new(MessageType::Pong).to_bytes());
-        bytes.extend(self.ignored.to_bytes());
+        bytes.extend(MessageTypeElement::new(MessageType::UpdateFailHTLC).to_bytes());
+        bytes.extend(self.channel_id.to_bytes());
+        bytes.extend(WireU64Int::new(self.id).to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.reason.clone()).to_bytes());
         bytes
     }
 }
@@ -177,8 +1329,8 @@ pub struct ChannelAnnouncementMessage {
     features: FeaturesElement,
     chain_hash: ChainHashElement,
     pub short_channel_id: ShortChannelIDElement,
-    node_id_1: PointElement,
-    node_id_2: PointElement,
+    pub node_id_1: PointElement,
+    pub node_id_2: PointElement,
     bitcoin_node_id_1: PointElement,
     bitcoin_node_id_2: PointElement,
 }
@@ -241,6 +1393,16 @@ pub struct GossipTimestampFilterMessage {
     pub timestamp_range: u32,
 }
 
+impl GossipTimestampFilterMessage {
+    pub fn new(chain_hash: [u8; 32], first_timestamp: u32, timestamp_range: u32) -> Self {
+        GossipTimestampFilterMessage {
+            chain_hash: ChainHashElement { value: chain_hash },
+            first_timestamp,
+            timestamp_range,
+        }
+    }
+}
+
 impl SerializableToBytes for GossipTimestampFilterMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
@@ -268,14 +1430,68 @@ impl SerializableToBytes for GossipTimestampFilterMessage {
     }
 }
 
-#[derive(Debug)]
+const QUERY_CHANNEL_RANGE_TLV_QUERY_OPTION: u64 = 1;
+// query_option bits
+pub const QUERY_OPTION_TIMESTAMPS: u64 = 1;
+pub const QUERY_OPTION_CHECKSUMS: u64 = 2;
+
+#[derive(Debug, Clone)]
 pub struct QueryChannelRangeMessage {
-    chain_hash: ChainHashElement,
-    first_blocknum: u32,
-    number_of_blocks: u32,
+    pub chain_hash: ChainHashElement,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
     query_range_tlvs: Vec<u8>,
 }
 
+impl QueryChannelRangeMessage {
+    pub fn new(chain_hash: [u8; 32], first_blocknum: u32, number_of_blocks: u32) -> Self {
+        QueryChannelRangeMessage {
+            chain_hash: ChainHashElement { value: chain_hash },
+            first_blocknum,
+            number_of_blocks,
+            query_range_tlvs: Vec::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_query_option(mut self, query_option: u64) -> Self {
+        self.query_range_tlvs = TLVRecord::new(
+            QUERY_CHANNEL_RANGE_TLV_QUERY_OPTION,
+            BigSizeElement::new(query_option).to_bytes(),
+        )
+        .to_bytes();
+        self
+    }
+
+    // 0 if the querier did not ask for timestamps or checksums
+    pub fn query_option(&self) -> u64 {
+        let records = match TLVRecord::parse_stream(&self.query_range_tlvs) {
+            Ok(records) => records,
+            Err(_) => return 0,
+        };
+        match TLVRecord::find(&records, QUERY_CHANNEL_RANGE_TLV_QUERY_OPTION) {
+            Some(record) => match BigSizeElement::from_bytes(&record.value) {
+                Ok((query_option, _)) => query_option.value,
+                Err(_) => 0,
+            },
+            None => 0,
+        }
+    }
+
+    // one past the last block of the range, wider than u32 so it can't overflow
+    pub fn end_blocknum(&self) -> u64 {
+        self.first_blocknum as u64 + self.number_of_blocks as u64
+    }
+}
+
+impl TLVStreamMessage for QueryChannelRangeMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[QUERY_CHANNEL_RANGE_TLV_QUERY_OPTION];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.query_range_tlvs
+    }
+}
+
 impl SerializableToBytes for QueryChannelRangeMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
@@ -306,16 +1522,128 @@ impl SerializableToBytes for QueryChannelRangeMessage {
     }
 }
 
-#[derive(Debug)]
+const REPLY_CHANNEL_RANGE_TLV_TIMESTAMPS: u64 = 1;
+const REPLY_CHANNEL_RANGE_TLV_CHECKSUMS: u64 = 3;
+// encoded_short_ids and timestamps start with an encoding type, we only do uncompressed
+const ENCODING_UNCOMPRESSED: u8 = 0;
+
+#[derive(Debug, Clone)]
 pub struct ReplyChannelRangeMessage {
-    chain_hash: ChainHashElement,
-    first_blocknum: u32,
-    number_of_blocks: u32,
-    sync_complete: u8,
+    pub chain_hash: ChainHashElement,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    pub sync_complete: u8,
     encoded_short_ids: Vec<u8>,
     reply_channel_range_tlvs: Vec<u8>,
 }
 
+impl ReplyChannelRangeMessage {
+    // timestamps and checksums, if given, hold one [node_id_1, node_id_2] pair per scid
+    pub fn new(
+        chain_hash: [u8; 32],
+        first_blocknum: u32,
+        number_of_blocks: u32,
+        sync_complete: bool,
+        short_channel_ids: &[ShortChannelIDElement],
+        timestamps: Option<Vec<[u32; 2]>>,
+        checksums: Option<Vec<[u32; 2]>>,
+    ) -> Self {
+        let mut tlv = Vec::new();
+        if let Some(timestamps) = timestamps {
+            let mut value = vec![ENCODING_UNCOMPRESSED];
+            value.extend(timestamps.iter().flatten().flat_map(|t| t.to_be_bytes()));
+            tlv.extend(TLVRecord::new(REPLY_CHANNEL_RANGE_TLV_TIMESTAMPS, value).to_bytes());
+        }
+        if let Some(checksums) = checksums {
+            let value = checksums.iter().flatten().flat_map(|c| c.to_be_bytes());
+            tlv.extend(
+                TLVRecord::new(REPLY_CHANNEL_RANGE_TLV_CHECKSUMS, value.collect()).to_bytes(),
+            );
+        }
+        ReplyChannelRangeMessage {
+            chain_hash: ChainHashElement { value: chain_hash },
+            first_blocknum,
+            number_of_blocks,
+            sync_complete: sync_complete as u8,
+            encoded_short_ids: encode_short_channel_ids(short_channel_ids),
+            reply_channel_range_tlvs: tlv,
+        }
+    }
+
+    // None for compressed or malformed encodings
+    #[allow(dead_code)]
+    pub fn short_channel_ids(&self) -> Option<Vec<ShortChannelIDElement>> {
+        decode_short_channel_ids(&self.encoded_short_ids)
+    }
+
+    #[allow(dead_code)]
+    pub fn timestamps(&self) -> Option<Vec<[u32; 2]>> {
+        let records = TLVRecord::parse_stream(&self.reply_channel_range_tlvs).ok()?;
+        let record = TLVRecord::find(&records, REPLY_CHANNEL_RANGE_TLV_TIMESTAMPS)?;
+        let (encoding, data) = record.value.split_first()?;
+        if *encoding != ENCODING_UNCOMPRESSED {
+            return None;
+        }
+        u32_pairs(data)
+    }
+
+    #[allow(dead_code)]
+    pub fn checksums(&self) -> Option<Vec<[u32; 2]>> {
+        let records = TLVRecord::parse_stream(&self.reply_channel_range_tlvs).ok()?;
+        let record = TLVRecord::find(&records, REPLY_CHANNEL_RANGE_TLV_CHECKSUMS)?;
+        u32_pairs(&record.value)
+    }
+}
+
+fn encode_short_channel_ids(short_channel_ids: &[ShortChannelIDElement]) -> Vec<u8> {
+    let mut encoded_short_ids = vec![ENCODING_UNCOMPRESSED];
+    for short_channel_id in short_channel_ids {
+        encoded_short_ids.extend(short_channel_id.to_bytes());
+    }
+    encoded_short_ids
+}
+
+fn decode_short_channel_ids(encoded_short_ids: &[u8]) -> Option<Vec<ShortChannelIDElement>> {
+    let (encoding, mut data) = encoded_short_ids.split_first()?;
+    if *encoding != ENCODING_UNCOMPRESSED || data.len() % 8 != 0 {
+        return None;
+    }
+    let mut short_channel_ids = Vec::new();
+    while !data.is_empty() {
+        let (short_channel_id, rest) = ShortChannelIDElement::from_bytes(data).ok()?;
+        short_channel_ids.push(short_channel_id);
+        data = rest;
+    }
+    Some(short_channel_ids)
+}
+
+fn u32_pairs(data: &[u8]) -> Option<Vec<[u32; 2]>> {
+    if data.len() % 8 != 0 {
+        return None;
+    }
+    Some(
+        data.chunks_exact(8)
+            .map(|pair| {
+                [
+                    u32::from_be_bytes(pair[..4].try_into().unwrap()),
+                    u32::from_be_bytes(pair[4..].try_into().unwrap()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+impl TLVStreamMessage for ReplyChannelRangeMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[
+        REPLY_CHANNEL_RANGE_TLV_TIMESTAMPS,
+        REPLY_CHANNEL_RANGE_TLV_CHECKSUMS,
+    ];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.reply_channel_range_tlvs
+    }
+}
+
 impl SerializableToBytes for ReplyChannelRangeMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
@@ -352,15 +1680,109 @@ impl SerializableToBytes for ReplyChannelRangeMessage {
     }
 }
 
+const QUERY_SHORT_CHANNEL_IDS_TLV_QUERY_FLAGS: u64 = 1;
+
+// asks for the announcement and latest updates of each listed channel
+#[derive(Debug, Clone)]
+pub struct QueryShortChannelIdsMessage {
+    pub chain_hash: ChainHashElement,
+    encoded_short_ids: Vec<u8>,
+    query_short_channel_ids_tlvs: Vec<u8>,
+}
+
+impl QueryShortChannelIdsMessage {
+    // without query_flags the peer sends everything it has for each channel
+    pub fn new(chain_hash: [u8; 32], short_channel_ids: &[ShortChannelIDElement]) -> Self {
+        QueryShortChannelIdsMessage {
+            chain_hash: ChainHashElement { value: chain_hash },
+            encoded_short_ids: encode_short_channel_ids(short_channel_ids),
+            query_short_channel_ids_tlvs: Vec::new(),
+        }
+    }
+
+    // None for compressed or malformed encodings
+    #[allow(dead_code)]
+    pub fn short_channel_ids(&self) -> Option<Vec<ShortChannelIDElement>> {
+        decode_short_channel_ids(&self.encoded_short_ids)
+    }
+}
+
+impl TLVStreamMessage for QueryShortChannelIdsMessage {
+    const KNOWN_TLV_TYPES: &'static [u64] = &[QUERY_SHORT_CHANNEL_IDS_TLV_QUERY_FLAGS];
+
+    fn tlv_stream(&self) -> &[u8] {
+        &self.query_short_channel_ids_tlvs
+    }
+}
+
+impl SerializableToBytes for QueryShortChannelIdsMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (encoded_short_ids, data) = WireU16SizedBytes::from_bytes(data)?;
+        let (query_short_channel_ids_tlvs, data) = TLVStreamElement::from_bytes(data)?;
+
+        Ok((
+            QueryShortChannelIdsMessage {
+                chain_hash,
+                encoded_short_ids: encoded_short_ids.value,
+                query_short_channel_ids_tlvs: query_short_channel_ids_tlvs.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::QueryShortChannelIds).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(WireU16SizedBytes::new(self.encoded_short_ids.clone()).to_bytes());
+        bytes.extend(TLVStreamElement::new(self.query_short_channel_ids_tlvs.clone()).to_bytes());
+        bytes
+    }
+}
+
+// ends the gossip answering a query_short_channel_ids
+#[derive(Debug, Clone)]
+pub struct ReplyShortChannelIdsEndMessage {
+    pub chain_hash: ChainHashElement,
+    // 0 if the peer lacks up to date information for some of the channels
+    pub full_information: u8,
+}
+
+impl SerializableToBytes for ReplyShortChannelIdsEndMessage {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (_, data) = MessageTypeElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (full_information, data) = Wire1Byte::from_bytes(data)?;
+
+        Ok((
+            ReplyShortChannelIdsEndMessage {
+                chain_hash,
+                full_information: full_information.value,
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MessageTypeElement::new(MessageType::ReplyShortChannelIdsEnd).to_bytes());
+        bytes.extend(self.chain_hash.to_bytes());
+        bytes.extend(Wire1Byte::new(self.full_information).to_bytes());
+        bytes
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NodeAnnouncementMessage {
     signature: SignatureElement,
-    features: FeaturesElement,
-    timestamp: u32,
+    pub features: FeaturesElement,
+    pub timestamp: u32,
     pub node_id: PointElement,
-    rgb_color: [u8; 3],
-    alias: NodeAliasElement,
-    addresses: NodeAddressesElement,
+    pub rgb_color: [u8; 3],
+    pub alias: NodeAliasElement,
+    pub addresses: NodeAddressesElement,
 }
 
 impl NodeAnnouncementMessage {
@@ -421,35 +1843,64 @@ impl SerializableToBytes for NodeAnnouncementMessage {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChannelUpdateMessage {
     signature: SignatureElement,
     chain_hash: ChainHashElement,
-    short_channel_id: ShortChannelIDElement,
-    timestamp: u32,
+    pub short_channel_id: ShortChannelIDElement,
+    pub timestamp: u32,
     message_flags: u8,
-    channel_flags: u8,
+    pub channel_flags: u8,
     cltv_expiry_delta: u16,
     htlc_minimum_msat: u64,
     fee_base_msat: u32,
     fee_proportional_millionths: u32,
-    htlc_maximum_msat: u64,
+    // only on the wire when bit 0 of message_flags is set, to_bytes sets the bit from this
+    pub htlc_maximum_msat: Option<u64>,
+}
+
+// message_flags bit 0: the update carries htlc_maximum_msat
+const MESSAGE_FLAG_HTLC_MAXIMUM: u8 = 1;
+
+impl ChannelUpdateMessage {
+    // 0 if the update is from node_id_1, 1 if it is from node_id_2
+    pub fn direction(&self) -> usize {
+        (self.channel_flags & 1) as usize
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.channel_flags & 2 != 0
+    }
+
+    // BOLT 7 checksum: crc32c over the update without its type, signature and timestamp
+    pub fn checksum(&self) -> u32 {
+        let bytes = self.to_bytes();
+        // type (2) + signature (64), then chain_hash (32) + short_channel_id (8)
+        let (head, tail) = bytes[66..].split_at(40);
+        // skip the 4-byte timestamp
+        crc32c(&[head, &tail[4..]].concat())
+    }
 }
 
 impl SerializableToBytes for ChannelUpdateMessage {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (_, data) = MessageTypeElement::from_bytes(data)?;
-        let (signature, data) = SignatureElement::from_bytes(data).unwrap();
-        let (chain_hash, data) = ChainHashElement::from_bytes(data).unwrap();
-        let (short_channel_id, data) = ShortChannelIDElement::from_bytes(data).unwrap();
-        let (timestamp, data) = TimestampElement::from_bytes(data).unwrap();
-        let (message_flags, data) = Wire1Byte::from_bytes(data).unwrap();
-        let (channel_flags, data) = Wire1Byte::from_bytes(data).unwrap();
-        let (cltv_expiry_delta, data) = WireU16Int::from_bytes(data).unwrap();
-        let (htlc_minimum_msat, data) = WireU64Int::from_bytes(data).unwrap();
-        let (fee_base_msat, data) = WireU32Int::from_bytes(data).unwrap();
-        let (fee_proportional_millionths, data) = WireU32Int::from_bytes(data).unwrap();
-        let (htlc_maximum_msat, data) = WireU64Int::from_bytes(data).unwrap();
+        let (signature, data) = SignatureElement::from_bytes(data)?;
+        let (chain_hash, data) = ChainHashElement::from_bytes(data)?;
+        let (short_channel_id, data) = ShortChannelIDElement::from_bytes(data)?;
+        let (timestamp, data) = TimestampElement::from_bytes(data)?;
+        let (message_flags, data) = Wire1Byte::from_bytes(data)?;
+        let (channel_flags, data) = Wire1Byte::from_bytes(data)?;
+        let (cltv_expiry_delta, data) = WireU16Int::from_bytes(data)?;
+        let (htlc_minimum_msat, data) = WireU64Int::from_bytes(data)?;
+        let (fee_base_msat, data) = WireU32Int::from_bytes(data)?;
+        let (fee_proportional_millionths, data) = WireU32Int::from_bytes(data)?;
+        let (htlc_maximum_msat, data) = if message_flags.value & MESSAGE_FLAG_HTLC_MAXIMUM != 0 {
+            let (htlc_maximum_msat, data) = WireU64Int::from_bytes(data)?;
+            (Some(htlc_maximum_msat.value), data)
+        } else {
+            (None, data)
+        };
 
         Ok((
             ChannelUpdateMessage {
@@ -463,7 +1914,7 @@ impl SerializableToBytes for ChannelUpdateMessage {
                 htlc_minimum_msat: htlc_minimum_msat.value,
                 fee_base_msat: fee_base_msat.value,
                 fee_proportional_millionths: fee_proportional_millionths.value,
-                htlc_maximum_msat: htlc_maximum_msat.value,
+                htlc_maximum_msat,
             },
             data,
         ))
@@ -476,20 +1927,26 @@ impl SerializableToBytes for ChannelUpdateMessage {
         bytes.extend(self.chain_hash.to_bytes());
         bytes.extend(self.short_channel_id.to_bytes());
         bytes.extend(TimestampElement::new(self.timestamp).to_bytes());
-        bytes.extend(Wire1Byte::new(self.message_flags).to_bytes());
+        let message_flags = match self.htlc_maximum_msat {
+            Some(_) => self.message_flags | MESSAGE_FLAG_HTLC_MAXIMUM,
+            None => self.message_flags & !MESSAGE_FLAG_HTLC_MAXIMUM,
+        };
+        bytes.extend(Wire1Byte::new(message_flags).to_bytes());
         bytes.extend(Wire1Byte::new(self.channel_flags).to_bytes());
         bytes.extend(WireU16Int::new(self.cltv_expiry_delta).to_bytes());
         bytes.extend(WireU64Int::new(self.htlc_minimum_msat).to_bytes());
         bytes.extend(WireU32Int::new(self.fee_base_msat).to_bytes());
         bytes.extend(WireU32Int::new(self.fee_proportional_millionths).to_bytes());
-        bytes.extend(WireU64Int::new(self.htlc_maximum_msat).to_bytes());
+        if let Some(htlc_maximum_msat) = self.htlc_maximum_msat {
+            bytes.extend(WireU64Int::new(htlc_maximum_msat).to_bytes());
+        }
         bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct UnknownMessage {
-    type_id: u16,
+    pub type_id: u16,
     data: Vec<u8>,
 }
 
@@ -523,3 +1980,572 @@ fn test_decode_init_message() {
     // check serialization
     assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
 }
+
+#[test]
+fn test_gossip_timestamp_filter_window() {
+    use crate::config::CHAIN_HASH;
+
+    let chain_hash = hex::decode(CHAIN_HASH).unwrap().try_into().unwrap();
+    let msg = GossipTimestampFilterMessage::new(chain_hash, 1740000000, 3600);
+    let bytes = msg.to_bytes();
+    let (decoded, remainder) = GossipTimestampFilterMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(decoded.first_timestamp, 1740000000);
+    assert_eq!(decoded.timestamp_range, 3600);
+    assert_eq!(hex::encode(decoded.chain_hash.value), CHAIN_HASH);
+}
+
+#[test]
+fn test_init_message_from_config() {
+    use crate::config::CHAIN_HASH;
+
+    // the default config must match what we used to hardcode
+    let msg = InitMessage::from_config(&PeerConfig::default());
+    assert_eq!(hex::encode(msg.to_bytes()), "001000000001aa");
+
+    let config = PeerConfig {
+        global_features: vec![0x02],
+        local_features: vec![0x08, 0xa0],
+        networks: vec![hex::decode(CHAIN_HASH).unwrap().try_into().unwrap()],
+        ..PeerConfig::default()
+    };
+    let bytes = InitMessage::from_config(&config).to_bytes();
+    let (msg, remainder) = InitMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.global_features.value.value, vec![0x02]);
+    assert_eq!(msg.local_features.value.value, vec![0x08, 0xa0]);
+    assert_eq!(hex::encode(&msg.tlv), format!("0120{}", CHAIN_HASH));
+}
+
+#[test]
+fn test_open_and_accept_channel_type() {
+    let initial_bytes = hex::decode("002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910fbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb00000000000f424000000000000000000000000000000222000000003b02338000000000000027100000000000000001000000fd009001e302c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14010103401000").unwrap();
+    let (msg, remainder) = OpenChannelMessage::from_bytes(&initial_bytes).unwrap();
+    assert_eq!(msg.funding_satoshis, 1_000_000);
+    let channel_type = msg.channel_type().unwrap();
+    assert!(matches!(
+        channel_type.feature_status(&Features::StaticRemoteKey),
+        FeatureFlag::Compulsory
+    ));
+    assert!(matches!(
+        channel_type.feature_status(&Features::Anchors),
+        FeatureFlag::Compulsory
+    ));
+    assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
+
+    let initial_bytes = hex::decode("0021bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb0000000000000222000000003b0233800000000000002710000000000000000100000003009001e302c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e140103401000").unwrap();
+    let (msg, remainder) = AcceptChannelMessage::from_bytes(&initial_bytes).unwrap();
+    assert_eq!(msg.minimum_depth, 3);
+    let channel_type = msg.channel_type().unwrap();
+    assert!(matches!(
+        channel_type.feature_status(&Features::Anchors),
+        FeatureFlag::Compulsory
+    ));
+    assert!(matches!(
+        channel_type.feature_status(&Features::PaymentSecret),
+        FeatureFlag::Unset
+    ));
+    assert_eq!([msg.to_bytes(), remainder.to_vec()].concat(), initial_bytes);
+}
+
+#[test]
+fn test_open_channel_fields() {
+    let bytes = hex::decode("002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910fbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb00000000000f424000000000000000000000000000000222000000003b02338000000000000027100000000000000001000000fd009001e302c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14010103401000").unwrap();
+    let (msg, remainder) = OpenChannelMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(hex::encode(msg.chain_hash.value), crate::config::CHAIN_HASH);
+    assert_eq!(msg.temporary_channel_id.value, [0xbb; 32]);
+    assert_eq!(msg.funding_satoshis, 1_000_000);
+    assert_eq!(msg.push_msat, 0);
+    assert_eq!(msg.dust_limit_satoshis, 546);
+    assert_eq!(msg.max_htlc_value_in_flight_msat, 990_000_000);
+    assert_eq!(msg.channel_reserve_satoshis, 10_000);
+    assert_eq!(msg.htlc_minimum_msat, 1);
+    assert_eq!(msg.feerate_per_kw, 253);
+    assert_eq!(msg.to_self_delay, 144);
+    assert_eq!(msg.max_accepted_htlcs, 483);
+    assert_eq!(
+        msg.funding_pubkey.value,
+        msg.first_per_commitment_point.value
+    );
+    assert_eq!(msg.channel_flags, 1);
+    assert_eq!(msg.to_bytes(), bytes);
+}
+
+#[test]
+fn test_accept_channel_fields() {
+    // the generator point as funding_pubkey and first_per_commitment_point, so a wrong
+    // offset for minimum_depth or max_accepted_htlcs shows up in the points
+    let bytes = hex::decode("0021bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb0000000000000222000000003b0233800000000000002710000000000000000100000003009001e30279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179802c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e140279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f817980103401000").unwrap();
+    let (msg, remainder) = AcceptChannelMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.temporary_channel_id.value, [0xbb; 32]);
+    assert_eq!(msg.dust_limit_satoshis, 546);
+    assert_eq!(msg.max_htlc_value_in_flight_msat, 990_000_000);
+    assert_eq!(msg.channel_reserve_satoshis, 10_000);
+    assert_eq!(msg.htlc_minimum_msat, 1);
+    assert_eq!(msg.minimum_depth, 3);
+    assert_eq!(msg.to_self_delay, 144);
+    assert_eq!(msg.max_accepted_htlcs, 483);
+    let generator = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    assert_eq!(hex::encode(msg.funding_pubkey.value), generator);
+    assert_eq!(hex::encode(msg.first_per_commitment_point.value), generator);
+    for point in [
+        &msg.revocation_basepoint,
+        &msg.payment_basepoint,
+        &msg.delayed_payment_basepoint,
+        &msg.htlc_basepoint,
+    ] {
+        assert_ne!(hex::encode(point.value), generator);
+    }
+    assert!(msg.channel_type().is_some());
+    assert_eq!(msg.to_bytes(), bytes);
+}
+
+#[test]
+fn test_open_and_accept_upfront_shutdown_script() {
+    let open_bytes = hex::decode("002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910fbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb00000000000f424000000000000000000000000000000222000000003b02338000000000000027100000000000000001000000fd009001e302c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14010103401000").unwrap();
+    let accept_bytes = hex::decode("0021bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb0000000000000222000000003b0233800000000000002710000000000000000100000003009001e302c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1402c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e140103401000").unwrap();
+    let (open, _) = OpenChannelMessage::from_bytes(&open_bytes).unwrap();
+    assert_eq!(open.upfront_shutdown_script(), None);
+    let (accept, _) = AcceptChannelMessage::from_bytes(&accept_bytes).unwrap();
+    assert_eq!(accept.upfront_shutdown_script(), None);
+
+    // a p2wpkh script as record type 0, ahead of the channel_type record that
+    // ends both fixtures
+    let mut script = vec![0x00, 0x14];
+    script.extend([0xcc; 20]);
+    let mut record = vec![0x00, script.len() as u8];
+    record.extend(&script);
+    let with_record = |bytes: &[u8], record: &[u8]| {
+        let tlv_start = bytes.len() - 5;
+        [&bytes[..tlv_start], record, &bytes[tlv_start..]].concat()
+    };
+    let (open, _) = OpenChannelMessage::from_bytes(&with_record(&open_bytes, &record)).unwrap();
+    assert_eq!(open.upfront_shutdown_script(), Some(script.clone()));
+    assert!(open.channel_type().is_some());
+    assert!(open.unknown_tlvs().is_empty());
+    let (accept, _) =
+        AcceptChannelMessage::from_bytes(&with_record(&accept_bytes, &record)).unwrap();
+    assert_eq!(accept.upfront_shutdown_script(), Some(script));
+
+    // zero length opts out of committing to a script
+    let (open, _) =
+        OpenChannelMessage::from_bytes(&with_record(&open_bytes, &[0x00, 0x00])).unwrap();
+    assert_eq!(open.upfront_shutdown_script(), Some(vec![]));
+}
+
+#[test]
+fn test_message_type_wire_names() {
+    for (message_type, name) in [
+        (MessageType::ChannelUpdate, "channel_update"),
+        (MessageType::UpdateAddHTLC, "update_add_htlc"),
+        (
+            MessageType::GossipTimestampFilter,
+            "gossip_timestamp_filter",
+        ),
+        (MessageType::OpenChannel2, "open_channel2"),
+    ] {
+        assert_eq!(message_type.wire_name(), name);
+        assert_eq!(MessageType::from_wire_name(name), Some(message_type));
+    }
+    assert_eq!(MessageType::from_wire_name("ChannelUpdate"), None);
+    for message_type in MessageType::iter() {
+        assert_eq!(
+            MessageType::from_wire_name(message_type.wire_name()),
+            Some(message_type)
+        );
+    }
+}
+
+#[test]
+fn test_init_features_merge_global_and_local() {
+    // gossip_queries (bit 7) only in global, var_onion_optin (bit 9) only in local
+    let init = InitMessage::new(vec![0x80], vec![0x02, 0x00], &[]);
+    assert_eq!(init.features().value.value, vec![0x02, 0x80]);
+    assert!(init.supports_feature(&Features::GossipQueries));
+    assert!(init.supports_feature(&Features::OnionOptin));
+    assert!(!init.supports_feature(&Features::DataLossProtect));
+    assert_eq!(
+        init.supported_features(),
+        HashSet::from([Features::GossipQueries, Features::OnionOptin])
+    );
+    // the longer vector may be either one
+    let init = InitMessage::new(vec![0x02, 0x00], vec![0x80], &[]);
+    assert_eq!(init.features().value.value, vec![0x02, 0x80]);
+    // data_loss_protect compulsory in global and optional in local is compulsory
+    let init = InitMessage::new(vec![0x01], vec![0x02], &[]);
+    assert!(matches!(
+        init.features().feature_status(&Features::DataLossProtect),
+        FeatureFlag::Compulsory
+    ));
+}
+
+#[test]
+fn test_error_roundtrip() {
+    let bytes = hex::decode("0011aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa001f696e7465726e616c206572726f723a2066756e64696e672074696d656f7574").unwrap();
+    let (error, remainder) = ErrorMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(error.channel_id.value, [0xaa; 32]);
+    assert!(!error.applies_to_all_channels());
+    assert_eq!(error.data_as_string(), "internal error: funding timeout");
+    assert_eq!(error.to_bytes(), bytes);
+
+    let error = ErrorMessage::for_connection("bye");
+    assert!(error.applies_to_all_channels());
+    let error = ErrorMessage {
+        data: vec![0x62, 0xff, 0x65],
+        ..error
+    };
+    assert_eq!(error.data_as_string(), "b\u{fffd}e");
+}
+
+#[test]
+fn test_warning_roundtrip() {
+    let warning = WarningMessage {
+        channel_id: ChannelIDElement { value: [0; 32] },
+        data: vec![],
+    };
+    let bytes = warning.to_bytes();
+    assert_eq!(bytes.len(), 36);
+    let (decoded, remainder) = WarningMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert!(decoded.applies_to_all_channels());
+    assert_eq!(decoded.data_as_string(), "");
+    assert_eq!(decoded.to_bytes(), bytes);
+
+    let warning = WarningMessage {
+        data: vec![0xc3, 0x28, 0xff],
+        ..decoded
+    };
+    let (decoded, _) = WarningMessage::from_bytes(&warning.to_bytes()).unwrap();
+    assert_eq!(decoded.data, vec![0xc3, 0x28, 0xff]);
+    assert_eq!(decoded.data_as_string(), "\u{fffd}(\u{fffd}");
+}
+
+#[test]
+fn test_message_type_ids_roundtrip() {
+    // from_int and the derived TryFromPrimitive are two lookups over the one enum,
+    // they must agree on every id
+    for message_type in MessageType::iter() {
+        let id = message_type.as_u16();
+        assert_eq!(MessageType::from_int(id), Some(message_type));
+        assert_eq!(MessageType::try_from(id).ok(), Some(message_type));
+    }
+    assert_eq!(MessageType::from_int(0), Some(MessageType::Unknown));
+    assert_eq!(MessageType::from_int(1), Some(MessageType::Warning));
+    assert_eq!(MessageType::from_int(3), None);
+    assert!(MessageType::try_from(3).is_err());
+}
+
+#[test]
+fn test_init_custom_tlv_roundtrip() {
+    use crate::config::CHAIN_HASH;
+
+    let chain_hash: [u8; 32] = hex::decode(CHAIN_HASH).unwrap().try_into().unwrap();
+    let init = InitMessage::new(vec![], vec![0xaa], &[chain_hash])
+        .with_custom_tlv(65537, vec![0xde, 0xad])
+        .with_custom_tlv(3, vec![0xbe, 0xef]);
+    let bytes = init.to_bytes();
+    let (decoded, remainder) = InitMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(decoded.to_bytes(), bytes);
+    assert_eq!(decoded.networks(), Some(vec![chain_hash]));
+
+    let records = TLVRecord::parse_stream(&decoded.tlv).unwrap();
+    let types: Vec<u64> = records.iter().map(|record| record.record_type).collect();
+    assert_eq!(types, vec![INIT_TLV_NETWORKS, 3, 65537]);
+    assert_eq!(
+        TLVRecord::find(&records, 65537).unwrap().value,
+        vec![0xde, 0xad]
+    );
+}
+
+#[test]
+fn test_failure_codes() {
+    for (code, failure_code, name) in [
+        (
+            0xc004,
+            FailureCode::InvalidOnionVersion,
+            "invalid_onion_version",
+        ),
+        (
+            0x1007,
+            FailureCode::TemporaryChannelFailure,
+            "temporary_channel_failure",
+        ),
+        (
+            0x400f,
+            FailureCode::IncorrectOrUnknownPaymentDetails,
+            "incorrect_or_unknown_payment_details",
+        ),
+        (0x0017, FailureCode::MppTimeout, "mpp_timeout"),
+    ] {
+        assert_eq!(FailureCode::from_u16(code), failure_code);
+        assert_eq!(failure_code.to_u16(), code);
+        assert_eq!(failure_code.to_string(), name);
+    }
+    let unknown = FailureCode::from_u16(0x4242);
+    assert_eq!(unknown, FailureCode::Unknown(0x4242));
+    assert_eq!(unknown.to_u16(), 0x4242);
+    assert_eq!(unknown.to_string(), "unknown_failure_code(0x4242)");
+}
+
+#[test]
+fn test_channel_ready_alias_tlv() {
+    let point = "02c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14";
+    let bytes = hex::decode(format!("0024{}{}", "aa".repeat(32), point)).unwrap();
+    let (msg, remainder) = ChannelReadyMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.channel_id.value, [0xaa; 32]);
+    assert_eq!(hex::encode(msg.second_per_commitment_point.value), point);
+    assert!(msg.scid_alias().is_none());
+    assert_eq!(msg.to_bytes(), bytes);
+
+    let mut with_alias = bytes.clone();
+    with_alias.extend(hex::decode("01080000710000010000").unwrap());
+    let (msg, remainder) = ChannelReadyMessage::from_bytes(&with_alias).unwrap();
+    assert!(remainder.is_empty());
+    let alias = msg.scid_alias().unwrap();
+    assert_eq!(alias.block_height, 113);
+    assert_eq!(alias.tx_index, 1);
+    assert_eq!(alias.output_index, 0);
+    assert!(msg.unknown_tlvs().is_empty());
+    assert_eq!(msg.to_bytes(), with_alias);
+
+    // a record too short for a scid is not an alias
+    let mut short_alias = bytes;
+    short_alias.extend(hex::decode("01020000").unwrap());
+    let (msg, _) = ChannelReadyMessage::from_bytes(&short_alias).unwrap();
+    assert!(msg.scid_alias().is_none());
+}
+
+#[test]
+fn test_funding_signed_signature() {
+    use crate::util::SECP;
+    use bitcoin::secp256k1::{Message, SecretKey};
+
+    let secp = &*SECP;
+    let key = SecretKey::from_slice(&[7; 32]).unwrap();
+    let digest = Message::from_digest([1; 32]);
+    let signature = secp.sign_ecdsa(&digest, &key);
+    let mut bytes = hex::decode("0023").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(signature.serialize_compact());
+
+    let (msg, remainder) = FundingSignedMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.signature.as_bytes(), &signature.serialize_compact());
+    let decoded = msg.as_secp_signature().unwrap();
+    assert!(secp
+        .verify_ecdsa(&digest, &decoded, &key.public_key(secp))
+        .is_ok());
+    assert_eq!(msg.to_bytes(), bytes);
+
+    // r and s must be below the curve order
+    bytes[34..].copy_from_slice(&[0xff; 64]);
+    let (msg, _) = FundingSignedMessage::from_bytes(&bytes).unwrap();
+    assert!(msg.as_secp_signature().is_err());
+}
+
+#[test]
+fn test_funding_created_fields() {
+    let mut bytes = hex::decode("0022").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend([0xbb; 32]);
+    bytes.extend([0xff, 0xfe]);
+    bytes.extend([0x01; 64]);
+    let (msg, remainder) = FundingCreatedMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.funding_output_index, 0xfffe);
+    assert_eq!(msg.funding_txid.value, [0xbb; 32]);
+    assert_eq!(msg.to_bytes(), bytes);
+}
+
+#[test]
+fn test_update_add_htlc_onion_is_preserved() {
+    let onion: Vec<u8> = (0..ONION_PACKET_LEN).map(|i| (i % 251) as u8).collect();
+    let mut bytes = hex::decode("0080").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(7u64.to_be_bytes());
+    bytes.extend(1000u64.to_be_bytes());
+    bytes.extend([0xbb; 32]);
+    bytes.extend(800_000u32.to_be_bytes());
+    bytes.extend(&onion);
+    // blinded_path
+    bytes.extend(hex::decode("00021234").unwrap());
+    let (msg, remainder) = UpdateAddHTLCMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.payment_hash.value, [0xbb; 32]);
+    assert_eq!(msg.onion_routing_packet.value.to_vec(), onion);
+    assert!(msg.unknown_tlvs().is_empty());
+    assert_eq!(msg.to_bytes(), bytes);
+}
+
+#[test]
+fn test_query_short_channel_ids_roundtrip() {
+    let short_channel_ids = vec![
+        ShortChannelIDElement {
+            block_height: 113,
+            tx_index: 1,
+            output_index: 0,
+        },
+        ShortChannelIDElement {
+            block_height: 114,
+            tx_index: 2,
+            output_index: 1,
+        },
+    ];
+    let query = QueryShortChannelIdsMessage::new([6; 32], &short_channel_ids);
+    let bytes = query.to_bytes();
+    // type, chain_hash, length, encoding byte and two scids
+    assert_eq!(bytes.len(), 2 + 32 + 2 + 1 + 16);
+    let (query, remainder) = QueryShortChannelIdsMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(query.short_channel_ids().unwrap(), short_channel_ids);
+    assert_eq!(query.to_bytes(), bytes);
+
+    let mut bytes = hex::decode("0106").unwrap();
+    bytes.extend([6; 32]);
+    bytes.push(0);
+    let (end, remainder) = ReplyShortChannelIdsEndMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(end.full_information, 0);
+    assert_eq!(end.to_bytes(), bytes);
+}
+
+#[test]
+fn test_update_fulfill_and_fail_htlc_roundtrip() {
+    let mut bytes = hex::decode("0082").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(7u64.to_be_bytes());
+    bytes.extend([0; 32]);
+    let (fulfill, remainder) = UpdateFulfillHTLCMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(fulfill.id, 7);
+    assert_eq!(fulfill.to_bytes(), bytes);
+    // sha256 of 32 zero bytes
+    assert_eq!(
+        hex::encode(fulfill.payment_hash()),
+        "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925"
+    );
+
+    let mut bytes = hex::decode("0083").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(7u64.to_be_bytes());
+    bytes.extend([0x00, 0x03, 0x01, 0x02, 0x03]);
+    let (fail, remainder) = UpdateFailHTLCMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(fail.id, 7);
+    assert_eq!(fail.reason, vec![1, 2, 3]);
+    assert_eq!(fail.to_bytes(), bytes);
+    // the reason is cut short
+    assert!(UpdateFailHTLCMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn test_update_add_htlc_rejects_short_and_zero_amount() {
+    use crate::message_decoder::{MessageDecoder, MessageDecoderError};
+
+    let mut bytes = hex::decode("0080").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(7u64.to_be_bytes());
+    bytes.extend(1000u64.to_be_bytes());
+    bytes.extend([0xbb; 32]);
+    bytes.extend(800_000u32.to_be_bytes());
+    bytes.extend([0xcc; ONION_PACKET_LEN]);
+    let (msg, remainder) = UpdateAddHTLCMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.amount_msat, 1000);
+    assert_eq!(msg.cltv_expiry, 800_000);
+    assert_eq!(msg.to_bytes(), bytes);
+
+    // cut off inside the onion
+    let short = &bytes[..bytes.len() - 1];
+    assert!(matches!(
+        UpdateAddHTLCMessage::from_bytes(short),
+        Err(SerializationError::TooFewBytes)
+    ));
+    assert!(matches!(
+        MessageDecoder::from_bytes(short),
+        Err(MessageDecoderError::TooFewBytes { .. })
+    ));
+
+    bytes[42..50].copy_from_slice(&[0; 8]);
+    assert!(matches!(
+        UpdateAddHTLCMessage::from_bytes(&bytes),
+        Err(SerializationError::InvalidValue)
+    ));
+}
+
+#[test]
+fn test_closing_signed_fee_range() {
+    let mut bytes = hex::decode("0027").unwrap();
+    bytes.extend([0xaa; 32]);
+    bytes.extend(1_500u64.to_be_bytes());
+    bytes.extend([0x01; 64]);
+    let (msg, _) = ClosingSignedMessage::from_bytes(&bytes).unwrap();
+    assert_eq!(msg.fee_satoshis, 1_500);
+    assert_eq!(msg.fee_range(), None);
+
+    // fee_range tlv: type 1, length 16
+    bytes.extend([0x01, 0x10]);
+    bytes.extend(1_000u64.to_be_bytes());
+    bytes.extend(2_000u64.to_be_bytes());
+    let (msg, remainder) = ClosingSignedMessage::from_bytes(&bytes).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(msg.fee_range(), Some((1_000, 2_000)));
+    assert_eq!(msg.to_bytes(), bytes);
+}
+
+#[test]
+fn test_unknown_tlvs_are_preserved() {
+    let init = InitMessage::new(vec![], vec![0xaa], &[[6; 32]]).with_custom_tlv(65537, vec![1, 2]);
+    let bytes = init.to_bytes();
+    let (init, _) = InitMessage::from_bytes(&bytes).unwrap();
+    assert_eq!(init.unknown_tlvs(), vec![TLVRecord::new(65537, vec![1, 2])]);
+    assert_eq!(init.networks(), Some(vec![[6; 32]]));
+    assert_eq!(init.to_bytes(), bytes);
+
+    let query = QueryChannelRangeMessage::new([6; 32], 0, 10).with_query_option(1);
+    assert!(query.unknown_tlvs().is_empty());
+}
+
+#[test]
+fn test_truncated_channel_update_is_an_error() {
+    use crate::test_util::example_message;
+
+    // every cut short of the fields message_flags says are there
+    let bytes = example_message(MessageType::ChannelUpdate);
+    for len in 0..bytes.len() {
+        assert!(ChannelUpdateMessage::from_bytes(&bytes[..len]).is_err());
+    }
+}
+
+#[test]
+fn test_channel_update_optional_htlc_maximum_roundtrip() {
+    use crate::test_util::example_message;
+
+    // message_flags 0x01, htlc_maximum_msat 10_000_000_000
+    let with_max = example_message(MessageType::ChannelUpdate);
+    let (update, remainder) = ChannelUpdateMessage::from_bytes(&with_max).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(update.htlc_maximum_msat, Some(10_000_000_000));
+    assert_eq!(update.to_bytes(), with_max);
+
+    // same update with message_flags 0x00 and no trailing htlc_maximum_msat
+    let mut without_max = with_max[..with_max.len() - 8].to_vec();
+    without_max[110] = 0;
+    let (update, remainder) = ChannelUpdateMessage::from_bytes(&without_max).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(update.htlc_maximum_msat, None);
+    assert_eq!(update.to_bytes(), without_max);
+
+    // dropping or adding the field keeps the flag in step
+    let mut dropped = ChannelUpdateMessage::from_bytes(&with_max).unwrap().0;
+    dropped.htlc_maximum_msat = None;
+    assert_eq!(dropped.to_bytes(), without_max);
+    let mut added = update;
+    added.htlc_maximum_msat = Some(10_000_000_000);
+    assert_eq!(added.to_bytes(), with_max);
+}
@@ -1,6 +1,8 @@
+use std::hash::{Hash, Hasher};
+
 use bitcoin::secp256k1::PublicKey;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Node {
     pub public_key: [u8; 33],
     pub ip_address: String,
@@ -47,3 +49,40 @@ impl Node {
         format!("{}@{}", hex::encode(self.public_key), self.address())
     }
 }
+
+// a node is its public key, the same node can be reached at many addresses
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+impl Eq for Node {}
+
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.public_key.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    const PUBKEY: &str = "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+
+    #[test]
+    fn test_nodes_with_same_key_are_equal() {
+        let a = Node::from_str(&format!("{}@127.0.0.1:9735", PUBKEY)).unwrap();
+        let b = Node::from_str(&format!("{}@10.0.0.1:9736", PUBKEY)).unwrap();
+        assert_eq!(a, b);
+        let mut c = Node::from_str(&format!("{}@127.0.0.1:9735", PUBKEY)).unwrap();
+        c.public_key[1] ^= 1;
+        assert_ne!(a, c);
+
+        let nodes: HashSet<Node> = [a, b, c].into_iter().collect();
+        assert_eq!(nodes.len(), 2);
+    }
+}
@@ -1,20 +1,40 @@
-use crate::config::PING_INTERVAL;
+use crate::message_decoder::peek_message_type;
 use crate::message_decoder::MessageContainer;
 use crate::message_decoder::MessageDecoder;
-use crate::messages::PingMessage;
-use crate::serialization::IgnoredBytesElement;
+use crate::message_decoder::MessageDecoderError;
+use crate::messages::{ErrorMessage, MessageType, PingMessage, PongMessage};
+use crate::serialization::{with_strict_point_decoding, IgnoredBytesElement};
 use crate::vendor::{KeysManager, LightningError, MessageBuf, NextNoiseStep};
 use bitcoin::secp256k1::PublicKey as BitcoinPublicKey;
-use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1::SecretKey;
-use bitcoin::secp256k1::SignOnly;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
 
 use crate::node::Node;
-use crate::util::{get_current_timestamp, new_random_secret_key};
+use crate::util::{new_random_secret_key, SECP};
 use crate::vendor::PeerChannelEncryptor;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+// tokio's clock so tests can pause and advance it
+use tokio::time::Instant;
+
+// BOLT 8 handshake act and transport framing sizes
+const ACT_ONE_LEN: usize = 50;
+const ACT_TWO_LEN: usize = 50;
+const ACT_THREE_LEN: usize = 66;
+const HEADER_LEN: usize = 18;
+const MAC_LEN: usize = 16;
+// how long each resolved address of a node gets before we move on to the next
+const CONNECT_TIMEOUT_PER_ADDRESS: Duration = Duration::from_secs(5);
+// ping round trips kept per connection for rtt_stats
+const RTT_SAMPLES: usize = 20;
+// largest cleartext a transport frame can carry
+const MAX_MESSAGE_LEN: usize = 65535;
+// pong size our pings ask for
+const PING_NUM_PONG_BYTES: u16 = 100;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -22,26 +42,157 @@ pub enum NodeConnectionError {
     HandshakeFailed,
     NoMessageFound,
     InvalidHeaderLength,
-    DecryptionError(LightningError),
+    // with how many messages each direction had gone through, to spot nonce desyncs
+    DecryptionError {
+        err: LightningError,
+        encrypted: u64,
+        decrypted: u64,
+    },
     ConnectionError(std::io::Error),
     IOError(std::io::Error),
     LightningError(LightningError),
-    MessageDecodeError,
+    MessageDecodeError(MessageDecoderError),
+    MalformedMessage,
+    // the cleartext doesn't fit the 2-byte length of a single transport frame
+    MessageTooLarge(usize),
+    // tried to send before the noise handshake completed
+    NotReady,
+    // the message can't be sent in the connection's current state
+    NotAllowed(ConnState),
+}
+
+impl From<MessageDecoderError> for NodeConnectionError {
+    fn from(err: MessageDecoderError) -> Self {
+        NodeConnectionError::MessageDecodeError(err)
+    }
+}
+
+impl fmt::Display for NodeConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeConnectionError::HandshakeFailed => write!(f, "handshake failed"),
+            NodeConnectionError::NoMessageFound => write!(f, "no message found"),
+            NodeConnectionError::InvalidHeaderLength => write!(f, "invalid header length"),
+            NodeConnectionError::DecryptionError {
+                err,
+                encrypted,
+                decrypted,
+            } => write!(
+                f,
+                "decryption failed after {} messages decrypted and {} encrypted: {}",
+                decrypted, encrypted, err.err
+            ),
+            NodeConnectionError::ConnectionError(_) => write!(f, "failed to connect"),
+            NodeConnectionError::IOError(_) => write!(f, "io error"),
+            NodeConnectionError::LightningError(err) => write!(f, "lightning error: {}", err.err),
+            NodeConnectionError::MessageDecodeError(_) => write!(f, "failed to decode message"),
+            NodeConnectionError::MalformedMessage => write!(f, "malformed message"),
+            NodeConnectionError::MessageTooLarge(len) => {
+                write!(f, "message of {} bytes exceeds {}", len, MAX_MESSAGE_LEN)
+            }
+            NodeConnectionError::NotReady => write!(f, "transport not established"),
+            NodeConnectionError::NotAllowed(state) => {
+                write!(f, "message not allowed while {}", state)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NodeConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NodeConnectionError::ConnectionError(err) | NodeConnectionError::IOError(err) => {
+                Some(err)
+            }
+            NodeConnectionError::MessageDecodeError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for ConnectionDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionDirection::Inbound => write!(f, "inbound"),
+            ConnectionDirection::Outbound => write!(f, "outbound"),
+        }
+    }
 }
 
-pub struct NodeConnection {
+// Handshaking -> AwaitingInit once the noise acts are done -> Ready once init went both
+// ways. Closing from close() on, whatever the state was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    Handshaking,
+    AwaitingInit,
+    Ready,
+    Closing,
+}
+
+impl fmt::Display for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnState::Handshaking => write!(f, "handshaking"),
+            ConnState::AwaitingInit => write!(f, "awaiting init"),
+            ConnState::Ready => write!(f, "ready"),
+            ConnState::Closing => write!(f, "closing"),
+        }
+    }
+}
+
+// Generic over the byte stream so the handshake and framing can run over anything,
+// tcp is just what we dial and accept.
+pub struct NodeConnection<S = TcpStream> {
     pub public_key: [u8; 33],
-    last_contacted: u64,
-    stream: TcpStream,
-    secp: Secp256k1<SignOnly>,
+    pub direction: ConnectionDirection,
+    // set once the handshake completes
+    pub handshake_duration: Option<Duration>,
+    state: ConnState,
+    init_sent: bool,
+    init_received: bool,
+    last_contacted: Instant,
+    // set by send_ping, cleared by the next message the peer sends
+    ping_sent_at: Option<Instant>,
+    // when our last ping went out and its num_pong_bytes, until a pong of that size
+    // answers it
+    pong_expected: Option<(Instant, u16)>,
+    // most recent ping round trips, oldest first
+    rtt_samples: VecDeque<Duration>,
+    // buffered so has_message_within can wait for data without consuming it
+    stream: BufReader<S>,
     peer_encryptor: PeerChannelEncryptor,
     km: Arc<KeysManager>,
+    // messages read while waiting in ping_rtt, handed out by read_next_message first
+    pending: VecDeque<MessageContainer>,
+    // reused for every transport read, grows to the largest message seen
+    read_buffer: Vec<u8>,
+    // reject messages carrying points that aren't valid public keys
+    strict_points: bool,
+    // transport messages through the noise state, each one advances a nonce
+    messages_encrypted: u64,
+    messages_decrypted: u64,
+    // per message type, taken from the first two bytes so undecodable messages count too
+    received_types: HashMap<MessageType, u64>,
 }
 
-impl NodeConnection {
+impl NodeConnection<TcpStream> {
     pub async fn new(node: &Node, node_secret_key: SecretKey) -> Result<Self, NodeConnectionError> {
-        let ephemeral_key = new_random_secret_key();
-        let stream = match TcpStream::connect(node.address()).await {
+        NodeConnection::new_with_ephemeral(node, node_secret_key, new_random_secret_key()).await
+    }
+
+    // a fixed ephemeral key makes the handshake acts deterministic, only tests should pass one
+    pub async fn new_with_ephemeral(
+        node: &Node,
+        node_secret_key: SecretKey,
+        ephemeral_key: SecretKey,
+    ) -> Result<Self, NodeConnectionError> {
+        let stream = match connect_any(&node.address(), CONNECT_TIMEOUT_PER_ADDRESS).await {
             Ok(stream) => stream,
             Err(err) => {
                 println!("Failed to connect to {}: {}", node.address(), err);
@@ -49,36 +200,219 @@ impl NodeConnection {
             }
         };
         println!("Connected to {}", node.display_str());
-        Ok(NodeConnection {
-            public_key: node.public_key,
-            last_contacted: get_current_timestamp(),
+        Ok(NodeConnection::outbound(
             stream,
-            secp: Secp256k1::signing_only(),
-            peer_encryptor: PeerChannelEncryptor::new_outbound(
-                node.bitcoin_public_key().clone(),
-                ephemeral_key,
-            ),
+            node.bitcoin_public_key(),
+            node_secret_key,
+            ephemeral_key,
+        ))
+    }
+
+    // lightning messages are small and latency sensitive, so Nagle only gets in the way
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), NodeConnectionError> {
+        match self.stream.get_ref().set_nodelay(nodelay) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(NodeConnectionError::IOError(err)),
+        }
+    }
+
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.stream.get_ref().peer_addr().ok()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NodeConnection<S> {
+    // an outbound connection over an already open stream, handshake() starts it
+    pub fn outbound(
+        stream: S,
+        remote_public_key: BitcoinPublicKey,
+        node_secret_key: SecretKey,
+        ephemeral_key: SecretKey,
+    ) -> Self {
+        NodeConnection {
+            public_key: remote_public_key.serialize(),
+            direction: ConnectionDirection::Outbound,
+            handshake_duration: None,
+            state: ConnState::Handshaking,
+            init_sent: false,
+            init_received: false,
+            last_contacted: Instant::now(),
+            ping_sent_at: None,
+            pong_expected: None,
+            rtt_samples: VecDeque::new(),
+            stream: BufReader::new(stream),
+            peer_encryptor: PeerChannelEncryptor::new_outbound(remote_public_key, ephemeral_key),
             km: Arc::new(KeysManager::new(&node_secret_key.secret_bytes(), 0, 0)),
-        })
+            pending: VecDeque::new(),
+            read_buffer: Vec::new(),
+            strict_points: false,
+            messages_encrypted: 0,
+            messages_decrypted: 0,
+            received_types: HashMap::new(),
+        }
+    }
+
+    // performs the responder side of the handshake on a stream a peer opened to us
+    #[allow(dead_code)]
+    pub async fn accept(
+        stream: S,
+        node_secret_key: SecretKey,
+    ) -> Result<Self, NodeConnectionError> {
+        let km = Arc::new(KeysManager::new(&node_secret_key.secret_bytes(), 0, 0));
+        let mut node_connection = NodeConnection {
+            // unknown until act three
+            public_key: [0; 33],
+            direction: ConnectionDirection::Inbound,
+            handshake_duration: None,
+            state: ConnState::Handshaking,
+            init_sent: false,
+            init_received: false,
+            last_contacted: Instant::now(),
+            ping_sent_at: None,
+            pong_expected: None,
+            rtt_samples: VecDeque::new(),
+            stream: BufReader::new(stream),
+            peer_encryptor: PeerChannelEncryptor::new_inbound(&km),
+            km,
+            pending: VecDeque::new(),
+            read_buffer: Vec::new(),
+            strict_points: false,
+            messages_encrypted: 0,
+            messages_decrypted: 0,
+            received_types: HashMap::new(),
+        };
+        let public_key = node_connection.respond_handshake().await?;
+        node_connection.public_key = public_key.serialize();
+        Ok(node_connection)
+    }
+
+    pub fn set_strict_points(&mut self, strict_points: bool) {
+        self.strict_points = strict_points;
     }
 
     fn update_last_contacted(&mut self) {
-        self.last_contacted = get_current_timestamp();
+        self.last_contacted = Instant::now();
+    }
+
+    pub fn state(&self) -> ConnState {
+        self.state
+    }
+
+    fn init_exchanged(&mut self) {
+        if self.state == ConnState::AwaitingInit && self.init_sent && self.init_received {
+            self.state = ConnState::Ready;
+        }
+    }
+
+    // BOLT 1: init goes first. Errors and warnings may still be sent, they are how a
+    // connection is refused.
+    fn check_can_send(&self, message: &MessageContainer) -> Result<(), NodeConnectionError> {
+        let allowed = match self.state {
+            // the encryptor can't send anything yet either
+            ConnState::Handshaking => return Err(NodeConnectionError::NotReady),
+            ConnState::AwaitingInit => matches!(
+                message.message_type(),
+                MessageType::Init | MessageType::Error | MessageType::Warning
+            ),
+            ConnState::Ready => true,
+            ConnState::Closing => false,
+        };
+        if !allowed {
+            return Err(NodeConnectionError::NotAllowed(self.state));
+        }
+        Ok(())
+    }
+
+    fn message_received(&mut self, message: &MessageContainer) {
+        self.update_last_contacted();
+        if let MessageContainer::Init(_) = message {
+            self.init_received = true;
+            self.init_exchanged();
+        }
+        // any message shows the peer is alive, answers_ping times the ping
+        self.ping_sent_at = None;
+    }
+
+    fn record_rtt(&mut self, rtt: Duration) {
+        if self.rtt_samples.len() == RTT_SAMPLES {
+            self.rtt_samples.pop_front();
+        }
+        self.rtt_samples.push_back(rtt);
+    }
+
+    // (min, avg, max) over the recent ping round trips, None before the first pong
+    #[allow(dead_code)]
+    pub fn rtt_stats(&self) -> Option<(Duration, Duration, Duration)> {
+        let min = *self.rtt_samples.iter().min()?;
+        let max = *self.rtt_samples.iter().max()?;
+        let avg = self.rtt_samples.iter().sum::<Duration>() / self.rtt_samples.len() as u32;
+        Some((min, avg, max))
     }
 
-    pub fn ready_for_ping(&self) -> bool {
-        self.last_contacted + PING_INTERVAL < get_current_timestamp()
+    // quiet for at least interval and not still waiting on an earlier ping
+    pub fn ready_for_ping(&self, interval: Duration) -> bool {
+        self.state == ConnState::Ready
+            && self.ping_sent_at.is_none()
+            && self.last_contacted.elapsed() >= interval
+    }
+
+    // our ping has gone unanswered for timeout, the connection is likely dead
+    pub fn is_unresponsive(&self, timeout: Duration) -> bool {
+        self.ping_sent_at
+            .is_some_and(|sent_at| sent_at.elapsed() >= timeout)
     }
 
     pub async fn send_ping(&mut self) -> Result<(), NodeConnectionError> {
         let wrapped = MessageContainer::Ping(PingMessage {
-            num_pong_bytes: 100,
+            num_pong_bytes: PING_NUM_PONG_BYTES,
             ignored: IgnoredBytesElement::new([0; 10].to_vec()),
         });
         self.encrypt_and_send_message(&wrapped).await?;
+        let sent_at = Instant::now();
+        self.ping_sent_at = Some(sent_at);
+        self.pong_expected = Some((sent_at, PING_NUM_PONG_BYTES));
         Ok(())
     }
 
+    // BOLT 1: a pong must answer a ping and carry the num_pong_bytes it asked for.
+    // True if this one does, our ping is then no longer outstanding and its round trip
+    // is recorded.
+    pub fn answers_ping(&mut self, pong: &PongMessage) -> bool {
+        match self.pong_expected {
+            Some((sent_at, num_pong_bytes)) if num_pong_bytes as usize == pong.byteslen() => {
+                self.pong_expected = None;
+                self.record_rtt(sent_at.elapsed());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Sends a ping and waits for the pong. The peer may be doing the same and waiting
+    // on us, so inbound pings are answered while we wait. Anything else, a pong that
+    // doesn't answer our ping included, is queued for read_next_message.
+    #[allow(dead_code)]
+    pub async fn ping_rtt(&mut self) -> Result<Duration, NodeConnectionError> {
+        let sent_at = Instant::now();
+        self.send_ping().await?;
+        loop {
+            self.wait_for_message().await?;
+            let bytes = self.read_next_message_bytes().await?;
+            let message = self.decode_received(&bytes)?;
+            self.message_received(&message);
+            match message {
+                MessageContainer::Pong(pong) if self.answers_ping(&pong) => {
+                    return Ok(sent_at.elapsed())
+                }
+                MessageContainer::Ping(ping) => {
+                    let pong = MessageContainer::Pong(PongMessage::from_ping(ping));
+                    self.encrypt_and_send_message(&pong).await?;
+                }
+                other => self.pending.push_back(other),
+            }
+        }
+    }
+
     async fn write_raw_data(&mut self, data: &[u8]) -> Result<(), NodeConnectionError> {
         match self.stream.write_all(data).await {
             Ok(_) => Ok(()),
@@ -101,91 +435,998 @@ impl NodeConnection {
     }
 
     pub async fn handshake(&mut self) -> Result<BitcoinPublicKey, NodeConnectionError> {
-        let act_one = self.peer_encryptor.get_act_one(&self.secp);
+        let started = Instant::now();
+        let act_one = self.peer_encryptor.get_act_one(&*SECP);
         match self.write_raw_data(&act_one).await {
             Ok(_) => (),
             Err(err) => return Err(err),
         }
-        let act_two = self.read_exact_n_bytes(50).await?;
+        let act_two = self.read_handshake_act(ACT_TWO_LEN).await?;
         let (act_three, public_key) = match self.peer_encryptor.process_act_two(&act_two, &self.km)
         {
             Ok((x, y)) => (x, y),
-            Err(err) => return Err(NodeConnectionError::LightningError(err)),
+            Err(err) => return Err(not_lightning_noise(&act_two, err)),
         };
-        assert_eq!(act_three.len(), 66);
-        match self.write_raw_data(&act_three).await {
-            Ok(_) => (),
-            Err(err) => return Err(err),
+        assert_eq!(act_three.len(), ACT_THREE_LEN);
+        self.send_act_three(&act_three).await?;
+        self.handshake_duration = Some(started.elapsed());
+        self.state = ConnState::AwaitingInit;
+        println!("Handshake completed with {}", public_key);
+
+        self.update_last_contacted();
+        Ok(public_key)
+    }
+
+    // The handshake is only done once act three is fully on the wire and the noise
+    // state is complete, otherwise the peer would get init over a half-open channel.
+    async fn send_act_three(&mut self, act_three: &[u8]) -> Result<(), NodeConnectionError> {
+        if let Err(err) = self.write_raw_data(act_three).await {
+            println!("Failed to send act three: {:?}", err);
+            return Err(NodeConnectionError::HandshakeFailed);
+        }
+        match self.peer_encryptor.get_noise_step() {
+            NextNoiseStep::NoiseComplete => Ok(()),
+            _ => Err(NodeConnectionError::HandshakeFailed),
+        }
+    }
+
+    // true once the handshake completed and the transport keys are in place
+    pub fn is_transport_ready(&self) -> bool {
+        matches!(
+            self.peer_encryptor.get_noise_step(),
+            NextNoiseStep::NoiseComplete
+        )
+    }
+
+    async fn respond_handshake(&mut self) -> Result<BitcoinPublicKey, NodeConnectionError> {
+        let started = Instant::now();
+        let act_one = self.read_handshake_act(ACT_ONE_LEN).await?;
+        let act_two = match self.peer_encryptor.process_act_one_with_keys(
+            &act_one,
+            &self.km,
+            new_random_secret_key(),
+            &*SECP,
+        ) {
+            Ok(act_two) => act_two,
+            Err(err) => return Err(not_lightning_noise(&act_one, err)),
+        };
+        self.write_raw_data(&act_two).await?;
+        let act_three = self.read_handshake_act(ACT_THREE_LEN).await?;
+        let public_key = match self.peer_encryptor.process_act_three(&act_three) {
+            Ok(public_key) => public_key,
+            Err(err) => return Err(NodeConnectionError::LightningError(err)),
         };
         match self.peer_encryptor.get_noise_step() {
             NextNoiseStep::NoiseComplete => println!("Handshake completed with {}", public_key),
             _ => return Err(NodeConnectionError::HandshakeFailed),
         }
+        self.handshake_duration = Some(started.elapsed());
+        self.state = ConnState::AwaitingInit;
 
         self.update_last_contacted();
         Ok(public_key)
     }
 
+    // a peer closing the stream before sending a full act is a failed handshake,
+    // not a generic io error
+    async fn read_handshake_act(
+        &mut self,
+        num_bytes: usize,
+    ) -> Result<Vec<u8>, NodeConnectionError> {
+        match self.read_exact_n_bytes(num_bytes).await {
+            Ok(act) => Ok(act),
+            Err(NodeConnectionError::IOError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                Err(NodeConnectionError::HandshakeFailed)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // true if read_next_message would find something within wait. Unlike a read this
+    // is safe to give up on, bytes that arrive stay in the stream's buffer. The end of
+    // the stream counts too, so the read that follows can report it.
+    pub async fn has_message_within(&mut self, wait: Duration) -> bool {
+        if !self.pending.is_empty() {
+            return true;
+        }
+        matches!(
+            tokio::time::timeout(wait, self.stream.fill_buf()).await,
+            Ok(Ok(_))
+        )
+    }
+
     async fn wait_for_message(&mut self) -> Result<(), NodeConnectionError> {
-        match self.stream.readable().await {
+        match self.stream.fill_buf().await {
             Ok(_) => Ok(()),
             Err(err) => Err(NodeConnectionError::IOError(err)),
         }
     }
 
-    async fn read_next_message_bytes(&mut self) -> Result<Vec<u8>, NodeConnectionError> {
-        let mut header = match self.read_exact_n_bytes(18).await {
-            Ok(header) => header,
-            Err(err) => return Err(err),
-        };
-        if header.len() != 18 {
-            return Err(NodeConnectionError::InvalidHeaderLength);
+    // reads exactly num_bytes into the front of read_buffer
+    async fn fill_read_buffer(&mut self, num_bytes: usize) -> Result<(), NodeConnectionError> {
+        if self.read_buffer.len() < num_bytes {
+            self.read_buffer.resize(num_bytes, 0);
         }
-        match self.peer_encryptor.decrypt_message(header.as_mut()) {
+        match self
+            .stream
+            .read_exact(&mut self.read_buffer[..num_bytes])
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(NodeConnectionError::IOError(err)),
+        }
+    }
+
+    async fn read_next_message_bytes(&mut self) -> Result<Vec<u8>, NodeConnectionError> {
+        self.fill_read_buffer(HEADER_LEN).await?;
+        let header = &mut self.read_buffer[..HEADER_LEN];
+        match self.peer_encryptor.decrypt_message(header) {
             Ok(_) => (),
-            Err(err) => return Err(NodeConnectionError::DecryptionError(err)),
+            Err(err) => return Err(self.decryption_error(err)),
         }
-        let length = u16::from_be_bytes([header[0], header[1]]);
-        let mut message = self.read_exact_n_bytes(length as usize + 16).await?;
-        match self.peer_encryptor.decrypt_message(message.as_mut()) {
+        let length = u16::from_be_bytes([header[0], header[1]]) as usize;
+        self.fill_read_buffer(length + MAC_LEN).await?;
+        match self
+            .peer_encryptor
+            .decrypt_message(&mut self.read_buffer[..length + MAC_LEN])
+        {
             Ok(_) => (),
-            Err(err) => return Err(NodeConnectionError::DecryptionError(err)),
+            Err(err) => return Err(self.decryption_error(err)),
         }
-        Ok(message)
+        self.messages_decrypted += 1;
+        // the trailing mac is not part of the plaintext
+        Ok(self.read_buffer[..length].to_vec())
+    }
+
+    fn decryption_error(&self, err: LightningError) -> NodeConnectionError {
+        println!(
+            "Decryption failed for {} after {} messages decrypted and {} encrypted",
+            hex::encode(self.public_key),
+            self.messages_decrypted,
+            self.messages_encrypted
+        );
+        NodeConnectionError::DecryptionError {
+            err,
+            encrypted: self.messages_encrypted,
+            decrypted: self.messages_decrypted,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn messages_encrypted(&self) -> u64 {
+        self.messages_encrypted
+    }
+
+    #[allow(dead_code)]
+    pub fn messages_decrypted(&self) -> u64 {
+        self.messages_decrypted
     }
 
     pub async fn read_next_message(&mut self) -> Result<MessageContainer, NodeConnectionError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
         self.wait_for_message().await?;
         let bytes = self.read_next_message_bytes().await?;
-        if bytes.is_empty() {
-            return Err(NodeConnectionError::NoMessageFound);
-        }
-        let (message, _bytes) = match MessageDecoder::from_bytes(bytes.as_slice()) {
-            Ok(msg) => msg,
-            Err(_) => return Err(NodeConnectionError::MessageDecodeError),
-        };
-        self.update_last_contacted();
+        let message = self.decode_received(&bytes)?;
+        self.message_received(&message);
         Ok(message)
     }
 
+    // counted before decoding, a message that then fails to decode still shows up
+    fn decode_received(&mut self, bytes: &[u8]) -> Result<MessageContainer, NodeConnectionError> {
+        if let Some(message_type) = peek_message_type(bytes) {
+            *self.received_types.entry(message_type).or_insert(0) += 1;
+        }
+        decode_message_bytes(bytes, self.strict_points)
+    }
+
+    pub fn received_types(&self) -> &HashMap<MessageType, u64> {
+        &self.received_types
+    }
+
     pub async fn encrypt_and_send_bytes(
         &mut self,
         bytes: &[u8],
     ) -> Result<(), NodeConnectionError> {
+        // the encryptor would panic without transport keys
+        if !self.is_transport_ready() {
+            return Err(NodeConnectionError::NotReady);
+        }
+        // the encryptor writes one frame per message and doesn't split
+        if bytes.len() > MAX_MESSAGE_LEN {
+            return Err(NodeConnectionError::MessageTooLarge(bytes.len()));
+        }
         let buf = MessageBuf::from_encoded(bytes);
         let encrypted = self.peer_encryptor.encrypt_buffer(buf);
+        self.messages_encrypted += 1;
         self.write_raw_data(encrypted.as_slice()).await?;
         Ok(())
     }
 
+    // Tears the connection down with a TCP shutdown instead of just dropping the stream,
+    // which peers see as a reset. With a reason the peer gets an error message first.
+    pub async fn close(&mut self, reason: Option<&str>) -> Result<(), NodeConnectionError> {
+        if let Some(reason) = reason {
+            let error = MessageContainer::Error(ErrorMessage::for_connection(reason));
+            if let Err(err) = self.encrypt_and_send_message(&error).await {
+                println!("Failed to send error before closing: {:?}", err);
+            }
+        }
+        self.state = ConnState::Closing;
+        let result = self.stream.shutdown().await;
+        println!("Closed connection to {}", hex::encode(self.public_key));
+        result.map_err(NodeConnectionError::IOError)
+    }
+
     pub async fn encrypt_and_send_message(
         &mut self,
         message: &MessageContainer,
     ) -> Result<(), NodeConnectionError> {
+        self.check_can_send(message)?;
         let bytes = message.to_bytes();
         self.encrypt_and_send_bytes(bytes.as_slice()).await?;
         println!("Sent message {:?}", message);
         self.update_last_contacted();
+        if let MessageContainer::Init(_) = message {
+            self.init_sent = true;
+            self.init_exchanged();
+        }
         Ok(())
     }
 }
+
+// Resolves the address ourselves and tries each result in turn, so one unreachable
+// address of a multi-homed host can't hold up the others for the OS connect timeout.
+async fn connect_any(address: &str, per_address: Duration) -> std::io::Result<TcpStream> {
+    let resolved: Vec<SocketAddr> = lookup_host(address).await?.collect();
+    connect_first(&resolved, per_address).await
+}
+
+// the first address that accepts within per_address, or the last failure
+async fn connect_first(
+    addresses: &[SocketAddr],
+    per_address: Duration,
+) -> std::io::Result<TcpStream> {
+    let mut last_err =
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address resolved to nothing");
+    for address in addresses {
+        match tokio::time::timeout(per_address, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => {
+                println!("Failed to connect to {}: {}", address, err);
+                last_err = err;
+            }
+            Err(_) => {
+                println!(
+                    "Timed out connecting to {} after {:?}",
+                    address, per_address
+                );
+                last_err = std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out");
+            }
+        }
+    }
+    Err(last_err)
+}
+
+fn decode_message_bytes(
+    bytes: &[u8],
+    strict_points: bool,
+) -> Result<MessageContainer, NodeConnectionError> {
+    // every message carries at least a 2-byte type, anything shorter means we are out of sync
+    if bytes.len() < 2 {
+        return Err(NodeConnectionError::MalformedMessage);
+    }
+    let (message, _bytes) = if strict_points {
+        with_strict_point_decoding(|| MessageDecoder::from_bytes(bytes))?
+    } else {
+        MessageDecoder::from_bytes(bytes)?
+    };
+    Ok(message)
+}
+
+// bytes of a rejected act we log, enough to recognise e.g. a bitcoin p2p version message
+const ACT_PREVIEW_LEN: usize = 16;
+
+// An act that fails to parse usually means we reached something that isn't a Lightning
+// node at all, say a bitcoind p2p port, so say that instead of the encryptor's error.
+fn not_lightning_noise(act: &[u8], err: LightningError) -> NodeConnectionError {
+    println!(
+        "Peer does not appear to speak Lightning Noise ({}), first bytes: {}",
+        err.err,
+        hex::encode(&act[..act.len().min(ACT_PREVIEW_LEN)])
+    );
+    NodeConnectionError::HandshakeFailed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::InitMessage;
+    use crate::test_util::example_message;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_truncated_act_one_fails_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(&[0u8; 20]).await.unwrap();
+            // dropping the stream closes it before act one is complete
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        client.await.unwrap();
+        let result = NodeConnection::accept(stream, new_random_secret_key()).await;
+        assert!(matches!(result, Err(NodeConnectionError::HandshakeFailed)));
+    }
+
+    // (client, server) with the handshake done and nothing else sent
+    pub(super) async fn connected_pair() -> (NodeConnection, NodeConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_key = new_random_secret_key();
+        let node = Node {
+            public_key: BitcoinPublicKey::from_secret_key(&*SECP, &server_key).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let client = tokio::spawn(async move {
+            let mut conn = NodeConnection::new(&node, new_random_secret_key())
+                .await
+                .unwrap();
+            conn.handshake().await.unwrap();
+            conn
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = NodeConnection::accept(stream, server_key).await.unwrap();
+        let client = client.await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_in_memory_stream() {
+        let (client_stream, server_stream) = tokio::io::duplex(MAX_MESSAGE_LEN);
+        let server_key = new_random_secret_key();
+        let client_key = new_random_secret_key();
+        let mut client = NodeConnection::outbound(
+            client_stream,
+            BitcoinPublicKey::from_secret_key(&*SECP, &server_key),
+            client_key,
+            new_random_secret_key(),
+        );
+        let server = tokio::spawn(NodeConnection::accept(server_stream, server_key));
+        client.handshake().await.unwrap();
+        let mut server = server.await.unwrap().unwrap();
+        assert_eq!(
+            server.public_key,
+            BitcoinPublicKey::from_secret_key(&*SECP, &client_key).serialize()
+        );
+        assert_eq!(server.direction, ConnectionDirection::Inbound);
+
+        let init = MessageContainer::Init(InitMessage::new(vec![], vec![0xaa], &[]));
+        client.encrypt_and_send_message(&init).await.unwrap();
+        server.encrypt_and_send_message(&init).await.unwrap();
+        assert!(client.has_message_within(Duration::from_secs(1)).await);
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        assert!(matches!(
+            server.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        assert_eq!(client.state(), ConnState::Ready);
+        assert_eq!(server.state(), ConnState::Ready);
+        assert!(!server.has_message_within(Duration::from_millis(50)).await);
+
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![0; 16]),
+        });
+        client.encrypt_and_send_message(&ping).await.unwrap();
+        assert_eq!(
+            server.read_next_message().await.unwrap().to_bytes(),
+            ping.to_bytes()
+        );
+    }
+
+    // a connected pair that also exchanged init, each side has sent and read one message
+    pub(super) async fn ready_pair() -> (NodeConnection, NodeConnection) {
+        let (mut client, mut server) = connected_pair().await;
+        let init = MessageContainer::Init(InitMessage::new(vec![], vec![0xaa], &[]));
+        client.encrypt_and_send_message(&init).await.unwrap();
+        server.encrypt_and_send_message(&init).await.unwrap();
+        client.read_next_message().await.unwrap();
+        server.read_next_message().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_transitions() {
+        let (client, _remote) = unstarted_connection().await;
+        assert_eq!(client.state(), ConnState::Handshaking);
+
+        let (mut client, mut server) = connected_pair().await;
+        assert_eq!(client.state(), ConnState::AwaitingInit);
+        assert_eq!(server.state(), ConnState::AwaitingInit);
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        assert!(matches!(
+            client.encrypt_and_send_message(&ping).await,
+            Err(NodeConnectionError::NotAllowed(ConnState::AwaitingInit))
+        ));
+        assert!(!client.ready_for_ping(Duration::ZERO));
+
+        // sending ours is not enough, theirs has to arrive too
+        let init = MessageContainer::Init(InitMessage::new(vec![], vec![0xaa], &[]));
+        client.encrypt_and_send_message(&init).await.unwrap();
+        assert_eq!(client.state(), ConnState::AwaitingInit);
+        server.read_next_message().await.unwrap();
+        assert_eq!(server.state(), ConnState::AwaitingInit);
+        server.encrypt_and_send_message(&init).await.unwrap();
+        assert_eq!(server.state(), ConnState::Ready);
+        client.read_next_message().await.unwrap();
+        assert_eq!(client.state(), ConnState::Ready);
+        client.encrypt_and_send_message(&ping).await.unwrap();
+
+        client.close(None).await.unwrap();
+        assert_eq!(client.state(), ConnState::Closing);
+        assert!(matches!(
+            client.encrypt_and_send_message(&ping).await,
+            Err(NodeConnectionError::NotAllowed(ConnState::Closing))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mutual_ping_rtt_does_not_deadlock() {
+        let (mut client, mut server) = ready_pair().await;
+
+        // both sides ping at once and each has to answer the other's ping to get its pong
+        let (server_rtt, client_rtt) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(server.ping_rtt(), client.ping_rtt())
+        })
+        .await
+        .expect("mutual ping deadlocked");
+        assert!(server_rtt.is_ok());
+        assert!(client_rtt.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_nodelay_and_read_buffer_reuse() {
+        let (mut client, mut server) = ready_pair().await;
+        server.set_nodelay(true).unwrap();
+        assert!(server.stream.get_ref().nodelay().unwrap());
+        server.set_nodelay(false).unwrap();
+        assert!(!server.stream.get_ref().nodelay().unwrap());
+
+        // a large message, then a small one read into the same allocation
+        for ignored in [1000, 0] {
+            let ping = MessageContainer::Ping(PingMessage {
+                num_pong_bytes: 0,
+                ignored: IgnoredBytesElement::new(vec![0; ignored]),
+            });
+            client.encrypt_and_send_message(&ping).await.unwrap();
+        }
+        server.read_next_message().await.unwrap();
+        let buffer = server.read_buffer.as_ptr();
+        let len = server.read_buffer.len();
+        assert!(len >= 1000 + MAC_LEN);
+        server.read_next_message().await.unwrap();
+        assert_eq!(server.read_buffer.as_ptr(), buffer);
+        assert_eq!(server.read_buffer.len(), len);
+    }
+
+    // an outbound connection that has not started its handshake, and the raw far end
+    async fn unstarted_connection() -> (NodeConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Node {
+            public_key: BitcoinPublicKey::from_secret_key(&*SECP, &new_random_secret_key())
+                .serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let client = tokio::spawn(async move {
+            NodeConnection::new(&node, new_random_secret_key())
+                .await
+                .unwrap()
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        (client.await.unwrap(), stream)
+    }
+
+    #[tokio::test]
+    async fn test_non_lightning_act_two_fails_handshake() {
+        let (mut client, mut remote) = unstarted_connection().await;
+        let server = tokio::spawn(async move {
+            let mut act_one = [0; ACT_ONE_LEN];
+            remote.read_exact(&mut act_one).await.unwrap();
+            // a bitcoin p2p message header (mainnet magic, "version") padded out to act two
+            let mut reply = hex::decode("f9beb4d976657273696f6e0000000000").unwrap();
+            reply.resize(ACT_TWO_LEN, 0);
+            remote.write_all(&reply).await.unwrap();
+            remote
+        });
+        assert!(matches!(
+            client.handshake().await,
+            Err(NodeConnectionError::HandshakeFailed)
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transport_message_counters() {
+        let (mut client, mut server) = ready_pair().await;
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        for _ in 0..3 {
+            client.encrypt_and_send_message(&ping).await.unwrap();
+        }
+        // on top of the init each side sent and read
+        for expected in 2..=4 {
+            server.read_next_message().await.unwrap();
+            assert_eq!(server.messages_decrypted(), expected);
+        }
+        assert_eq!(client.messages_encrypted(), 4);
+        assert_eq!(client.messages_decrypted(), 1);
+        assert_eq!(server.messages_encrypted(), 1);
+
+        // garbage where the next header should be fails with the counts attached
+        client.write_raw_data(&[0; HEADER_LEN]).await.unwrap();
+        match server.read_next_message().await {
+            Err(NodeConnectionError::DecryptionError {
+                encrypted: 1,
+                decrypted: 4,
+                ..
+            }) => (),
+            other => panic!("expected a decryption error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_types_count_undecodable_messages() {
+        let (mut client, mut server) = ready_pair().await;
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        client.encrypt_and_send_message(&ping).await.unwrap();
+        // a channel_update cut off after its type, and a type the spec doesn't assign
+        client
+            .encrypt_and_send_bytes(&[0x01, 0x02, 0x00])
+            .await
+            .unwrap();
+        client.encrypt_and_send_bytes(&[0x80, 0x01]).await.unwrap();
+        assert!(server.read_next_message().await.is_ok());
+        assert!(matches!(
+            server.read_next_message().await,
+            Err(NodeConnectionError::MessageDecodeError(_))
+        ));
+        assert!(server.read_next_message().await.is_ok());
+        assert_eq!(
+            server.received_types(),
+            &HashMap::from([
+                (MessageType::Init, 1),
+                (MessageType::Ping, 1),
+                (MessageType::ChannelUpdate, 1),
+            ])
+        );
+    }
+
+    // BOLT 8 appendix A, initiator and responder keys and their expected outputs
+    const BOLT8_INITIATOR_KEY: [u8; 32] = [0x11; 32];
+    const BOLT8_INITIATOR_EPHEMERAL: [u8; 32] = [0x12; 32];
+    const BOLT8_RESPONDER_KEY: [u8; 32] = [0x21; 32];
+    const BOLT8_RESPONDER_EPHEMERAL: [u8; 32] = [0x22; 32];
+    const BOLT8_INITIATOR_PUBKEY: &str =
+        "034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa";
+    const BOLT8_RESPONDER_PUBKEY: &str =
+        "028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7";
+    const BOLT8_ACT_ONE: &str = "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a";
+    const BOLT8_ACT_TWO: &str = "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae";
+    const BOLT8_ACT_THREE: &str = "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba";
+    // "hello" encrypted as transport messages 0 and 1
+    const BOLT8_MESSAGES: [&str; 2] = [
+        "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95",
+        "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1",
+    ];
+
+    #[test]
+    fn test_handshake_matches_bolt8_vectors() {
+        let initiator_km = Arc::new(KeysManager::new(&BOLT8_INITIATOR_KEY, 0, 0));
+        let responder_km = Arc::new(KeysManager::new(&BOLT8_RESPONDER_KEY, 0, 0));
+        let responder_pubkey =
+            BitcoinPublicKey::from_slice(&hex::decode(BOLT8_RESPONDER_PUBKEY).unwrap()).unwrap();
+        let mut initiator = PeerChannelEncryptor::new_outbound(
+            responder_pubkey,
+            SecretKey::from_slice(&BOLT8_INITIATOR_EPHEMERAL).unwrap(),
+        );
+        let mut responder = PeerChannelEncryptor::new_inbound(&responder_km);
+
+        let act_one = initiator.get_act_one(&*SECP);
+        assert_eq!(hex::encode(act_one), BOLT8_ACT_ONE);
+        let act_two = responder
+            .process_act_one_with_keys(
+                &act_one,
+                &responder_km,
+                SecretKey::from_slice(&BOLT8_RESPONDER_EPHEMERAL).unwrap(),
+                &*SECP,
+            )
+            .unwrap();
+        assert_eq!(hex::encode(act_two), BOLT8_ACT_TWO);
+        let (act_three, public_key) = initiator.process_act_two(&act_two, &initiator_km).unwrap();
+        assert_eq!(public_key, responder_pubkey);
+        assert_eq!(hex::encode(act_three), BOLT8_ACT_THREE);
+        let public_key = responder.process_act_three(&act_three).unwrap();
+        assert_eq!(hex::encode(public_key.serialize()), BOLT8_INITIATOR_PUBKEY);
+
+        // the keys themselves are private to the encryptor, the spec's ciphertexts
+        // are only reproduced if sk and rk are the expected ones
+        for expected in BOLT8_MESSAGES {
+            let mut encrypted = initiator.encrypt_buffer(MessageBuf::from_encoded(b"hello"));
+            assert_eq!(hex::encode(&encrypted), expected);
+            let (header, body) = encrypted.split_at_mut(HEADER_LEN);
+            responder.decrypt_message(header).unwrap();
+            assert_eq!(u16::from_be_bytes([header[0], header[1]]), 5);
+            responder.decrypt_message(body).unwrap();
+            assert_eq!(&body[..5], b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_act_one_matches_bolt8_vector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Node {
+            public_key: hex::decode(BOLT8_RESPONDER_PUBKEY)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let client = tokio::spawn(async move {
+            let mut conn = NodeConnection::new_with_ephemeral(
+                &node,
+                SecretKey::from_slice(&BOLT8_INITIATOR_KEY).unwrap(),
+                SecretKey::from_slice(&BOLT8_INITIATOR_EPHEMERAL).unwrap(),
+            )
+            .await
+            .unwrap();
+            // never completes, nobody answers act one
+            let _ = conn.handshake().await;
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut act_one = [0; ACT_ONE_LEN];
+        stream.read_exact(&mut act_one).await.unwrap();
+        assert_eq!(hex::encode(act_one), BOLT8_ACT_ONE);
+        client.abort();
+    }
+
+    #[tokio::test]
+    async fn test_send_before_handshake_is_not_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Node {
+            public_key: BitcoinPublicKey::from_secret_key(&*SECP, &new_random_secret_key())
+                .serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let mut conn = NodeConnection::new(&node, new_random_secret_key())
+            .await
+            .unwrap();
+        assert!(!conn.is_transport_ready());
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        assert!(matches!(
+            conn.encrypt_and_send_message(&ping).await,
+            Err(NodeConnectionError::NotReady)
+        ));
+        assert_eq!(conn.messages_encrypted(), 0);
+
+        let (client, server) = connected_pair().await;
+        assert!(client.is_transport_ready());
+        assert!(server.is_transport_ready());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_interval_and_timeout() {
+        let (mut client, mut server) = ready_pair().await;
+        let interval = Duration::from_secs(5);
+        let timeout = Duration::from_secs(10);
+        assert!(!client.ready_for_ping(interval));
+        tokio::time::advance(interval).await;
+        assert!(client.ready_for_ping(interval));
+
+        client.send_ping().await.unwrap();
+        // one outstanding ping at a time
+        tokio::time::advance(interval).await;
+        assert!(!client.ready_for_ping(interval));
+        assert!(!client.is_unresponsive(timeout));
+        tokio::time::advance(timeout - interval).await;
+        assert!(client.is_unresponsive(timeout));
+
+        let ping = match server.read_next_message().await.unwrap() {
+            MessageContainer::Ping(ping) => ping,
+            other => panic!("expected ping, got {:?}", other),
+        };
+        let pong = MessageContainer::Pong(PongMessage::from_ping(ping));
+        server.encrypt_and_send_message(&pong).await.unwrap();
+        let pong = match client.read_next_message().await.unwrap() {
+            MessageContainer::Pong(pong) => pong,
+            other => panic!("expected pong, got {:?}", other),
+        };
+        assert!(!client.is_unresponsive(timeout));
+        assert!(!client.ready_for_ping(interval));
+        // the pong timed the ping
+        assert!(client.answers_ping(&pong));
+        assert_eq!(client.rtt_stats(), Some((timeout, timeout, timeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rtt_survives_messages_before_the_pong() {
+        let (mut client, mut server) = ready_pair().await;
+        client.send_ping().await.unwrap();
+        let ping = match server.read_next_message().await.unwrap() {
+            MessageContainer::Ping(ping) => ping,
+            other => panic!("expected ping, got {:?}", other),
+        };
+        let rtt = Duration::from_millis(300);
+        tokio::time::advance(rtt).await;
+        // something else arrives first, as gossip would
+        let other = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        server.encrypt_and_send_message(&other).await.unwrap();
+        server
+            .encrypt_and_send_message(&MessageContainer::Pong(PongMessage::from_ping(ping)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Ping(_)
+        ));
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::Pong(pong) => assert!(client.answers_ping(&pong)),
+            other => panic!("expected pong, got {:?}", other),
+        }
+        assert_eq!(client.rtt_stats(), Some((rtt, rtt, rtt)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_moves_past_dead_addresses() {
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // bound then dropped, so nothing is listening there
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // non-routable, the connect hangs until the timeout (or fails fast without a route)
+        let blackhole: SocketAddr = "10.255.255.1:9735".parse().unwrap();
+        let addresses = [blackhole, refused, live.local_addr().unwrap()];
+        let stream = connect_first(&addresses, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live.local_addr().unwrap());
+
+        assert!(connect_first(&[refused], Duration::from_millis(200))
+            .await
+            .is_err());
+        assert!(connect_first(&[], Duration::from_millis(200))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rtt_stats_keep_recent_samples() {
+        let (mut client, _server) = connected_pair().await;
+        assert_eq!(client.rtt_stats(), None);
+        for ms in [30, 10, 20] {
+            client.record_rtt(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            client.rtt_stats(),
+            Some((
+                Duration::from_millis(10),
+                Duration::from_millis(20),
+                Duration::from_millis(30)
+            ))
+        );
+        // push the first three out of the window
+        for _ in 0..RTT_SAMPLES {
+            client.record_rtt(Duration::from_millis(50));
+        }
+        assert_eq!(client.rtt_samples.len(), RTT_SAMPLES);
+        let fifty = Duration::from_millis(50);
+        assert_eq!(client.rtt_stats(), Some((fifty, fifty, fifty)));
+    }
+
+    #[tokio::test]
+    async fn test_largest_message_fits_one_frame() {
+        let (mut client, mut server) = ready_pair().await;
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 0,
+            ignored: IgnoredBytesElement::new(vec![0; MAX_MESSAGE_LEN - 6]),
+        });
+        let bytes = ping.to_bytes();
+        assert_eq!(bytes.len(), MAX_MESSAGE_LEN);
+        // read concurrently, the frame is larger than a socket buffer may hold
+        let (sent, received) = tokio::join!(
+            client.encrypt_and_send_bytes(&bytes),
+            server.read_next_message()
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap().to_bytes(), bytes);
+
+        let mut too_large = bytes.clone();
+        too_large.push(0);
+        assert!(matches!(
+            client.encrypt_and_send_bytes(&too_large).await,
+            Err(NodeConnectionError::MessageTooLarge(65536))
+        ));
+        // nothing was encrypted, so the nonces still line up
+        assert_eq!(client.messages_encrypted(), 2);
+        let (sent, received) = tokio::join!(
+            client.encrypt_and_send_message(&ping),
+            server.read_next_message()
+        );
+        sent.unwrap();
+        assert!(received.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close_shuts_down_write_half() {
+        let (mut client, mut server) = connected_pair().await;
+
+        server.close(Some("shutting down")).await.unwrap();
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::Error(error) => assert_eq!(error.data, b"shutting down"),
+            other => panic!("expected error, got {:?}", other),
+        }
+        // then a clean end of stream rather than a reset
+        let mut buf = [0; 1];
+        assert_eq!(client.stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_act_three_write_fails_handshake() {
+        let (mut client, _remote) = unstarted_connection().await;
+        client.stream.shutdown().await.unwrap();
+        assert!(matches!(
+            client.send_act_three(&[0; ACT_THREE_LEN]).await,
+            Err(NodeConnectionError::HandshakeFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_act_three_before_act_two_fails_handshake() {
+        // the write goes through, but the noise state never got past act one
+        let (mut client, _remote) = unstarted_connection().await;
+        assert!(matches!(
+            client.send_act_three(&[0; ACT_THREE_LEN]).await,
+            Err(NodeConnectionError::HandshakeFailed)
+        ));
+    }
+
+    #[test]
+    fn test_decode_error_keeps_source_chain() {
+        use crate::serialization::SerializationError;
+        use std::error::Error;
+
+        // a node_announcement whose address list claims 7 bytes but carries only 3
+        let mut bytes = example_message(MessageType::NodeAnnouncement);
+        bytes.truncate(bytes.len() - 2);
+        bytes.extend([0, 7, 1, 127, 0]);
+        let err = decode_message_bytes(&bytes, false).unwrap_err();
+        assert!(matches!(
+            err,
+            NodeConnectionError::MessageDecodeError(MessageDecoderError::SerializationError(
+                SerializationError::TooFewBytes
+            ))
+        ));
+        let decoder_err = err.source().unwrap();
+        assert_eq!(decoder_err.to_string(), "failed to decode message");
+        let serialization_err = decoder_err.source().unwrap();
+        assert_eq!(serialization_err.to_string(), "too few bytes");
+    }
+
+    #[test]
+    fn test_zero_length_message_is_malformed() {
+        assert!(matches!(
+            decode_message_bytes(&[], false),
+            Err(NodeConnectionError::MalformedMessage)
+        ));
+        assert!(matches!(
+            decode_message_bytes(&hex::decode("001200010000").unwrap(), false),
+            Ok(MessageContainer::Ping(_))
+        ));
+    }
+
+    #[test]
+    fn test_strict_points_reject_invalid_node_id() {
+        use crate::serialization::SerializationError;
+
+        let mut bytes = example_message(MessageType::NodeAnnouncement);
+        assert!(decode_message_bytes(&bytes, true).is_ok());
+        // the node_id prefix, neither 0x02 nor 0x03
+        bytes[79] = 0x05;
+        assert!(decode_message_bytes(&bytes, false).is_ok());
+        assert!(matches!(
+            decode_message_bytes(&bytes, true),
+            Err(NodeConnectionError::MessageDecodeError(
+                MessageDecoderError::SerializationError(SerializationError::InvalidValue)
+            ))
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use super::tests::{connected_pair, ready_pair};
+    use super::*;
+    use crate::test_util::example_message;
+    use bitcoin::secp256k1::Secp256k1;
+    use test::Bencher;
+
+    const UPDATES_PER_ITER: usize = 100;
+
+    // Reads UPDATES_PER_ITER channel_updates over loopback. Without reuse the read
+    // buffer is dropped before every read, so each one allocates as reads used to.
+    fn read_gossip(b: &mut Bencher, reuse_buffer: bool) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (mut client, mut server) = runtime.block_on(ready_pair());
+        let update = example_message(MessageType::ChannelUpdate);
+        b.bytes = (update.len() * UPDATES_PER_ITER) as u64;
+        b.iter(|| {
+            runtime.block_on(async {
+                for _ in 0..UPDATES_PER_ITER {
+                    client.encrypt_and_send_bytes(&update).await.unwrap();
+                }
+                for _ in 0..UPDATES_PER_ITER {
+                    if !reuse_buffer {
+                        server.read_buffer = Vec::new();
+                    }
+                    server.read_next_message().await.unwrap();
+                }
+            })
+        });
+    }
+
+    #[bench]
+    fn bench_gossip_throughput_reused_buffer(b: &mut Bencher) {
+        read_gossip(b, true);
+    }
+
+    #[bench]
+    fn bench_gossip_throughput_fresh_buffer(b: &mut Bencher) {
+        read_gossip(b, false);
+    }
+
+    // dial and handshake over loopback, every key operation on the shared SECP
+    #[bench]
+    fn bench_connection_setup(b: &mut Bencher) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        b.iter(|| runtime.block_on(connected_pair()));
+    }
+
+    // the same, plus the signing context each side used to build per connection
+    #[bench]
+    fn bench_connection_setup_per_connection_context(b: &mut Bencher) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        b.iter(|| {
+            let contexts = [Secp256k1::signing_only(), Secp256k1::signing_only()];
+            test::black_box(contexts);
+            runtime.block_on(connected_pair())
+        });
+    }
+}
@@ -0,0 +1,67 @@
+use serde::Serialize;
+
+use crate::{messages::NodeAnnouncementMessage, serialization::FeatureFlag};
+
+// flattened, display-friendly view of a node_announcement
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeProfile {
+    pub node_id: String,
+    pub alias: String,
+    pub rgb: String,
+    pub addresses: Vec<String>,
+    pub features: Vec<String>,
+    // timestamp of the announcement the profile was built from
+    pub last_seen: u32,
+}
+
+impl NodeProfile {
+    pub fn from_announcement(announcement: &NodeAnnouncementMessage) -> Self {
+        NodeProfile {
+            node_id: hex::encode(announcement.node_id.value),
+            alias: announcement.alias.as_string(),
+            rgb: format!("#{}", hex::encode(announcement.rgb_color)),
+            addresses: announcement
+                .addresses
+                .connectable_addresses()
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            features: announcement
+                .features
+                .set_features()
+                .iter()
+                .map(|(feature, status)| match status {
+                    FeatureFlag::Compulsory => format!("{:?}:compulsory", feature),
+                    _ => format!("{:?}:optional", feature),
+                })
+                .collect(),
+            last_seen: announcement.timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::MessageType;
+    use crate::serialization::SerializableToBytes;
+    use crate::test_util::example_message;
+
+    #[test]
+    fn test_profile_from_announcement() {
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        let (announcement, _) = NodeAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let profile = NodeProfile::from_announcement(&announcement);
+        assert_eq!(
+            profile.node_id,
+            "02c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14"
+        );
+        assert_eq!(profile.alias, "dave");
+        assert_eq!(profile.rgb, "#02c812");
+        assert!(profile.addresses.is_empty());
+        assert!(profile
+            .features
+            .contains(&"GossipQueries:optional".to_string()));
+        assert_eq!(profile.last_seen, 0x67beb137);
+    }
+}
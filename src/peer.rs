@@ -1,14 +1,36 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 
-use bitcoin::secp256k1::SecretKey;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use serde::Serialize;
+use strum::IntoEnumIterator;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::DO_CONNECT_TO_NEW_NODES,
-    message_decoder::MessageContainer,
-    messages::{ChannelAnnouncementMessage, InitMessage, NodeAnnouncementMessage, PongMessage},
+    channel_graph::{ChannelGraph, NetworkSummary},
+    channel_range_sync::{ChannelRangeSync, ChannelRangeSyncError},
+    config::{JsonStream, PeerConfig, DO_CONNECT_TO_NEW_NODES},
+    gossip_store::{GossipStore, MemoryGossipStore},
+    message_decoder::{MessageContainer, MessageDecoder},
+    messages::{
+        ChannelAnnouncementMessage, ChannelReadyMessage, ChannelUpdateMessage,
+        GossipTimestampFilterMessage, InitMessage, MessageType, PongMessage,
+        QueryChannelRangeMessage, QueryShortChannelIdsMessage, ReplyChannelRangeMessage,
+        StfuMessage, TxAckRbfMessage, TxInitRbfMessage,
+    },
     node::Node,
-    node_connection::{NodeConnection, NodeConnectionError},
-    serialization::{PointElement, SerializableToBytes, ShortChannelIDElement},
+    node_connection::{ConnectionDirection, NodeConnection, NodeConnectionError},
+    node_profile::NodeProfile,
+    serialization::SerializableToBytes,
+    serialization::{Address, ChannelIDElement, Features, PointElement, ShortChannelIDElement},
+    util::{get_current_timestamp, SECP},
 };
 
 #[allow(dead_code)]
@@ -16,23 +38,176 @@ use crate::{
 pub enum MessageHandlerError {
     NodeConnectionError(NodeConnectionError),
     NodeHandshakeError(NodeConnectionError),
+    NotConnected,
+    PeerNotAllowed,
+    UnsupportedChain,
+    SelfConnection,
+    // connect_budget ran out before the init exchange completed, or the peer stopped
+    // answering a channel range sync
+    Timeout,
+    // the caller cancelled the connection attempt
+    Cancelled,
+    // BOLT 1: the peer's first message has to be init, it sent this instead
+    InitExpected(MessageType),
+    ChannelRangeSyncError(ChannelRangeSyncError),
+}
+
+// The "connection established" event, logged once init has been exchanged
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionEstablished {
+    pub node_id: [u8; 33],
+    pub address: Option<SocketAddr>,
+    pub direction: ConnectionDirection,
+    pub handshake_duration: Option<Duration>,
+    // what both sides support, not just what they advertised
+    pub negotiated_features: HashSet<Features>,
+}
+
+impl fmt::Display for ConnectionEstablished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut features: Vec<String> = self
+            .negotiated_features
+            .iter()
+            .map(|feature| format!("{:?}", feature))
+            .collect();
+        features.sort();
+        write!(
+            f,
+            "event=connection_established node_id={} address={} direction={} handshake_ms={} features={}",
+            hex::encode(self.node_id),
+            self.address.map_or("unknown".to_string(), |a| a.to_string()),
+            self.direction,
+            self.handshake_duration
+                .map_or("unknown".to_string(), |d| d.as_millis().to_string()),
+            features.join(",")
+        )
+    }
+}
+
+// how long the event loop waits on each connection before moving on, short so that one
+// quiet peer can't hold up the others
+pub const READ_WAIT: Duration = Duration::from_millis(10);
+// enable/disable transitions kept per channel direction
+const CHANNEL_HISTORY_LEN: usize = 16;
+// query_short_channel_ids is 37 bytes plus 8 per scid, this many fit in one message
+const MAX_SHORT_CHANNEL_IDS_PER_QUERY: usize = (65535 - 37) / 8;
+// channel_updates held for unannounced channels, the oldest is dropped beyond this
+const MAX_ORPHAN_UPDATES: usize = 10_000;
+
+// what we have seen of a splice on a channel we observe
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpliceState {
+    // stfu was exchanged, the channel is quiescent
+    pub quiescent: bool,
+    // feerate of every tx_init_rbf, oldest first
+    pub rbf_feerates: Vec<u32>,
+    // the latest tx_init_rbf was answered with tx_ack_rbf
+    pub rbf_acked: bool,
+}
+
+// extra logic for one message type, run after the built-in handling with a copy of the
+// message and the id of the peer that sent it
+pub type MessageHandler = Box<
+    dyn Fn(MessageContainer, [u8; 33]) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync,
+>;
+
+// one line of PeerConfig::json_stream. The message structs have no serde impls, so the
+// message is carried as its wire bytes, which decode_hex turns back into a message.
+#[derive(Debug, Serialize)]
+struct MessageRecord {
+    timestamp: u64,
+    node_id: String,
+    message_type: &'static str,
+    message: String,
 }
 
 pub struct MiniPeer {
     secret_key: SecretKey,
+    config: PeerConfig,
     node_connections: HashMap<[u8; 33], NodeConnection>,
-    // eventually make a channel type not just the announcement message
-    known_channels: HashMap<ShortChannelIDElement, ChannelAnnouncementMessage>,
-    known_nodes: HashMap<PointElement, NodeAnnouncementMessage>,
+    graph: ChannelGraph,
+    message_counts: HashMap<MessageType, u64>,
+    // (message type, tlv type) of records outside what the spec defines
+    unknown_tlv_counts: HashMap<(MessageType, u64), u64>,
+    // alias scid from channel_ready -> the peer that sent it
+    scid_aliases: HashMap<ShortChannelIDElement, [u8; 33]>,
+    last_new_gossip: Instant,
+    // peers whose init networks do not include our chain, we do not query them for gossip
+    other_chain_peers: HashSet<[u8; 33]>,
+    // peers that advertised gossip_queries -> when they last sent us gossip
+    last_gossip_from: HashMap<[u8; 33], Instant>,
+    // the full channel range query we sent each peer, until its replies are complete
+    range_syncs: HashMap<[u8; 33], ChannelRangeSync>,
+    // channels still to ask each peer for, and the peers with a query_short_channel_ids
+    // not yet answered by reply_short_channel_ids_end
+    queued_scids: HashMap<[u8; 33], Vec<ShortChannelIDElement>>,
+    scid_queries_in_flight: HashSet<[u8; 33]>,
+    // features each node advertised in its last init, to spot downgrades on reconnect
+    known_features: HashMap<[u8; 33], HashSet<Features>>,
+    feature_downgrades: u64,
+    // best block height we know of, if set announcements for later blocks are rejected
+    chain_tip: Option<u32>,
+    future_announcements: u64,
+    // node_announcements dropped because we already held one at least as recent
+    stale_node_announcements: u64,
+    connect_timeouts: u64,
+    // (timestamp, enabled) each time a channel direction flips, oldest first
+    enabled_history: HashMap<(ShortChannelIDElement, usize), Vec<(u32, bool)>>,
+    // channel_updates that arrived before their announcement, per channel direction.
+    // They are only stored once the announcement shows up.
+    orphan_updates: HashMap<(ShortChannelIDElement, usize), (Instant, ChannelUpdateMessage)>,
+    // every peer that sent us the announcement of a channel we hold, a channel only one
+    // peer knows about may be made up
+    channel_sources: HashMap<ShortChannelIDElement, HashSet<[u8; 33]>>,
+    // nodes auto-connect already dialed, announcements are rebroadcast and we only try once
+    dialed_nodes: HashSet<Node>,
+    splice_states: HashMap<ChannelIDElement, SpliceState>,
+    handlers: HashMap<MessageType, MessageHandler>,
+    json_writer: Option<Box<dyn Write + Send>>,
 }
 
 impl MiniPeer {
     pub fn new(secret_key: SecretKey) -> Self {
+        MiniPeer::with_config(secret_key, PeerConfig::default())
+    }
+
+    pub fn with_config(secret_key: SecretKey, config: PeerConfig) -> Self {
+        MiniPeer::with_store(secret_key, config, Box::new(MemoryGossipStore::new()))
+    }
+
+    pub fn with_store(
+        secret_key: SecretKey,
+        config: PeerConfig,
+        store: Box<dyn GossipStore>,
+    ) -> Self {
+        let json_writer = config.json_stream.as_ref().and_then(open_json_stream);
         MiniPeer {
             secret_key,
+            config,
             node_connections: HashMap::new(),
-            known_channels: HashMap::new(),
-            known_nodes: HashMap::new(),
+            graph: ChannelGraph::with_store(store),
+            message_counts: HashMap::new(),
+            unknown_tlv_counts: HashMap::new(),
+            scid_aliases: HashMap::new(),
+            last_new_gossip: Instant::now(),
+            other_chain_peers: HashSet::new(),
+            last_gossip_from: HashMap::new(),
+            range_syncs: HashMap::new(),
+            queued_scids: HashMap::new(),
+            scid_queries_in_flight: HashSet::new(),
+            known_features: HashMap::new(),
+            feature_downgrades: 0,
+            chain_tip: None,
+            future_announcements: 0,
+            stale_node_announcements: 0,
+            connect_timeouts: 0,
+            enabled_history: HashMap::new(),
+            orphan_updates: HashMap::new(),
+            channel_sources: HashMap::new(),
+            dialed_nodes: HashSet::new(),
+            splice_states: HashMap::new(),
+            handlers: HashMap::new(),
+            json_writer,
         }
     }
 
@@ -40,140 +215,2727 @@ impl MiniPeer {
         self.node_connections.len()
     }
 
-    pub async fn event_loop(&mut self) {
-        loop {
-            let mut inbounds = Vec::new();
-            let mut disconnects = Vec::new();
-            for node_conn in &mut self.node_connections.values_mut() {
-                match node_conn.read_next_message().await {
-                    Ok(wrapped_message) => {
-                        inbounds.push((wrapped_message, node_conn.public_key.clone()));
-                    }
-                    Err(err) => {
-                        match err {
-                            NodeConnectionError::IOError(_) => {
-                                disconnects.push(node_conn.public_key.clone());
-                            }
-                            _ => {
-                                println!("Failed to read: {:?}", err);
-                            }
-                        }
-                        continue;
-                    }
+    pub fn connected_peers(&self) -> Vec<[u8; 33]> {
+        self.node_connections.keys().copied().collect()
+    }
+
+    pub fn node_id(&self) -> [u8; 33] {
+        PublicKey::from_secret_key(&*SECP, &self.secret_key).serialize()
+    }
+
+    pub fn graph(&self) -> &ChannelGraph {
+        &self.graph
+    }
+
+    // where else a peer says it can be reached, empty until we have its node_announcement
+    #[allow(dead_code)]
+    pub fn peer_addresses(&self, node_public_key: [u8; 33]) -> Vec<Address> {
+        match self.graph.get_node(&PointElement {
+            value: node_public_key,
+        }) {
+            Some(node) => node.addresses.connectable_addresses(),
+            None => Vec::new(),
+        }
+    }
+
+    // returns false if we were not connected to the peer
+    pub async fn disconnect(&mut self, node_public_key: [u8; 33]) -> bool {
+        self.forget_gossip_state(node_public_key);
+        match self.node_connections.remove(&node_public_key) {
+            Some(mut node_conn) => {
+                if let Err(err) = node_conn.close(Some("disconnecting")).await {
+                    println!("Failed to close connection: {:?}", err);
                 }
-                if node_conn.ready_for_ping() {
-                    node_conn.send_ping().await.unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn ping(&mut self, node_public_key: [u8; 33]) -> Result<(), MessageHandlerError> {
+        let node_conn = match self.node_connections.get_mut(&node_public_key) {
+            Some(node_conn) => node_conn,
+            None => return Err(MessageHandlerError::NotConnected),
+        };
+        match node_conn.send_ping().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MessageHandlerError::NodeConnectionError(e)),
+        }
+    }
+
+    // wire bytes of every stored announcement and latest update. Each channel
+    // announcement comes before its updates so the output can be imported in order.
+    pub fn export_raw(&self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for edge in self.graph.channels() {
+            messages.push(edge.announcement.to_bytes());
+            for update in edge.updates.iter().flatten() {
+                messages.push(update.to_bytes());
+            }
+        }
+        for node in self.graph.nodes() {
+            messages.push(node.to_bytes());
+        }
+        messages
+    }
+
+    // decodes and stores gossip produced by export_raw, skipping anything that does not
+    // decode as a gossip message. Returns the number of messages stored.
+    #[allow(dead_code)]
+    pub fn import_raw(&mut self, messages: Vec<Vec<u8>>) -> usize {
+        let mut stored = 0;
+        for bytes in messages {
+            let added = match MessageDecoder::from_bytes(&bytes) {
+                Ok((MessageContainer::ChannelAnnouncement(msg), _)) => {
+                    self.graph.add_channel_announcement(msg)
+                }
+                Ok((MessageContainer::ChannelUpdate(msg), _)) => self.graph.add_channel_update(msg),
+                Ok((MessageContainer::NodeAnnouncement(msg), _)) => {
+                    self.graph.add_node_announcement(msg)
+                }
+                Ok((other, _)) => {
+                    println!(
+                        "Skipping non gossip message on import: {:?}",
+                        other.message_type()
+                    );
+                    false
+                }
+                Err(e) => {
+                    println!("Skipping invalid message on import: {:?}", e);
+                    false
                 }
+            };
+            if added {
+                stored += 1;
             }
-            for node_public_key in disconnects {
-                self.node_connections.remove(&node_public_key);
+        }
+        stored
+    }
+
+    // writes every stored gossip message hex encoded, one per line, in the same
+    // format as test/examples. Returns the number of messages written.
+    pub fn dump_gossip(&self, path: &str) -> std::io::Result<usize> {
+        let lines: Vec<String> = self.export_raw().iter().map(hex::encode).collect();
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        std::fs::write(path, contents)?;
+        Ok(lines.len())
+    }
+
+    // announced nodes we have an address for, best connected first, leaving out
+    // ourselves and peers we are already connected to
+    pub fn bootstrap_candidates(&self, limit: usize) -> Vec<Node> {
+        let channel_counts = self.graph.channel_counts();
+        let mut candidates: Vec<(usize, Node)> = self
+            .graph
+            .nodes()
+            .iter()
+            .filter_map(|announcement| announcement.as_node())
+            .filter(|node| {
+                node.public_key != self.node_id()
+                    && !self.node_connections.contains_key(&node.public_key)
+            })
+            .map(|node| {
+                (
+                    channel_counts.get(&node.public_key).copied().unwrap_or(0),
+                    node,
+                )
+            })
+            .collect();
+        candidates.sort_by(|(a_count, a), (b_count, b)| {
+            b_count.cmp(a_count).then(a.public_key.cmp(&b.public_key))
+        });
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    // Loads gossip saved by an earlier run and connects to up to max_connections of the
+    // best connected nodes in it, so a crawler can start without seed nodes. Returns
+    // how many connections were opened.
+    #[allow(dead_code)]
+    pub async fn bootstrap_from_store(
+        &mut self,
+        store: &dyn GossipStore,
+        max_connections: usize,
+    ) -> usize {
+        for edge in store.iter_channels() {
+            self.graph.add_channel_announcement(edge.announcement);
+            for update in edge.updates.into_iter().flatten() {
+                self.graph.add_channel_update(update);
             }
-            for (message, node_public_key) in inbounds {
-                self.handle_inbound_message(message, node_public_key)
-                    .await
-                    .unwrap();
+        }
+        for node in store.iter_nodes() {
+            self.graph.add_node_announcement(node);
+        }
+        let mut connected = 0;
+        for node in self.bootstrap_candidates(max_connections) {
+            if self.open_node_connection(&node).await.is_ok() {
+                connected += 1;
             }
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
         }
+        connected
     }
 
-    pub async fn open_node_connection(&mut self, node: &Node) -> Result<(), MessageHandlerError> {
-        let mut node_connection = match NodeConnection::new(node, self.secret_key).await {
-            Ok(conn) => conn,
-            Err(err) => {
-                println!("Failed to create node connection: {:?}", err);
-                return Err(MessageHandlerError::NodeConnectionError(err));
+    // None for types filtered out by log_message_types
+    fn message_log_line(&self, message: &MessageContainer) -> Option<String> {
+        if let Some(types) = &self.config.log_message_types {
+            if !types.contains(&message.message_type()) {
+                return None;
+            }
+        }
+        Some(format!("Received message: {:?}", message))
+    }
+
+    fn write_json_line(&mut self, message: &MessageContainer, node_public_key: [u8; 33]) {
+        let writer = match self.json_writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let record = MessageRecord {
+            timestamp: get_current_timestamp(),
+            node_id: hex::encode(node_public_key),
+            message_type: message.message_type().wire_name(),
+            message: hex::encode(message.to_bytes()),
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(writer, "{}", line));
+        if let Err(e) = result {
+            println!("Failed to write to the json stream, closing it: {}", e);
+            self.json_writer = None;
+        }
+    }
+
+    // the json stream is written with blocking std::io from the runtime thread, so lines
+    // are buffered and only flushed once per pass over the connections
+    fn flush_json_stream(&mut self) {
+        if let Some(Err(e)) = self.json_writer.as_mut().map(|writer| writer.flush()) {
+            println!("Failed to write to the json stream, closing it: {}", e);
+            self.json_writer = None;
+        }
+    }
+
+    fn record_message(&mut self, message: &MessageContainer) {
+        *self
+            .message_counts
+            .entry(message.message_type())
+            .or_insert(0) += 1;
+        for record in message.unknown_tlvs() {
+            *self
+                .unknown_tlv_counts
+                .entry((message.message_type(), record.record_type))
+                .or_insert(0) += 1;
+        }
+    }
+
+    // returns false if the peer gossips only for chains other than ours
+    fn record_init(&mut self, init: &InitMessage, node_public_key: [u8; 33]) -> bool {
+        self.record_features(init, node_public_key);
+        match init.networks() {
+            Some(networks) if !networks.contains(&self.config.chain_hash) => {
+                println!(
+                    "Peer {} does not advertise our chain, not querying it for gossip",
+                    hex::encode(node_public_key)
+                );
+                self.other_chain_peers.insert(node_public_key);
+                false
+            }
+            _ => {
+                if init.supports_feature(&Features::GossipQueries) {
+                    self.last_gossip_from
+                        .insert(node_public_key, Instant::now());
+                }
+                true
             }
+        }
+    }
+
+    // None if the peer is no longer connected
+    fn connection_established(
+        &self,
+        init: &InitMessage,
+        node_public_key: [u8; 33],
+    ) -> Option<ConnectionEstablished> {
+        let node_conn = self.node_connections.get(&node_public_key)?;
+        let ours = InitMessage::from_config(&self.config).supported_features();
+        Some(ConnectionEstablished {
+            node_id: node_public_key,
+            address: node_conn.remote_address(),
+            direction: node_conn.direction,
+            handshake_duration: node_conn.handshake_duration,
+            negotiated_features: ours
+                .intersection(&init.supported_features())
+                .cloned()
+                .collect(),
+        })
+    }
+
+    // A node dropping features it advertised before may be a downgrade attempt, or a
+    // different node behind the same address. We only flag it.
+    fn record_features(&mut self, init: &InitMessage, node_public_key: [u8; 33]) -> bool {
+        let features = init.supported_features();
+        let downgraded = match self.known_features.get(&node_public_key) {
+            Some(previous) => {
+                let dropped: Vec<&Features> = previous.difference(&features).collect();
+                if !dropped.is_empty() {
+                    println!(
+                        "Node {} advertised fewer features than before, dropped {:?}",
+                        hex::encode(node_public_key),
+                        dropped
+                    );
+                }
+                !dropped.is_empty()
+            }
+            None => false,
         };
-        match node_connection.handshake().await {
-            Ok(_) => (),
-            Err(err) => {
-                println!("Failed to handshake: {:?}", err);
-                return Err(MessageHandlerError::NodeHandshakeError(err));
+        if downgraded {
+            self.feature_downgrades += 1;
+        }
+        self.known_features.insert(node_public_key, features);
+        downgraded
+    }
+
+    fn record_gossip_from(&mut self, node_public_key: [u8; 33]) {
+        if let Some(last_gossip) = self.last_gossip_from.get_mut(&node_public_key) {
+            *last_gossip = Instant::now();
+        }
+    }
+
+    fn peers_due_for_resync(&self) -> Vec<[u8; 33]> {
+        let interval = match self.config.gossip_resync_interval {
+            Some(interval) if self.config.store_gossip && !self.config.passive_mirror => interval,
+            _ => return Vec::new(),
+        };
+        self.last_gossip_from
+            .iter()
+            .filter(|(_, last_gossip)| last_gossip.elapsed() >= interval)
+            .map(|(node_public_key, _)| *node_public_key)
+            .collect()
+    }
+
+    // re-queries the whole channel range from peers that have gone quiet, in case we
+    // missed gossip while they stayed connected
+    async fn resync_gossip(&mut self) {
+        for node_public_key in self.peers_due_for_resync() {
+            if !self.node_connections.contains_key(&node_public_key) {
+                self.last_gossip_from.remove(&node_public_key);
+                continue;
             }
+            println!(
+                "No gossip from {} in a while, querying its channel range",
+                hex::encode(node_public_key)
+            );
+            if let Err(e) = self.start_range_sync(node_public_key).await {
+                println!("Failed to send query_channel_range: {:?}", e);
+            }
+            self.last_gossip_from
+                .insert(node_public_key, Instant::now());
+        }
+    }
+
+    // Queries the peer's whole channel range. The replies are collected by
+    // record_channel_range_reply, which then asks for the channels we are missing.
+    async fn start_range_sync(
+        &mut self,
+        node_public_key: [u8; 33],
+    ) -> Result<(), MessageHandlerError> {
+        // blocks 0 up to the tip, or every block there could be if no tip was set
+        let number_of_blocks = match self.chain_tip {
+            Some(tip) => tip.saturating_add(1),
+            None => u32::MAX,
         };
-        println!("Connected to node: {}", node.address());
-        let init = b"\x00\x10\x00\x00\x00\x01\xaa";
-        let (im, _) = InitMessage::from_bytes(init).unwrap();
-        let wrapped = MessageContainer::Init(im);
-        node_connection
-            .encrypt_and_send_message(&wrapped)
-            .await
-            .unwrap();
-        self.node_connections
-            .insert(node.public_key.clone(), node_connection);
+        let query = QueryChannelRangeMessage::new(self.config.chain_hash, 0, number_of_blocks);
+        let node_conn = match self.node_connections.get_mut(&node_public_key) {
+            Some(node_conn) => node_conn,
+            None => return Err(MessageHandlerError::NotConnected),
+        };
+        let sync = ChannelRangeSync::new(&query);
+        let wrapped = MessageContainer::QueryChannelRange(query);
+        if let Err(e) = node_conn.encrypt_and_send_message(&wrapped).await {
+            return Err(MessageHandlerError::NodeConnectionError(e));
+        }
+        self.range_syncs.insert(node_public_key, sync);
         Ok(())
     }
 
-    pub async fn handle_inbound_message(
+    async fn record_channel_range_reply(
         &mut self,
-        wrapped: MessageContainer,
+        reply: &ReplyChannelRangeMessage,
         node_public_key: [u8; 33],
     ) -> Result<(), MessageHandlerError> {
-        println!("Received message: {:?}", wrapped);
-        let node_conn = self.node_connections.get_mut(&node_public_key).unwrap();
-        match wrapped {
-            MessageContainer::Ping(ping) => {
-                let pong = MessageContainer::Pong(PongMessage::from_ping(ping));
-                match node_conn.encrypt_and_send_message(&pong).await {
-                    Ok(_) => (),
-                    Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
-                };
+        let sync = match self.range_syncs.get_mut(&node_public_key) {
+            Some(sync) => sync,
+            None => {
+                println!(
+                    "Ignoring reply_channel_range from {}, we have no query out",
+                    hex::encode(node_public_key)
+                );
+                return Ok(());
             }
-            MessageContainer::NodeAnnouncement(announcement) => {
-                if !self
-                    .node_connections
-                    .contains_key(&announcement.node_id.value)
+        };
+        match sync.add_reply(reply) {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                let sync = self.range_syncs.remove(&node_public_key).unwrap();
+                let unknown: Vec<ShortChannelIDElement> = sync
+                    .short_channel_ids()
+                    .iter()
+                    .filter(|short_channel_id| !self.graph.has_channel(short_channel_id))
+                    .cloned()
+                    .collect();
+                println!(
+                    "Channel range of {} complete, {} of its {} channels are new to us",
+                    hex::encode(node_public_key),
+                    unknown.len(),
+                    sync.short_channel_ids().len()
+                );
+                self.query_short_channel_ids(node_public_key, unknown).await
+            }
+            Err(e) => {
+                println!(
+                    "Bad reply_channel_range from {}: {}",
+                    hex::encode(node_public_key),
+                    e
+                );
+                self.range_syncs.remove(&node_public_key);
+                Err(MessageHandlerError::ChannelRangeSyncError(e))
+            }
+        }
+    }
+
+    // queues channels to ask the peer for, a query goes out unless one is in flight
+    async fn query_short_channel_ids(
+        &mut self,
+        node_public_key: [u8; 33],
+        short_channel_ids: Vec<ShortChannelIDElement>,
+    ) -> Result<(), MessageHandlerError> {
+        self.queued_scids
+            .entry(node_public_key)
+            .or_default()
+            .extend(short_channel_ids);
+        if self.scid_queries_in_flight.contains(&node_public_key) {
+            return Ok(());
+        }
+        self.send_next_scid_query(node_public_key).await
+    }
+
+    // BOLT 7: one query_short_channel_ids per peer at a time, the next goes out once
+    // reply_short_channel_ids_end answers the last
+    async fn send_next_scid_query(
+        &mut self,
+        node_public_key: [u8; 33],
+    ) -> Result<(), MessageHandlerError> {
+        self.scid_queries_in_flight.remove(&node_public_key);
+        let queued = match self.queued_scids.get_mut(&node_public_key) {
+            Some(queued) => queued,
+            None => return Ok(()),
+        };
+        let batch: Vec<ShortChannelIDElement> = queued
+            .drain(..queued.len().min(MAX_SHORT_CHANNEL_IDS_PER_QUERY))
+            .collect();
+        if queued.is_empty() {
+            self.queued_scids.remove(&node_public_key);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let node_conn = match self.node_connections.get_mut(&node_public_key) {
+            Some(node_conn) => node_conn,
+            None => return Err(MessageHandlerError::NotConnected),
+        };
+        println!(
+            "Querying {} channels from {}",
+            batch.len(),
+            hex::encode(node_public_key)
+        );
+        let query = MessageContainer::QueryShortChannelIds(QueryShortChannelIdsMessage::new(
+            self.config.chain_hash,
+            &batch,
+        ));
+        if let Err(e) = node_conn.encrypt_and_send_message(&query).await {
+            return Err(MessageHandlerError::NodeConnectionError(e));
+        }
+        self.scid_queries_in_flight.insert(node_public_key);
+        Ok(())
+    }
+
+    fn record_channel_ready(&mut self, msg: &ChannelReadyMessage, node_public_key: [u8; 33]) {
+        if let Some(alias) = msg.scid_alias() {
+            println!(
+                "Recorded scid alias {:?} for channel {:?}",
+                alias, msg.channel_id
+            );
+            self.scid_aliases.insert(alias, node_public_key);
+        }
+    }
+
+    // we get the tip out of band, nothing here follows the chain
+    #[allow(dead_code)]
+    pub fn set_chain_tip(&mut self, height: u32) {
+        self.chain_tip = Some(height);
+    }
+
+    // a channel can't be funded in a block that doesn't exist yet, so those are made up
+    fn record_channel_announcement(&mut self, msg: ChannelAnnouncementMessage, source: [u8; 33]) {
+        let short_channel_id = msg.short_channel_id.clone();
+        if let Some(tip) = self.chain_tip {
+            if short_channel_id.block_height > tip {
+                println!(
+                    "Rejecting channel {} from block {} beyond our tip {}",
+                    short_channel_id, short_channel_id.block_height, tip
+                );
+                self.future_announcements += 1;
+                return;
+            }
+        }
+        if self.graph.add_channel_announcement(msg) {
+            self.last_new_gossip = Instant::now();
+            println!("Found new channel: {:?}", short_channel_id);
+            println!("Known channels: {}", self.graph.num_channels());
+            for direction in 0..2 {
+                if let Some((received, update)) = self
+                    .orphan_updates
+                    .remove(&(short_channel_id.clone(), direction))
                 {
-                    if !self.known_nodes.contains_key(&announcement.node_id) {
-                        self.known_nodes
-                            .insert(announcement.node_id.clone(), announcement.clone());
-                        println!("Found new node: {:?}", announcement.node_id.clone());
-                        println!("Known nodes: {}", self.known_nodes.len())
+                    if received.elapsed() < self.config.orphan_update_window {
+                        self.record_channel_update(update);
                     }
-                    match announcement.as_node() {
-                        Some(node) => {
-                            println!("Found new node: {}", node.address());
-                            if DO_CONNECT_TO_NEW_NODES {
-                                self.open_node_connection(&node).await.unwrap();
-                            } else {
-                                println!(
-                                   "Not connecting to new node because DO_CONNECT_TO_NEW_NODES=false."
-                               );
-                            }
-                        }
-                        None => {
-                            println!("Found no address in node announcement");
-                        }
-                    }
-                } else {
-                    println!("Already connected to node.");
                 }
             }
-            MessageContainer::ChannelAnnouncement(msg) => {
-                if !self
-                    .known_channels
-                    .contains_key(&msg.short_channel_id.clone())
-                {
-                    self.known_channels
-                        .insert(msg.short_channel_id.clone(), msg.clone());
-                    println!("Found new channel: {:?}", msg.short_channel_id.clone());
-                    println!("Known channels: {}", self.known_channels.len())
-                } else {
+        }
+        // rebroadcasts of a channel we already hold count too, rejected ones don't
+        if self.graph.has_channel(&short_channel_id) {
+            self.channel_sources
+                .entry(short_channel_id)
+                .or_default()
+                .insert(source);
+        }
+    }
+
+    // the peers that sent us the announcement of short_channel_id
+    #[allow(dead_code)]
+    pub fn channel_sources(
+        &self,
+        short_channel_id: &ShortChannelIDElement,
+    ) -> Option<&HashSet<[u8; 33]>> {
+        self.channel_sources.get(short_channel_id)
+    }
+
+    // BOLT 7: an update for a channel we don't know yet is held until its announcement
+    // arrives, so it is never stored for a channel we couldn't verify
+    fn buffer_orphan_update(&mut self, msg: ChannelUpdateMessage) {
+        let window = self.config.orphan_update_window;
+        self.orphan_updates
+            .retain(|_, (received, _)| received.elapsed() < window);
+        let key = (msg.short_channel_id.clone(), msg.direction());
+        match self.orphan_updates.get(&key) {
+            Some((_, buffered)) if buffered.timestamp >= msg.timestamp => return,
+            Some(_) => {}
+            None if self.orphan_updates.len() >= MAX_ORPHAN_UPDATES => {
+                let oldest = self
+                    .orphan_updates
+                    .iter()
+                    .min_by_key(|(_, (received, _))| *received)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.orphan_updates.remove(&oldest);
                 }
             }
-            MessageContainer::GossipTimestampFilter(gtf) => {
-                let mut our_filter = gtf.clone();
-                // we ask for all the gossip
-                our_filter.first_timestamp = 0;
-                let response = MessageContainer::GossipTimestampFilter(our_filter);
-                match node_conn.encrypt_and_send_message(&response).await {
-                    Ok(_) => (),
-                    Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+            None => {}
+        }
+        println!(
+            "Holding channel_update for unannounced channel {}",
+            msg.short_channel_id
+        );
+        self.orphan_updates.insert(key, (Instant::now(), msg));
+    }
+
+    fn record_channel_update(&mut self, msg: ChannelUpdateMessage) {
+        if self.graph.get_channel(&msg.short_channel_id).is_none() {
+            self.buffer_orphan_update(msg);
+            return;
+        }
+        let key = (msg.short_channel_id.clone(), msg.direction());
+        let (timestamp, enabled) = (msg.timestamp, !msg.is_disabled());
+        if !self.graph.add_channel_update(msg) {
+            return;
+        }
+        let history = self.enabled_history.entry(key).or_default();
+        if history.last().map(|(_, was_enabled)| *was_enabled) != Some(enabled) {
+            history.push((timestamp, enabled));
+            if history.len() > CHANNEL_HISTORY_LEN {
+                history.remove(0);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn channel_history(
+        &self,
+        short_channel_id: &ShortChannelIDElement,
+        direction: usize,
+    ) -> &[(u32, bool)] {
+        self.enabled_history
+            .get(&(short_channel_id.clone(), direction))
+            .map(|history| history.as_slice())
+            .unwrap_or(&[])
+    }
+
+    fn record_stfu(&mut self, msg: &StfuMessage) {
+        println!("Channel {:?} is quiescent", msg.channel_id);
+        self.splice_states
+            .entry(msg.channel_id.clone())
+            .or_default()
+            .quiescent = true;
+    }
+
+    fn record_tx_init_rbf(&mut self, msg: &TxInitRbfMessage) {
+        let state = self
+            .splice_states
+            .entry(msg.channel_id.clone())
+            .or_default();
+        println!(
+            "Channel {:?} splice rbf proposed at {} sat/kw (previous {:?}), contribution {:?}",
+            msg.channel_id,
+            msg.feerate,
+            state.rbf_feerates.last(),
+            msg.funding_output_contribution()
+        );
+        state.rbf_feerates.push(msg.feerate);
+        state.rbf_acked = false;
+    }
+
+    fn record_tx_ack_rbf(&mut self, msg: &TxAckRbfMessage) {
+        let state = self
+            .splice_states
+            .entry(msg.channel_id.clone())
+            .or_default();
+        println!(
+            "Channel {:?} splice rbf accepted at {:?} sat/kw, contribution {:?}",
+            msg.channel_id,
+            state.rbf_feerates.last(),
+            msg.funding_output_contribution()
+        );
+        state.rbf_acked = true;
+    }
+
+    #[allow(dead_code)]
+    pub fn splice_state(&self, channel_id: &ChannelIDElement) -> Option<SpliceState> {
+        self.splice_states.get(channel_id).cloned()
+    }
+
+    #[allow(dead_code)]
+    pub fn scid_alias_peer(&self, alias: &ShortChannelIDElement) -> Option<[u8; 33]> {
+        self.scid_aliases.get(alias).copied()
+    }
+
+    #[allow(dead_code)]
+    pub fn node_profile(&self, node_public_key: [u8; 33]) -> Option<NodeProfile> {
+        self.graph
+            .get_node(&PointElement {
+                value: node_public_key,
+            })
+            .map(|announcement| NodeProfile::from_announcement(&announcement))
+    }
+
+    // our answer to a query_channel_range, never channels of a chain other than ours
+    fn channel_range_replies(
+        &self,
+        query: &QueryChannelRangeMessage,
+    ) -> Vec<ReplyChannelRangeMessage> {
+        if query.chain_hash.value == self.config.chain_hash {
+            return self
+                .graph
+                .channel_range_replies(query, self.config.max_short_channel_ids_per_reply);
+        }
+        println!(
+            "Query for chain {} we don't serve, replying empty",
+            hex::encode(query.chain_hash.value)
+        );
+        // BOLT 7: a chain we don't know gets a single empty reply with sync_complete unset
+        vec![ReplyChannelRangeMessage::new(
+            query.chain_hash.value,
+            query.first_blocknum,
+            query.number_of_blocks,
+            false,
+            &[],
+            None,
+            None,
+        )]
+    }
+
+    // channels the node is an endpoint of, owned since the store hands out copies
+    #[allow(dead_code)]
+    pub fn node_channels(&self, node_public_key: [u8; 33]) -> Vec<ChannelAnnouncementMessage> {
+        self.graph.node_channels(&node_public_key)
+    }
+
+    // feature bit -> how many announced nodes set it. Compulsory and optional are
+    // separate bits, so they are counted separately.
+    #[allow(dead_code)]
+    pub fn feature_histogram(&self) -> HashMap<usize, usize> {
+        let mut histogram = HashMap::new();
+        for node in self.graph.nodes() {
+            for bit in node.features.set_bits() {
+                *histogram.entry(bit).or_insert(0) += 1;
+            }
+        }
+        histogram
+    }
+
+    #[allow(dead_code)]
+    pub fn network_summary(&self) -> NetworkSummary {
+        self.graph.summary()
+    }
+
+    #[allow(dead_code)]
+    pub fn metrics_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE lnminipeer_messages_total counter\n");
+        for message_type in MessageType::iter() {
+            if let Some(count) = self.message_counts.get(&message_type) {
+                out.push_str(&format!(
+                    "lnminipeer_messages_total{{type=\"{}\"}} {}\n",
+                    sanitize_label_value(message_type.wire_name()),
+                    count
+                ));
+            }
+        }
+        // what each connected peer sent on the wire, decodable or not
+        out.push_str("# TYPE lnminipeer_received_messages_total counter\n");
+        let mut node_public_keys: Vec<&[u8; 33]> = self.node_connections.keys().collect();
+        node_public_keys.sort();
+        for node_public_key in node_public_keys {
+            let received_types = self.node_connections[node_public_key].received_types();
+            for message_type in MessageType::iter() {
+                if let Some(count) = received_types.get(&message_type) {
+                    out.push_str(&format!(
+                        "lnminipeer_received_messages_total{{peer=\"{}\",type=\"{}\"}} {}\n",
+                        hex::encode(node_public_key),
+                        sanitize_label_value(message_type.wire_name()),
+                        count
+                    ));
+                }
+            }
+        }
+        out.push_str("# TYPE lnminipeer_unknown_tlvs_total counter\n");
+        let mut unknown_tlvs: Vec<_> = self.unknown_tlv_counts.iter().collect();
+        unknown_tlvs
+            .sort_by_key(|((message_type, tlv_type), _)| (message_type.as_u16(), *tlv_type));
+        for ((message_type, tlv_type), count) in unknown_tlvs {
+            out.push_str(&format!(
+                "lnminipeer_unknown_tlvs_total{{type=\"{}\",tlv=\"{}\"}} {}\n",
+                sanitize_label_value(message_type.wire_name()),
+                tlv_type,
+                count
+            ));
+        }
+        out.push_str("# TYPE lnminipeer_feature_downgrades_total counter\n");
+        out.push_str(&format!(
+            "lnminipeer_feature_downgrades_total {}\n",
+            self.feature_downgrades
+        ));
+        out.push_str("# TYPE lnminipeer_future_channel_announcements_total counter\n");
+        out.push_str(&format!(
+            "lnminipeer_future_channel_announcements_total {}\n",
+            self.future_announcements
+        ));
+        out.push_str("# TYPE lnminipeer_stale_node_announcements_total counter\n");
+        out.push_str(&format!(
+            "lnminipeer_stale_node_announcements_total {}\n",
+            self.stale_node_announcements
+        ));
+        out.push_str("# TYPE lnminipeer_connect_timeouts_total counter\n");
+        out.push_str(&format!(
+            "lnminipeer_connect_timeouts_total {}\n",
+            self.connect_timeouts
+        ));
+        for (metric, value) in [
+            ("lnminipeer_connections", self.node_connections.len()),
+            ("lnminipeer_channels_known", self.graph.num_channels()),
+            ("lnminipeer_nodes_known", self.graph.num_nodes()),
+        ] {
+            out.push_str(&format!("# TYPE {} gauge\n", metric));
+            out.push_str(&format!("{} {}\n", metric, value));
+        }
+        out
+    }
+
+    // never blocks on one connection, so quiet peers are pinged and dead ones dropped
+    pub async fn event_loop(&mut self) {
+        loop {
+            self.process_ready_connections(READ_WAIT).await;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    // the event loop, but inbound peers on listener are accepted while it waits between
+    // passes instead of sleeping
+    pub async fn serve(&mut self, listener: TcpListener) {
+        loop {
+            self.process_ready_connections(READ_WAIT).await;
+            // accept is cancel safe, a peer arriving as the wait ends is picked up next pass
+            let stream =
+                match tokio::time::timeout(Duration::from_millis(500), listener.accept()).await {
+                    Ok(Ok((stream, address))) => {
+                        println!("Inbound connection from {}", address);
+                        stream
+                    }
+                    Ok(Err(err)) => {
+                        println!("Failed to accept inbound connection: {:?}", err);
+                        continue;
+                    }
+                    Err(_) => continue,
                 };
+            // a peer that stalls the handshake can't hold up everyone else
+            let budget = self.config.connect_budget;
+            if tokio::time::timeout(budget, self.accept_node_connection(stream))
+                .await
+                .is_err()
+            {
+                println!("Inbound handshake took longer than {:?}, dropped", budget);
             }
-            _ => {}
         }
-        Ok(())
+    }
+
+    // runs the event loop until no new channels or nodes have been seen for idle_timeout
+    #[allow(dead_code)]
+    pub async fn run_until_synced(&mut self, idle_timeout: Duration) {
+        while !self.gossip_is_quiet(idle_timeout) {
+            self.process_ready_connections(READ_WAIT).await;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        println!(
+            "Gossip quiet for {:?}, stopping with {} channels and {} nodes",
+            idle_timeout,
+            self.graph.num_channels(),
+            self.graph.num_nodes()
+        );
+    }
+
+    fn gossip_is_quiet(&self, idle_timeout: Duration) -> bool {
+        self.last_new_gossip.elapsed() >= idle_timeout
+    }
+
+    // reads one message from every connection and handles them
+    pub async fn process_connections(&mut self) {
+        self.process_connections_within(None).await;
+    }
+
+    // like process_connections, but skips connections with nothing to read within wait,
+    // so the caller is not blocked on a quiet peer
+    pub async fn process_ready_connections(&mut self, wait: Duration) {
+        self.process_connections_within(Some(wait)).await;
+    }
+
+    async fn process_connections_within(&mut self, wait: Option<Duration>) {
+        let mut inbounds = Vec::new();
+        let mut disconnects = Vec::new();
+        for node_conn in &mut self.node_connections.values_mut() {
+            if node_conn.is_unresponsive(self.config.ping_timeout) {
+                println!(
+                    "No answer to ping from {} in {:?}, disconnecting",
+                    hex::encode(node_conn.public_key),
+                    self.config.ping_timeout
+                );
+                disconnects.push(node_conn.public_key.clone());
+                continue;
+            }
+            if let Some(wait) = wait {
+                if !node_conn.has_message_within(wait).await {
+                    if node_conn.ready_for_ping(self.config.ping_interval) {
+                        if let Err(err) = node_conn.send_ping().await {
+                            println!(
+                                "Failed to ping {}, dropping the peer: {:?}",
+                                hex::encode(node_conn.public_key),
+                                err
+                            );
+                            disconnects.push(node_conn.public_key);
+                        }
+                    }
+                    continue;
+                }
+            }
+            match node_conn.read_next_message().await {
+                Ok(wrapped_message) => {
+                    inbounds.push((wrapped_message, node_conn.public_key.clone()));
+                }
+                Err(err) => {
+                    match err {
+                        NodeConnectionError::IOError(_) | NodeConnectionError::MalformedMessage => {
+                            disconnects.push(node_conn.public_key.clone());
+                        }
+                        _ => {
+                            println!("Failed to read: {:?}", err);
+                        }
+                    }
+                    continue;
+                }
+            }
+            if node_conn.ready_for_ping(self.config.ping_interval) {
+                if let Err(err) = node_conn.send_ping().await {
+                    println!(
+                        "Failed to ping {}, dropping the peer: {:?}",
+                        hex::encode(node_conn.public_key),
+                        err
+                    );
+                    disconnects.push(node_conn.public_key);
+                }
+            }
+        }
+        for node_public_key in disconnects {
+            self.forget_connection(node_public_key);
+        }
+        pings_first(&mut inbounds);
+        let mut disconnects = Vec::new();
+        for (message, node_public_key) in inbounds {
+            // an earlier message may have got the peer disconnected
+            if !self.node_connections.contains_key(&node_public_key) {
+                continue;
+            }
+            let message_type = message.message_type();
+            match self.handle_inbound_message(message, node_public_key).await {
+                Ok(_) => (),
+                Err(MessageHandlerError::NodeConnectionError(err)) => {
+                    println!(
+                        "Failed to handle {:?} from {}, dropping the peer: {:?}",
+                        message_type,
+                        hex::encode(node_public_key),
+                        err
+                    );
+                    disconnects.push(node_public_key);
+                }
+                Err(err) => {
+                    println!(
+                        "Failed to handle {:?} from {}: {:?}",
+                        message_type,
+                        hex::encode(node_public_key),
+                        err
+                    );
+                }
+            }
+        }
+        for node_public_key in disconnects {
+            self.forget_connection(node_public_key);
+        }
+        self.flush_json_stream();
+        self.resync_gossip().await;
+    }
+
+    // for connections that are already broken, nothing is sent to the peer
+    fn forget_connection(&mut self, node_public_key: [u8; 33]) {
+        self.node_connections.remove(&node_public_key);
+        self.forget_gossip_state(node_public_key);
+    }
+
+    // syncs and queries in progress with a peer end with its connection
+    fn forget_gossip_state(&mut self, node_public_key: [u8; 33]) {
+        self.last_gossip_from.remove(&node_public_key);
+        self.range_syncs.remove(&node_public_key);
+        self.queued_scids.remove(&node_public_key);
+        self.scid_queries_in_flight.remove(&node_public_key);
+    }
+
+    pub async fn open_node_connection(&mut self, node: &Node) -> Result<(), MessageHandlerError> {
+        self.open_node_connection_cancellable(node, CancellationToken::new())
+            .await
+    }
+
+    // like open_node_connection, but gives up as soon as cancel is cancelled. Whatever
+    // part of the connection exists by then is dropped.
+    pub async fn open_node_connection_cancellable(
+        &mut self,
+        node: &Node,
+        cancel: CancellationToken,
+    ) -> Result<(), MessageHandlerError> {
+        self.check_dial_target(node)?;
+        let attempt = tokio::select! {
+            result = MiniPeer::dial(node, self.secret_key, &self.config) => result,
+            _ = cancel.cancelled() => {
+                println!("Connection attempt to {} cancelled", node.address());
+                return Err(MessageHandlerError::Cancelled);
+            }
+        };
+        self.add_dialed_connection(node, attempt).await
+    }
+
+    pub fn check_dial_target(&self, node: &Node) -> Result<(), MessageHandlerError> {
+        if node.public_key == self.node_id() {
+            println!("Not connecting to {}, that is us", node.address());
+            return Err(MessageHandlerError::SelfConnection);
+        }
+        Ok(())
+    }
+
+    // what dial needs, for callers that dial without holding on to the peer
+    pub fn dial_settings(&self) -> (SecretKey, PeerConfig) {
+        (self.secret_key, self.config.clone())
+    }
+
+    // Connects and exchanges init within config.connect_budget. Doesn't touch the peer,
+    // so a shared peer needn't be locked for the duration; add_dialed_connection takes
+    // the result.
+    pub async fn dial(
+        node: &Node,
+        secret_key: SecretKey,
+        config: &PeerConfig,
+    ) -> Result<(NodeConnection, MessageContainer), MessageHandlerError> {
+        let budget = config.connect_budget;
+        match tokio::time::timeout(
+            budget,
+            MiniPeer::connect_and_exchange_init(node, secret_key, config),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                println!(
+                    "Giving up on {} after {:?} without an init exchange",
+                    node.address(),
+                    budget
+                );
+                Err(MessageHandlerError::Timeout)
+            }
+        }
+    }
+
+    pub async fn add_dialed_connection(
+        &mut self,
+        node: &Node,
+        attempt: Result<(NodeConnection, MessageContainer), MessageHandlerError>,
+    ) -> Result<(), MessageHandlerError> {
+        let (node_connection, first_message) = match attempt {
+            Ok(result) => result,
+            Err(MessageHandlerError::Timeout) => {
+                self.connect_timeouts += 1;
+                return Err(MessageHandlerError::Timeout);
+            }
+            Err(err) => return Err(err),
+        };
+        self.node_connections
+            .insert(node.public_key.clone(), node_connection);
+        // boxed, handling a node_announcement can open another connection
+        Box::pin(self.handle_inbound_message(first_message, node.public_key)).await
+    }
+
+    // dials, handshakes, sends our init and returns the peer's first message, which
+    // BOLT 1 requires to be its init
+    async fn connect_and_exchange_init(
+        node: &Node,
+        secret_key: SecretKey,
+        config: &PeerConfig,
+    ) -> Result<(NodeConnection, MessageContainer), MessageHandlerError> {
+        let mut node_connection = match NodeConnection::new(node, secret_key).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to create node connection: {:?}", err);
+                return Err(MessageHandlerError::NodeConnectionError(err));
+            }
+        };
+        match node_connection.handshake().await {
+            Ok(_) => (),
+            Err(err) => {
+                println!("Failed to handshake: {:?}", err);
+                return Err(MessageHandlerError::NodeHandshakeError(err));
+            }
+        };
+        println!("Connected to node: {}", node.address());
+        node_connection.set_strict_points(config.strict_points);
+        if let Err(err) = node_connection.set_nodelay(config.tcp_nodelay) {
+            println!("Failed to set TCP_NODELAY: {:?}", err);
+        }
+        let wrapped = MessageContainer::Init(InitMessage::from_config(config));
+        if let Err(err) = node_connection.encrypt_and_send_message(&wrapped).await {
+            return Err(MessageHandlerError::NodeConnectionError(err));
+        }
+        let first_message = match node_connection.read_next_message().await {
+            Ok(first_message) => first_message,
+            Err(err) => return Err(MessageHandlerError::NodeConnectionError(err)),
+        };
+        if let MessageContainer::Init(_) = first_message {
+            return Ok((node_connection, first_message));
+        }
+        println!(
+            "Expected init from {}, got {:?}",
+            node.address(),
+            first_message
+        );
+        // best effort, we are closing the connection either way
+        let _ = node_connection.close(Some("expected init")).await;
+        Err(MessageHandlerError::InitExpected(
+            first_message.message_type(),
+        ))
+    }
+
+    // Queries a peer's channel range and collects the short_channel_ids of the replies,
+    // handling anything else the peer sends meanwhile. Fails on replies that leave gaps
+    // or overlap instead of trusting a peer's idea of the range.
+    #[allow(dead_code)]
+    pub async fn sync_channel_range(
+        &mut self,
+        node_public_key: [u8; 33],
+        first_blocknum: u32,
+        number_of_blocks: u32,
+    ) -> Result<Vec<ShortChannelIDElement>, MessageHandlerError> {
+        let query =
+            QueryChannelRangeMessage::new(self.config.chain_hash, first_blocknum, number_of_blocks);
+        let mut sync = ChannelRangeSync::new(&query);
+        let node_conn = match self.node_connections.get_mut(&node_public_key) {
+            Some(node_conn) => node_conn,
+            None => return Err(MessageHandlerError::NotConnected),
+        };
+        let wrapped = MessageContainer::QueryChannelRange(query);
+        if let Err(e) = node_conn.encrypt_and_send_message(&wrapped).await {
+            return Err(MessageHandlerError::NodeConnectionError(e));
+        }
+        loop {
+            let node_conn = match self.node_connections.get_mut(&node_public_key) {
+                Some(node_conn) => node_conn,
+                None => return Err(MessageHandlerError::NotConnected),
+            };
+            // waiting is safe to give up on, a read cut off halfway would lose the message
+            let reply_timeout = self.config.channel_range_reply_timeout;
+            if !node_conn.has_message_within(reply_timeout).await {
+                println!(
+                    "No reply_channel_range from {} in {:?}, giving up on the sync",
+                    hex::encode(node_public_key),
+                    reply_timeout
+                );
+                return Err(MessageHandlerError::Timeout);
+            }
+            let message = match node_conn.read_next_message().await {
+                Ok(message) => message,
+                Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+            };
+            let reply = match &message {
+                MessageContainer::ReplyChannelRange(reply) => reply,
+                _ => {
+                    self.handle_inbound_message(message, node_public_key)
+                        .await?;
+                    continue;
+                }
+            };
+            self.record_message(&message);
+            match sync.add_reply(reply) {
+                Ok(true) => return Ok(sync.short_channel_ids().to_vec()),
+                Ok(false) => (),
+                Err(e) => {
+                    println!(
+                        "Bad reply_channel_range from {}: {}",
+                        hex::encode(node_public_key),
+                        e
+                    );
+                    return Err(MessageHandlerError::ChannelRangeSyncError(e));
+                }
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn refresh_gossip(
+        &mut self,
+        node_public_key: [u8; 33],
+        first_timestamp: u32,
+        timestamp_range: u32,
+    ) -> Result<(), MessageHandlerError> {
+        if self.other_chain_peers.contains(&node_public_key) {
+            return Err(MessageHandlerError::UnsupportedChain);
+        }
+        let node_conn = match self.node_connections.get_mut(&node_public_key) {
+            Some(node_conn) => node_conn,
+            None => return Err(MessageHandlerError::NotConnected),
+        };
+        let filter = MessageContainer::GossipTimestampFilter(GossipTimestampFilterMessage::new(
+            self.config.chain_hash,
+            first_timestamp,
+            timestamp_range,
+        ));
+        match node_conn.encrypt_and_send_message(&filter).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MessageHandlerError::NodeConnectionError(e)),
+        }
+    }
+
+    // takes a stream a peer opened to us, handshakes, and checks it against allowed_peers
+    pub async fn accept_node_connection(
+        &mut self,
+        stream: TcpStream,
+    ) -> Result<(), MessageHandlerError> {
+        let mut node_connection = match NodeConnection::accept(stream, self.secret_key).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("Failed to handshake with inbound peer: {:?}", err);
+                return Err(MessageHandlerError::NodeHandshakeError(err));
+            }
+        };
+        let public_key = node_connection.public_key;
+        if let Some(allowed_peers) = &self.config.allowed_peers {
+            if !allowed_peers.contains(&public_key) {
+                println!(
+                    "Rejecting inbound peer {} not in allowed_peers",
+                    hex::encode(public_key)
+                );
+                // best effort, we are closing the connection either way
+                let _ = node_connection.close(Some("peer not allowed")).await;
+                return Err(MessageHandlerError::PeerNotAllowed);
+            }
+        }
+        println!("Accepted inbound peer {}", hex::encode(public_key));
+        node_connection.set_strict_points(self.config.strict_points);
+        if let Err(err) = node_connection.set_nodelay(self.config.tcp_nodelay) {
+            println!("Failed to set TCP_NODELAY: {:?}", err);
+        }
+        let wrapped = MessageContainer::Init(InitMessage::from_config(&self.config));
+        match node_connection.encrypt_and_send_message(&wrapped).await {
+            Ok(_) => (),
+            Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+        };
+        self.node_connections.insert(public_key, node_connection);
+        Ok(())
+    }
+
+    // registers handler for message_type, replacing any earlier one for that type
+    #[allow(dead_code)]
+    pub fn on<F, Fut>(&mut self, message_type: MessageType, handler: F)
+    where
+        F: Fn(MessageContainer, [u8; 33]) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.handlers.insert(
+            message_type,
+            Box::new(move |message, node_public_key| Box::pin(handler(message, node_public_key))),
+        );
+    }
+
+    pub async fn handle_inbound_message(
+        &mut self,
+        wrapped: MessageContainer,
+        node_public_key: [u8; 33],
+    ) -> Result<(), MessageHandlerError> {
+        // only copied when someone registered for the type
+        let for_handler = self
+            .handlers
+            .contains_key(&wrapped.message_type())
+            .then(|| wrapped.clone());
+        let result = self.handle_builtin_message(wrapped, node_public_key).await;
+        if let Some(message) = for_handler {
+            if let Some(handler) = self.handlers.get(&message.message_type()) {
+                handler(message, node_public_key).await;
+            }
+        }
+        result
+    }
+
+    async fn handle_builtin_message(
+        &mut self,
+        wrapped: MessageContainer,
+        node_public_key: [u8; 33],
+    ) -> Result<(), MessageHandlerError> {
+        if let Some(line) = self.message_log_line(&wrapped) {
+            println!("{}", line);
+        }
+        self.record_message(&wrapped);
+        self.write_json_line(&wrapped, node_public_key);
+        if matches!(
+            wrapped,
+            MessageContainer::ChannelAnnouncement(_)
+                | MessageContainer::ChannelUpdate(_)
+                | MessageContainer::NodeAnnouncement(_)
+        ) {
+            self.record_gossip_from(node_public_key);
+        }
+        let node_conn = self.node_connections.get_mut(&node_public_key).unwrap();
+        match wrapped {
+            MessageContainer::Ping(ping) => {
+                let pong = MessageContainer::Pong(PongMessage::from_ping(ping));
+                match node_conn.encrypt_and_send_message(&pong).await {
+                    Ok(_) => (),
+                    Err(e) => return Err(MessageHandlerError::NodeConnectionError(e)),
+                };
+            }
+            MessageContainer::Pong(pong) => {
+                if !node_conn.answers_ping(&pong) {
+                    println!(
+                        "Pong from {} answers no ping of ours, disconnecting",
+                        hex::encode(node_public_key)
+                    );
+                    self.disconnect(node_public_key).await;
+                }
+            }
+            // rebroadcasts are the bulk of node gossip, they are dropped before any other work
+            MessageContainer::NodeAnnouncement(announcement)
+                if self.config.store_gossip
+                    && self.graph.is_stale_node_announcement(&announcement) =>
+            {
+                self.stale_node_announcements += 1;
+            }
+            MessageContainer::NodeAnnouncement(announcement) => {
+                // stored for connected peers too, peer_addresses looks them up
+                let known = self.graph.has_node(&announcement.node_id);
+                if self.config.store_gossip
+                    && self.graph.add_node_announcement(announcement.clone())
+                {
+                    self.last_new_gossip = Instant::now();
+                    if known {
+                        println!("Updated node: {:?}", announcement.node_id);
+                    } else {
+                        println!("Found new node: {:?}", announcement.node_id);
+                    }
+                    println!("Known nodes: {}", self.graph.num_nodes())
+                }
+                if !self
+                    .node_connections
+                    .contains_key(&announcement.node_id.value)
+                {
+                    match announcement.as_node() {
+                        Some(node) => {
+                            println!("Node announced at {}", node.address());
+                            if node.public_key == self.node_id() {
+                                println!("Not connecting to our own announcement.");
+                            } else if self.dialed_nodes.contains(&node) {
+                                println!("Already dialed node.");
+                            } else if DO_CONNECT_TO_NEW_NODES && !self.config.passive_mirror {
+                                self.dialed_nodes.insert(node.clone());
+                                self.open_node_connection(&node).await.unwrap();
+                            } else {
+                                println!(
+                                   "Not connecting to new node because DO_CONNECT_TO_NEW_NODES=false."
+                               );
+                            }
+                        }
+                        None => {
+                            println!("Found no address in node announcement");
+                        }
+                    }
+                } else {
+                    println!("Already connected to node.");
+                }
+            }
+            MessageContainer::ChannelAnnouncement(_) | MessageContainer::ChannelUpdate(_)
+                if !self.config.store_gossip => {}
+            MessageContainer::ChannelAnnouncement(msg) => {
+                self.record_channel_announcement(msg, node_public_key);
+            }
+            MessageContainer::ChannelReady(msg) => {
+                self.record_channel_ready(&msg, node_public_key);
+            }
+            MessageContainer::Stfu(msg) => {
+                self.record_stfu(&msg);
+            }
+            MessageContainer::TxInitRbf(msg) => {
+                self.record_tx_init_rbf(&msg);
+            }
+            MessageContainer::TxAckRbf(msg) => {
+                self.record_tx_ack_rbf(&msg);
+            }
+            MessageContainer::ChannelUpdate(msg) => {
+                self.record_channel_update(msg);
+            }
+            MessageContainer::Init(init) => {
+                if let Some(event) = self.connection_established(&init, node_public_key) {
+                    println!("{}", event);
+                }
+                let on_our_chain = self.record_init(&init, node_public_key);
+                if !on_our_chain && self.config.disconnect_other_chains {
+                    self.disconnect(node_public_key).await;
+                    self.other_chain_peers.remove(&node_public_key);
+                } else if on_our_chain
+                    && self.config.store_gossip
+                    && !self.config.passive_mirror
+                    && init.supports_feature(&Features::GossipQueries)
+                {
+                    let node_conn = self.node_connections.get_mut(&node_public_key).unwrap();
+                    if let Some((first_timestamp, timestamp_range)) =
+                        self.config.gossip_timestamp_filter
+                    {
+                        let filter = MessageContainer::GossipTimestampFilter(
+                            GossipTimestampFilterMessage::new(
+                                self.config.chain_hash,
+                                first_timestamp,
+                                timestamp_range,
+                            ),
+                        );
+                        if let Err(e) = node_conn.encrypt_and_send_message(&filter).await {
+                            return Err(MessageHandlerError::NodeConnectionError(e));
+                        }
+                    }
+                    if self.config.proactive_sync {
+                        self.start_range_sync(node_public_key).await?;
+                    }
+                }
+            }
+            MessageContainer::Warning(warning) => {
+                // nothing to act on, the peer keeps the connection open
+                println!(
+                    "Warning from {}: {}",
+                    hex::encode(node_public_key),
+                    warning.data_as_string()
+                );
+            }
+            MessageContainer::Error(error) => {
+                let scope = if error.applies_to_all_channels() {
+                    "all channels".to_string()
+                } else {
+                    format!("channel {}", hex::encode(error.channel_id.value))
+                };
+                println!(
+                    "Error from {} for {}: {}",
+                    hex::encode(node_public_key),
+                    scope,
+                    error.data_as_string()
+                );
+            }
+            MessageContainer::ReplyChannelRange(reply) => {
+                self.record_channel_range_reply(&reply, node_public_key)
+                    .await?;
+            }
+            MessageContainer::ReplyShortChannelIdsEnd(end) => {
+                if end.full_information == 0 {
+                    println!(
+                        "{} lacks up to date information on channels we queried",
+                        hex::encode(node_public_key)
+                    );
+                }
+                self.send_next_scid_query(node_public_key).await?;
+            }
+            MessageContainer::QueryChannelRange(query) => {
+                for reply in self.channel_range_replies(&query) {
+                    let wrapped = MessageContainer::ReplyChannelRange(reply);
+                    if let Err(e) = node_conn.encrypt_and_send_message(&wrapped).await {
+                        return Err(MessageHandlerError::NodeConnectionError(e));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// None if the file can't be opened, the peer runs without the stream then
+fn open_json_stream(stream: &JsonStream) -> Option<Box<dyn Write + Send>> {
+    match stream {
+        JsonStream::Stdout => Some(Box::new(BufWriter::new(io::stdout()))),
+        JsonStream::File(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(Box::new(BufWriter::new(file))),
+            Err(e) => {
+                println!("Failed to open json stream {}: {}", path.display(), e);
+                None
+            }
+        },
+    }
+}
+
+// A peer that waits too long for its pong drops us, so pings are answered before a
+// backlog of gossip. The order is kept otherwise.
+fn pings_first(inbounds: &mut [(MessageContainer, [u8; 33])]) {
+    inbounds.sort_by_key(|(message, _)| !matches!(message, MessageContainer::Ping(_)));
+}
+
+// escape per the prometheus text exposition format
+fn sanitize_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_graph::ChannelEdge;
+    use crate::messages::{
+        ErrorMessage, NodeAnnouncementMessage, PingMessage, ReplyShortChannelIdsEndMessage,
+    };
+    use crate::serialization::{FeaturesElement, IgnoredBytesElement};
+    use crate::test_util::example_message;
+    use crate::util::new_random_secret_key;
+    use bitcoin::secp256k1::PublicKey;
+
+    const CHANNEL_READY: &str = "0024aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa02c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e1401080000710000010000";
+
+    fn is_valid_prometheus_line(line: &str) -> bool {
+        if line.starts_with("# TYPE ") {
+            return line.split(' ').count() == 4;
+        }
+        let (series, value) = match line.rsplit_once(' ') {
+            Some(x) => x,
+            None => return false,
+        };
+        if value.parse::<f64>().is_err() {
+            return false;
+        }
+        let name = match series.split_once('{') {
+            Some((name, labels)) => {
+                if !labels.ends_with('}') || !labels.contains("=\"") {
+                    return false;
+                }
+                name
+            }
+            None => series,
+        };
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    #[test]
+    fn test_metrics_prometheus() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        peer.record_message(&ping);
+        peer.record_message(&ping);
+        let init = InitMessage::new(vec![], vec![0xaa], &[]).with_custom_tlv(65537, vec![1]);
+        peer.record_message(&MessageContainer::Init(init));
+        let metrics = peer.metrics_prometheus();
+        assert!(metrics.contains("lnminipeer_messages_total{type=\"ping\"} 2\n"));
+        assert!(metrics.contains("lnminipeer_unknown_tlvs_total{type=\"init\",tlv=\"65537\"} 1\n"));
+        assert!(metrics.contains("lnminipeer_connections 0\n"));
+        assert!(metrics.contains("lnminipeer_channels_known 0\n"));
+        assert!(metrics.contains("lnminipeer_nodes_known 0\n"));
+        for line in metrics.lines() {
+            assert!(is_valid_prometheus_line(line), "invalid line: {}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_received_types_per_peer() {
+        let (peer, result, _client) = connect_to_listener(true).await;
+        result.unwrap();
+        let metrics = peer.metrics_prometheus();
+        assert!(metrics.contains(&format!(
+            "lnminipeer_received_messages_total{{peer=\"{}\",type=\"init\"}} 1\n",
+            hex::encode(peer.connected_peers()[0])
+        )));
+        for line in metrics.lines() {
+            assert!(is_valid_prometheus_line(line), "invalid line: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_channel_ready_alias_is_recorded() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = hex::decode(CHANNEL_READY).unwrap();
+        let (msg, _) = ChannelReadyMessage::from_bytes(&bytes).unwrap();
+        let alias = msg.scid_alias().unwrap();
+        assert_eq!(alias.block_height, 0x71);
+        peer.record_channel_ready(&msg, [2; 33]);
+        assert_eq!(peer.scid_alias_peer(&alias), Some([2; 33]));
+    }
+
+    #[test]
+    fn test_splice_rbf_is_tracked() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let channel_id = ChannelIDElement { value: [7; 32] };
+        assert_eq!(peer.splice_state(&channel_id), None);
+
+        // tx_init_rbf: locktime 100, feerate 2500, funding_output_contribution 50_000
+        let mut bytes = hex::decode("0048").unwrap();
+        bytes.extend([7; 32]);
+        bytes.extend(100u32.to_be_bytes());
+        bytes.extend(2_500u32.to_be_bytes());
+        bytes.extend([0x00, 0x08]);
+        bytes.extend(50_000i64.to_be_bytes());
+        let (init_rbf, _) = TxInitRbfMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(init_rbf.funding_output_contribution(), Some(50_000));
+        peer.record_tx_init_rbf(&init_rbf);
+        let state = peer.splice_state(&channel_id).unwrap();
+        assert_eq!(state.rbf_feerates, vec![2_500]);
+        assert!(!state.rbf_acked);
+
+        let mut bytes = hex::decode("0049").unwrap();
+        bytes.extend([7; 32]);
+        let (ack_rbf, _) = TxAckRbfMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(ack_rbf.funding_output_contribution(), None);
+        peer.record_tx_ack_rbf(&ack_rbf);
+        assert_eq!(
+            peer.splice_state(&channel_id),
+            Some(SpliceState {
+                quiescent: false,
+                rbf_feerates: vec![2_500],
+                rbf_acked: true,
+            })
+        );
+        assert_eq!(
+            peer.splice_state(&ChannelIDElement { value: [8; 32] }),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_until_synced_returns_when_quiet() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        assert!(!peer.gossip_is_quiet(Duration::from_secs(60)));
+        peer.last_new_gossip = Instant::now() - Duration::from_secs(10);
+        assert!(peer.gossip_is_quiet(Duration::from_secs(5)));
+        assert!(!peer.gossip_is_quiet(Duration::from_secs(60)));
+        // already quiet, so this must return without polling forever
+        peer.run_until_synced(Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_event_loop_pings_and_drops_silent_peer() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        peer.config.ping_interval = Duration::ZERO;
+        peer.config.ping_timeout = Duration::from_millis(200);
+        // the first pass pings, a later one finds the ping unanswered
+        let _ = tokio::time::timeout(Duration::from_millis(1500), peer.event_loop()).await;
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Ping(_)
+        ));
+        assert_eq!(peer.num_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_synced_returns_with_silent_peer() {
+        let (mut peer, result, _client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert_eq!(peer.num_connections(), 1);
+        peer.last_new_gossip = Instant::now();
+        // the client never sends anything after its init
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            peer.run_until_synced(Duration::from_millis(300)),
+        )
+        .await
+        .expect("a silent peer kept run_until_synced from noticing the quiet");
+        assert_eq!(peer.num_connections(), 1);
+    }
+
+    async fn connect_to_listener(
+        allowed: bool,
+    ) -> (MiniPeer, Result<(), MessageHandlerError>, NodeConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_key = new_random_secret_key();
+        let client_key = new_random_secret_key();
+        let client_public_key = PublicKey::from_secret_key(&*SECP, &client_key).serialize();
+        let mut allowed_peers = HashSet::new();
+        if allowed {
+            allowed_peers.insert(client_public_key);
+        }
+        let config = PeerConfig {
+            allowed_peers: Some(allowed_peers),
+            ..PeerConfig::default()
+        };
+        let mut peer = MiniPeer::with_config(server_key, config);
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &server_key).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port,
+        };
+        let client = tokio::spawn(async move {
+            let mut conn = NodeConnection::new(&node, client_key).await.unwrap();
+            conn.handshake().await.unwrap();
+            // fails if we are not allowed in, the peer closes on us. Without
+            // gossip_queries, so the peer sends nothing in return.
+            let init = InitMessage::new(vec![], vec![0x02], &[]);
+            let _ = conn
+                .encrypt_and_send_message(&MessageContainer::Init(init))
+                .await;
+            conn
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let result = peer.accept_node_connection(stream).await;
+        if result.is_ok() {
+            // reads the client's init, the connection is then ready for anything else
+            peer.process_connections().await;
+        }
+        let conn = client.await.unwrap();
+        (peer, result, conn)
+    }
+
+    #[tokio::test]
+    async fn test_allowed_peers() {
+        let (peer, result, _) = connect_to_listener(false).await;
+        assert!(matches!(result, Err(MessageHandlerError::PeerNotAllowed)));
+        assert_eq!(peer.num_connections(), 0);
+
+        let (peer, result, _) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        assert_eq!(peer.num_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gossip_resync_after_interval() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.gossip_resync_interval = Some(Duration::from_secs(60));
+        let init = InitMessage::new(vec![], vec![0xaa], &[]);
+        assert!(peer.record_init(&init, client_key));
+        assert!(peer.peers_due_for_resync().is_empty());
+
+        peer.last_gossip_from
+            .insert(client_key, Instant::now() - Duration::from_secs(61));
+        assert_eq!(peer.peers_due_for_resync(), vec![client_key]);
+        peer.resync_gossip().await;
+        assert!(peer.peers_due_for_resync().is_empty());
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::QueryChannelRange(query) => {
+                assert_eq!(query.first_blocknum, 0);
+                assert_eq!(query.number_of_blocks, u32::MAX);
+            }
+            other => panic!("expected query_channel_range, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_peer_on_other_chain_is_not_queried() {
+        let mainnet: [u8; 32] =
+            hex::decode("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let testnet: [u8; 32] =
+            hex::decode("43497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea330900000000")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let config = PeerConfig {
+            chain_hash: mainnet,
+            ..PeerConfig::default()
+        };
+        let mut peer = MiniPeer::with_config(new_random_secret_key(), config);
+
+        let testnet_only = InitMessage::new(vec![], vec![0xaa], &[testnet]);
+        assert!(!peer.record_init(&testnet_only, [2; 33]));
+        assert!(peer.other_chain_peers.contains(&[2; 33]));
+
+        let both = InitMessage::new(vec![], vec![0xaa], &[testnet, mainnet]);
+        assert!(peer.record_init(&both, [3; 33]));
+        let unrestricted = InitMessage::new(vec![], vec![0xaa], &[]);
+        assert!(peer.record_init(&unrestricted, [4; 33]));
+        assert_eq!(peer.other_chain_peers.len(), 1);
+    }
+
+    #[test]
+    fn test_announcement_beyond_chain_tip_is_rejected() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let block_height = announcement.short_channel_id.block_height;
+
+        peer.set_chain_tip(block_height - 1);
+        peer.record_channel_announcement(announcement.clone(), [2; 33]);
+        assert_eq!(peer.graph.num_channels(), 0);
+        assert_eq!(peer.future_announcements, 1);
+        assert!(peer
+            .metrics_prometheus()
+            .contains("lnminipeer_future_channel_announcements_total 1\n"));
+
+        // funded in the tip block itself is fine
+        peer.set_chain_tip(block_height);
+        peer.record_channel_announcement(announcement, [2; 33]);
+        assert_eq!(peer.graph.num_channels(), 1);
+        assert_eq!(peer.future_announcements, 1);
+    }
+
+    #[test]
+    fn test_query_for_other_chain_gets_no_channels() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        peer.graph.add_channel_announcement(announcement);
+
+        let ours = QueryChannelRangeMessage::new(peer.config.chain_hash, 0, u32::MAX);
+        let replies = peer.channel_range_replies(&ours);
+        assert_eq!(replies[0].short_channel_ids().unwrap().len(), 1);
+
+        // e.g. a peer that left chain_hash zeroed
+        let other = QueryChannelRangeMessage::new([0; 32], 0, u32::MAX);
+        let replies = peer.channel_range_replies(&other);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].chain_hash.value, [0; 32]);
+        assert!(replies[0].short_channel_ids().unwrap().is_empty());
+        assert_eq!(replies[0].sync_complete, 0);
+    }
+
+    #[test]
+    fn test_channel_range_replies_respect_configured_cap() {
+        use crate::messages::{QUERY_OPTION_CHECKSUMS, QUERY_OPTION_TIMESTAMPS};
+
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        peer.config.max_short_channel_ids_per_reply = 2;
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        for block_height in 100..105 {
+            let mut announcement = announcement.clone();
+            announcement.short_channel_id.block_height = block_height;
+            peer.graph.add_channel_announcement(announcement);
+        }
+        let query = QueryChannelRangeMessage::new(peer.config.chain_hash, 0, u32::MAX)
+            .with_query_option(QUERY_OPTION_TIMESTAMPS | QUERY_OPTION_CHECKSUMS);
+        let replies = peer.channel_range_replies(&query);
+        assert_eq!(replies.len(), 3);
+        let mut sync = ChannelRangeSync::new(&query);
+        for (i, reply) in replies.iter().enumerate() {
+            let bytes = reply.to_bytes();
+            let (reply, _) = ReplyChannelRangeMessage::from_bytes(&bytes).unwrap();
+            assert!(reply.short_channel_ids().unwrap().len() <= 2);
+            assert_eq!(reply.sync_complete, (i == replies.len() - 1) as u8);
+            assert_eq!(sync.add_reply(&reply).unwrap(), i == replies.len() - 1);
+        }
+        assert_eq!(sync.short_channel_ids().len(), 5);
+
+        // the default is the most that fits in one message with both tlvs
+        let reply_len = |count: usize| {
+            let scids = vec![announcement.short_channel_id.clone(); count];
+            let pairs = Some(vec![[u32::MAX; 2]; count]);
+            ReplyChannelRangeMessage::new([0; 32], 0, 1, true, &scids, pairs.clone(), pairs)
+                .to_bytes()
+                .len()
+        };
+        let default = PeerConfig::default().max_short_channel_ids_per_reply;
+        assert!(reply_len(default) <= 65535);
+        assert!(reply_len(default + 1) > 65535);
+    }
+
+    #[test]
+    fn test_feature_downgrade_is_flagged() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let full = InitMessage::new(vec![], vec![0xaa], &[]);
+        assert!(!peer.record_features(&full, [2; 33]));
+        assert!(!peer.record_features(&full, [2; 33]));
+        // gossip_queries dropped
+        let reduced = InitMessage::new(vec![], vec![0x2a], &[]);
+        assert!(peer.record_features(&reduced, [2; 33]));
+        assert_eq!(peer.feature_downgrades, 1);
+        // adding features back is not a downgrade
+        assert!(!peer.record_features(&full, [2; 33]));
+        assert!(peer
+            .metrics_prometheus()
+            .contains("lnminipeer_feature_downgrades_total 1\n"));
+    }
+
+    #[test]
+    fn test_export_then_import_raw() {
+        let mut source = MiniPeer::new(new_random_secret_key());
+        let fixtures = [
+            MessageType::ChannelAnnouncement,
+            MessageType::ChannelUpdate,
+            MessageType::NodeAnnouncement,
+        ]
+        .iter()
+        .map(|message_type| example_message(*message_type))
+        .collect();
+        assert_eq!(source.import_raw(fixtures), 3);
+
+        let exported = source.export_raw();
+        assert_eq!(exported.len(), 3);
+        let mut target = MiniPeer::new(new_random_secret_key());
+        // truncated and non gossip messages are skipped
+        let mut with_junk = exported.clone();
+        with_junk.push(exported[0][..100].to_vec());
+        with_junk.push(hex::decode(CHANNEL_READY).unwrap());
+        assert_eq!(target.import_raw(with_junk), 3);
+        assert_eq!(target.graph.num_channels(), 1);
+        assert_eq!(target.graph.num_nodes(), 1);
+        assert_eq!(target.export_raw(), exported);
+    }
+
+    #[tokio::test]
+    async fn test_gossip_not_stored_when_disabled() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.store_gossip = false;
+        for message_type in [
+            MessageType::ChannelAnnouncement,
+            MessageType::ChannelUpdate,
+            MessageType::NodeAnnouncement,
+        ] {
+            let bytes = example_message(message_type);
+            let (wrapped, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            peer.handle_inbound_message(wrapped, client_key)
+                .await
+                .unwrap();
+        }
+        assert_eq!(peer.message_counts[&MessageType::ChannelAnnouncement], 1);
+        assert_eq!(peer.message_counts[&MessageType::ChannelUpdate], 1);
+        assert_eq!(peer.message_counts[&MessageType::NodeAnnouncement], 1);
+        assert_eq!(peer.graph.num_channels(), 0);
+        assert_eq!(peer.graph.num_nodes(), 0);
+
+        // and their gossip_timestamp_filter goes unanswered
+        let filter = GossipTimestampFilterMessage::new(peer.config.chain_hash, 0, u32::MAX);
+        peer.handle_inbound_message(MessageContainer::GossipTimestampFilter(filter), client_key)
+            .await
+            .unwrap();
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn test_peer_addresses_from_announcement() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        assert!(peer.peer_addresses(client_key).is_empty());
+
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        let mut announcement = NodeAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+        announcement.node_id = PointElement { value: client_key };
+        announcement
+            .addresses
+            .ipv4_addresses
+            .push([10, 0, 0, 1, 0x26, 0x07]);
+        peer.handle_inbound_message(MessageContainer::NodeAnnouncement(announcement), client_key)
+            .await
+            .unwrap();
+        let addresses = peer.peer_addresses(client_key);
+        assert_eq!(addresses, vec![Address::IPv4([10, 0, 0, 1, 0x26, 0x07])]);
+        assert_eq!(addresses[0].to_string(), "10.0.0.1:9735");
+    }
+
+    #[tokio::test]
+    async fn test_connection_established_negotiates_intersection() {
+        let (peer, result, _client) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        let client_key = peer.connected_peers()[0];
+        // we advertise 0xaa: data_loss_protect, upfront_shutdown_script, gossip_queries.
+        // they advertise data_loss_protect, gossip_queries and var_onion_optin.
+        let init = InitMessage::new(vec![], vec![0x02, 0x82], &[]);
+        let event = peer.connection_established(&init, client_key).unwrap();
+        assert_eq!(
+            event.negotiated_features,
+            HashSet::from([Features::DataLossProtect, Features::GossipQueries])
+        );
+        assert_eq!(event.direction, ConnectionDirection::Inbound);
+        assert!(event.handshake_duration.is_some());
+        assert!(event.address.is_some());
+        assert!(event
+            .to_string()
+            .ends_with("features=DataLossProtect,GossipQueries"));
+        assert!(peer.connection_established(&init, [2; 33]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_proactive_sync_queries_after_init() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        assert!(result.is_ok());
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        // without gossip_queries there is nothing to query
+        peer.config.proactive_sync = true;
+        let init = InitMessage::new(vec![], vec![0x02], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::GossipTimestampFilter(_)
+        ));
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::QueryChannelRange(query) => {
+                assert_eq!(query.first_blocknum, 0);
+                assert_eq!(query.number_of_blocks, u32::MAX);
+            }
+            other => panic!("expected query_channel_range, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_sync_stops_at_chain_tip() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.proactive_sync = true;
+        peer.config.gossip_timestamp_filter = None;
+        peer.set_chain_tip(1000);
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::QueryChannelRange(query) => {
+                assert_eq!(query.first_blocknum, 0);
+                assert_eq!(query.number_of_blocks, 1001);
+            }
+            other => panic!("expected query_channel_range, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proactive_sync_fetches_unknown_channels() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let known = announcement.short_channel_id.clone();
+        peer.graph.add_channel_announcement(announcement);
+        let unknown = ShortChannelIDElement {
+            block_height: known.block_height + 1,
+            tx_index: 0,
+            output_index: 0,
+        };
+
+        peer.config.proactive_sync = true;
+        peer.config.gossip_timestamp_filter = None;
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        let query = match client.read_next_message().await.unwrap() {
+            MessageContainer::QueryChannelRange(query) => query,
+            other => panic!("expected query_channel_range, got {:?}", other),
+        };
+
+        // two fragments, the second one completes the range
+        let chain_hash = query.chain_hash.value;
+        for (first_blocknum, number_of_blocks, short_channel_ids) in [
+            (0, known.block_height + 1, vec![known.clone()]),
+            (
+                known.block_height + 1,
+                u32::MAX - known.block_height - 1,
+                vec![unknown.clone()],
+            ),
+        ] {
+            let last = first_blocknum > 0;
+            let reply = ReplyChannelRangeMessage::new(
+                chain_hash,
+                first_blocknum,
+                number_of_blocks,
+                last,
+                &short_channel_ids,
+                None,
+                None,
+            );
+            client
+                .encrypt_and_send_message(&MessageContainer::ReplyChannelRange(reply))
+                .await
+                .unwrap();
+        }
+        peer.process_connections().await;
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+        peer.process_connections().await;
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::QueryShortChannelIds(query) => {
+                assert_eq!(query.short_channel_ids().unwrap(), vec![unknown]);
+            }
+            other => panic!("expected query_short_channel_ids, got {:?}", other),
+        }
+        assert!(peer.range_syncs.is_empty());
+        assert!(peer.scid_queries_in_flight.contains(&client_key));
+
+        let end = ReplyShortChannelIdsEndMessage {
+            chain_hash: query.chain_hash.clone(),
+            full_information: 1,
+        };
+        client
+            .encrypt_and_send_message(&MessageContainer::ReplyShortChannelIdsEnd(end))
+            .await
+            .unwrap();
+        peer.process_connections().await;
+        assert!(peer.scid_queries_in_flight.is_empty());
+        assert!(peer.queued_scids.is_empty());
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn test_gossip_timestamp_filter_sent_after_init() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.gossip_timestamp_filter = Some((1_700_000_000, 3600));
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        match client.read_next_message().await.unwrap() {
+            MessageContainer::GossipTimestampFilter(filter) => {
+                assert_eq!(filter.chain_hash.value, peer.config.chain_hash);
+                assert_eq!(filter.first_timestamp, 1_700_000_000);
+                assert_eq!(filter.timestamp_range, 3600);
+            }
+            other => panic!("expected gossip_timestamp_filter, got {:?}", other),
+        }
+
+        // their filter is theirs, we don't answer it with ours
+        let filter = GossipTimestampFilterMessage::new(peer.config.chain_hash, 0, u32::MAX);
+        peer.handle_inbound_message(MessageContainer::GossipTimestampFilter(filter), client_key)
+            .await
+            .unwrap();
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+
+        peer.config.gossip_timestamp_filter = None;
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn test_passive_mirror_sends_no_filter_or_query() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.passive_mirror = true;
+        peer.config.proactive_sync = true;
+        peer.config.gossip_resync_interval = Some(Duration::ZERO);
+        let init = InitMessage::new(vec![], vec![0x80], &[]);
+        peer.handle_inbound_message(MessageContainer::Init(init), client_key)
+            .await
+            .unwrap();
+        peer.resync_gossip().await;
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+
+        // gossip is still recorded and pings still answered
+        for message_type in [
+            MessageType::ChannelAnnouncement,
+            MessageType::ChannelUpdate,
+            MessageType::NodeAnnouncement,
+        ] {
+            let bytes = example_message(message_type);
+            let (wrapped, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            peer.handle_inbound_message(wrapped, client_key)
+                .await
+                .unwrap();
+        }
+        assert_eq!(peer.graph.num_channels(), 1);
+        assert_eq!(peer.graph.num_nodes(), 1);
+        let ping = PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        };
+        peer.handle_inbound_message(MessageContainer::Ping(ping), client_key)
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Pong(_)
+        ));
+        assert!(!client.has_message_within(Duration::from_millis(100)).await);
+    }
+
+    #[tokio::test]
+    async fn test_serve_mirrors_inbound_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_key = new_random_secret_key();
+        let config = PeerConfig {
+            passive_mirror: true,
+            proactive_sync: true,
+            ..PeerConfig::default()
+        };
+        let mut peer = MiniPeer::with_config(server_key, config);
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &server_key).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let client = tokio::spawn(async move {
+            let mut conn = NodeConnection::new(&node, new_random_secret_key())
+                .await
+                .unwrap();
+            conn.handshake().await.unwrap();
+            let init = InitMessage::new(vec![], vec![0x80], &[]);
+            conn.encrypt_and_send_message(&MessageContainer::Init(init))
+                .await
+                .unwrap();
+            assert!(matches!(
+                conn.read_next_message().await.unwrap(),
+                MessageContainer::Init(_)
+            ));
+            let bytes = example_message(MessageType::ChannelAnnouncement);
+            let (announcement, _) = MessageDecoder::from_bytes(&bytes).unwrap();
+            conn.encrypt_and_send_message(&announcement).await.unwrap();
+            // a gossip_queries peer, still we are sent no filter or query
+            assert!(!conn.has_message_within(Duration::from_millis(300)).await);
+        });
+
+        let _ = tokio::time::timeout(Duration::from_millis(2000), peer.serve(listener)).await;
+        client.await.unwrap();
+        assert_eq!(peer.num_connections(), 1);
+        assert_eq!(peer.graph.num_channels(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_drops_peer_without_panicking() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        let ping = PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        };
+        client
+            .encrypt_and_send_message(&MessageContainer::Ping(ping))
+            .await
+            .unwrap();
+        // our side is closing, so the pong can't be sent
+        let node_conn = peer.node_connections.get_mut(&client_key).unwrap();
+        node_conn.close(None).await.unwrap();
+
+        peer.process_connections().await;
+        assert_eq!(peer.num_connections(), 0);
+        assert_eq!(peer.message_counts[&MessageType::Ping], 1);
+    }
+
+    #[tokio::test]
+    async fn test_sync_channel_range_times_out() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.config.channel_range_reply_timeout = Duration::from_millis(200);
+        // the client reads the query and never replies
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            peer.sync_channel_range(client_key, 0, u32::MAX),
+        )
+        .await
+        .expect("sync_channel_range waited past its timeout");
+        assert!(matches!(result, Err(MessageHandlerError::Timeout)));
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::QueryChannelRange(_)
+        ));
+        assert_eq!(peer.num_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connecting_to_ourselves_is_rejected() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let node = Node {
+            public_key: peer.node_id(),
+            ip_address: "127.0.0.1".to_string(),
+            port: 9735,
+        };
+        assert!(matches!(
+            peer.open_node_connection(&node).await,
+            Err(MessageHandlerError::SelfConnection)
+        ));
+        assert_eq!(peer.num_connections(), 0);
+    }
+
+    // a listener that completes the handshake, sends first_message if given and then
+    // goes quiet
+    async fn spawn_responder(
+        first_message: Option<MessageContainer>,
+    ) -> (Node, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_key = new_random_secret_key();
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &server_key).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = NodeConnection::accept(stream, server_key).await.unwrap();
+            if let Some(message) = first_message {
+                conn.encrypt_and_send_message(&message).await.unwrap();
+            }
+            // hold the connection open
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        (node, server)
+    }
+
+    #[tokio::test]
+    async fn test_connect_budget_covers_init_exchange() {
+        let config = PeerConfig {
+            connect_budget: Duration::from_millis(200),
+            ..PeerConfig::default()
+        };
+        let mut peer = MiniPeer::with_config(new_random_secret_key(), config);
+        let (node, server) = spawn_responder(None).await;
+        assert!(matches!(
+            peer.open_node_connection(&node).await,
+            Err(MessageHandlerError::Timeout)
+        ));
+        assert_eq!(peer.num_connections(), 0);
+        assert!(peer
+            .metrics_prometheus()
+            .contains("lnminipeer_connect_timeouts_total 1\n"));
+        server.abort();
+
+        let init = InitMessage::from_config(&PeerConfig::default());
+        let (node, server) = spawn_responder(Some(MessageContainer::Init(init))).await;
+        peer.open_node_connection(&node).await.unwrap();
+        assert_eq!(peer.num_connections(), 1);
+        assert_eq!(peer.message_counts.get(&MessageType::Init), Some(&1));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_first_message_must_be_init() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let error = ErrorMessage::for_connection("go away");
+        let (node, server) = spawn_responder(Some(MessageContainer::Error(error))).await;
+        assert!(matches!(
+            peer.open_node_connection(&node).await,
+            Err(MessageHandlerError::InitExpected(MessageType::Error))
+        ));
+        assert_eq!(peer.num_connections(), 0);
+        assert_eq!(peer.message_counts.get(&MessageType::Error), None);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_connection_attempt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &new_random_secret_key()).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        // accepts the stream but never answers act one
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(stream);
+        });
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            peer.open_node_connection_cancellable(&node, cancel),
+        )
+        .await
+        .expect("cancellation did not stop the attempt");
+        assert!(matches!(result, Err(MessageHandlerError::Cancelled)));
+        assert_eq!(peer.num_connections(), 0);
+        assert_eq!(peer.connect_timeouts, 0);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_registered_handler_runs_after_builtin() {
+        let (mut peer, result, _client) = connect_to_listener(true).await;
+        result.unwrap();
+        let node_id = peer.connected_peers()[0];
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        peer.on(MessageType::Pong, move |message, node_public_key| {
+            let tx = tx.clone();
+            async move {
+                tx.send((message.message_type(), node_public_key)).unwrap();
+            }
+        });
+        // the size send_ping asks for
+        let ping = PingMessage {
+            num_pong_bytes: 100,
+            ignored: IgnoredBytesElement::new(vec![]),
+        };
+        peer.ping(node_id).await.unwrap();
+        let pong = MessageContainer::Pong(PongMessage::from_ping(ping.clone()));
+        peer.handle_inbound_message(pong, node_id).await.unwrap();
+        assert_eq!(rx.try_recv().unwrap(), (MessageType::Pong, node_id));
+        assert_eq!(peer.message_counts.get(&MessageType::Pong), Some(&1));
+
+        // nothing registered for ping, the built-in reply still goes out
+        peer.handle_inbound_message(MessageContainer::Ping(ping), node_id)
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_pong_disconnects() {
+        let (mut peer, result, _client) = connect_to_listener(true).await;
+        result.unwrap();
+        let node_id = peer.connected_peers()[0];
+        let pong_for = |num_pong_bytes| {
+            MessageContainer::Pong(PongMessage::from_ping(PingMessage {
+                num_pong_bytes,
+                ignored: IgnoredBytesElement::new(vec![]),
+            }))
+        };
+        peer.ping(node_id).await.unwrap();
+        peer.handle_inbound_message(pong_for(100), node_id)
+            .await
+            .unwrap();
+        assert_eq!(peer.num_connections(), 1);
+
+        // the ping was already answered
+        peer.handle_inbound_message(pong_for(100), node_id)
+            .await
+            .unwrap();
+        assert_eq!(peer.num_connections(), 0);
+
+        let (mut peer, result, _client) = connect_to_listener(true).await;
+        result.unwrap();
+        let node_id = peer.connected_peers()[0];
+        peer.ping(node_id).await.unwrap();
+        // not the size we asked for
+        peer.handle_inbound_message(pong_for(4), node_id)
+            .await
+            .unwrap();
+        assert_eq!(peer.num_connections(), 0);
+    }
+
+    #[test]
+    fn test_log_message_types_filter() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let ping = MessageContainer::Ping(PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        });
+        let bytes = example_message(MessageType::ChannelUpdate);
+        let update = MessageDecoder::from_bytes(&bytes).unwrap().0;
+        peer.config.log_message_types = None;
+        assert!(peer.message_log_line(&ping).is_some());
+
+        peer.config.log_message_types = Some(HashSet::from([MessageType::ChannelUpdate]));
+        assert!(peer.message_log_line(&ping).is_none());
+        assert!(peer
+            .message_log_line(&update)
+            .unwrap()
+            .starts_with("Received message: ChannelUpdate"));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_prefers_well_connected_nodes() {
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let first = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+        let busy = first.node_id_1.value;
+        let quiet = first.node_id_2.value;
+        let mut second = first.clone();
+        second.short_channel_id.block_height += 1;
+        second.node_id_2 = PointElement { value: [3; 33] };
+
+        let mut store = MemoryGossipStore::new();
+        store.put_channel(ChannelEdge::new(first));
+        store.put_channel(ChannelEdge::new(second));
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        for (node_id, last_octet) in [(quiet, 2), (busy, 1)] {
+            let mut announcement = NodeAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+            announcement.node_id = PointElement { value: node_id };
+            announcement
+                .addresses
+                .ipv4_addresses
+                .push([10, 0, 0, last_octet, 0x26, 0x07]);
+            store.put_node(announcement);
+        }
+
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        // no connections attempted, just loaded
+        assert_eq!(peer.bootstrap_from_store(&store, 0).await, 0);
+        assert_eq!(peer.graph.num_channels(), 2);
+
+        let candidates: Vec<[u8; 33]> = peer
+            .bootstrap_candidates(2)
+            .iter()
+            .map(|node| node.public_key)
+            .collect();
+        assert_eq!(candidates, vec![busy, quiet]);
+        let best = peer.bootstrap_candidates(1);
+        assert_eq!(best.len(), 1);
+        assert_eq!(best[0].address(), "10.0.0.1:9735");
+    }
+
+    #[test]
+    fn test_channel_enable_history() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let scid = announcement.short_channel_id.clone();
+        peer.graph.add_channel_announcement(announcement);
+
+        let bytes = example_message(MessageType::ChannelUpdate);
+        let (enabled, _) = ChannelUpdateMessage::from_bytes(&bytes).unwrap();
+        let mut disabled = enabled.clone();
+        disabled.timestamp += 10;
+        disabled.channel_flags |= 2;
+        let mut still_disabled = disabled.clone();
+        still_disabled.timestamp += 10;
+        let direction = enabled.direction();
+        let first_timestamp = enabled.timestamp;
+
+        peer.record_channel_update(enabled);
+        peer.record_channel_update(disabled);
+        peer.record_channel_update(still_disabled);
+        assert_eq!(
+            peer.channel_history(&scid, direction),
+            &[(first_timestamp, true), (first_timestamp + 10, false)]
+        );
+        assert!(peer.channel_history(&scid, 1 - direction).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ping_is_answered_before_gossip_backlog() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let node_id = peer.connected_peers()[0];
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let mut inbounds = Vec::new();
+        for tx_index in 0..50 {
+            let mut announcement = announcement.clone();
+            announcement.short_channel_id.tx_index = tx_index;
+            inbounds.push((MessageContainer::ChannelAnnouncement(announcement), node_id));
+        }
+        let ping = PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        };
+        inbounds.push((MessageContainer::Ping(ping), node_id));
+
+        pings_first(&mut inbounds);
+        assert!(matches!(inbounds[0].0, MessageContainer::Ping(_)));
+        // the announcements keep their order
+        let tx_indexes: Vec<u32> = inbounds[1..]
+            .iter()
+            .filter_map(|(message, _)| match message {
+                MessageContainer::ChannelAnnouncement(msg) => Some(msg.short_channel_id.tx_index),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tx_indexes, (0..50).collect::<Vec<u32>>());
+
+        let (message, _) = inbounds.remove(0);
+        peer.handle_inbound_message(message, node_id).await.unwrap();
+        assert!(client.has_message_within(Duration::from_millis(100)).await);
+        assert_eq!(peer.graph.num_channels(), 0);
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Pong(_)
+        ));
+    }
+
+    #[test]
+    fn test_update_before_announcement_is_buffered() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let scid = announcement.short_channel_id.clone();
+        let bytes = example_message(MessageType::ChannelUpdate);
+        let (update, _) = ChannelUpdateMessage::from_bytes(&bytes).unwrap();
+        let direction = update.direction();
+        let mut older = update.clone();
+        older.timestamp -= 1;
+
+        peer.record_channel_update(update.clone());
+        // an older one doesn't replace what we hold
+        peer.record_channel_update(older);
+        assert!(peer.graph.get_channel(&scid).is_none());
+        assert_eq!(peer.orphan_updates.len(), 1);
+
+        peer.record_channel_announcement(announcement.clone(), [2; 33]);
+        let edge = peer.graph.get_channel(&scid).unwrap();
+        assert_eq!(
+            edge.updates[direction].as_ref().unwrap().timestamp,
+            update.timestamp
+        );
+        assert!(peer.orphan_updates.is_empty());
+        assert_eq!(peer.channel_history(&scid, direction).len(), 1);
+
+        // held past the window, the announcement comes too late for it
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        peer.config.orphan_update_window = Duration::ZERO;
+        peer.record_channel_update(update);
+        peer.record_channel_announcement(announcement, [2; 33]);
+        assert!(peer.graph.get_channel(&scid).unwrap().updates[direction].is_none());
+    }
+
+    #[test]
+    fn test_orphan_updates_are_capped() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelUpdate);
+        let (update, _) = ChannelUpdateMessage::from_bytes(&bytes).unwrap();
+        for tx_index in 0..=MAX_ORPHAN_UPDATES as u32 {
+            let mut update = update.clone();
+            update.short_channel_id.tx_index = tx_index;
+            peer.record_channel_update(update);
+            if tx_index == 0 {
+                // so the first one is strictly the oldest
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(peer.orphan_updates.len(), MAX_ORPHAN_UPDATES);
+        // the first one held is the one dropped
+        let mut first = update.short_channel_id.clone();
+        first.tx_index = 0;
+        assert!(!peer
+            .orphan_updates
+            .contains_key(&(first, update.direction())));
+    }
+
+    #[tokio::test]
+    async fn test_json_stream_line_per_message() {
+        let path = std::env::temp_dir().join(format!(
+            "lmp-json-stream-{}.ndjson",
+            hex::encode(&new_random_secret_key().secret_bytes()[..8])
+        ));
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        peer.json_writer = open_json_stream(&JsonStream::File(path.clone()));
+        let ping = PingMessage {
+            num_pong_bytes: 4,
+            ignored: IgnoredBytesElement::new(vec![]),
+        };
+        let bytes = ping.to_bytes();
+        peer.handle_inbound_message(MessageContainer::Ping(ping), client_key)
+            .await
+            .unwrap();
+        peer.flush_json_stream();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["message_type"], "ping");
+        assert_eq!(record["node_id"], hex::encode(client_key));
+        assert_eq!(record["message"], hex::encode(bytes));
+        assert!(record["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_channel_sources() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        let bytes = example_message(MessageType::ChannelAnnouncement);
+        let (announcement, _) = ChannelAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let scid = announcement.short_channel_id.clone();
+        assert!(peer.channel_sources(&scid).is_none());
+
+        peer.record_channel_announcement(announcement.clone(), [2; 33]);
+        peer.record_channel_announcement(announcement.clone(), [3; 33]);
+        peer.record_channel_announcement(announcement.clone(), [2; 33]);
+        assert_eq!(
+            peer.channel_sources(&scid),
+            Some(&HashSet::from([[2; 33], [3; 33]]))
+        );
+
+        // a rejected announcement doesn't make its sender a source
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        peer.set_chain_tip(scid.block_height - 1);
+        peer.record_channel_announcement(announcement, [2; 33]);
+        assert!(peer.channel_sources(&scid).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stale_node_announcement_is_dropped() {
+        let (mut peer, result, mut client) = connect_to_listener(true).await;
+        result.unwrap();
+        assert!(matches!(
+            client.read_next_message().await.unwrap(),
+            MessageContainer::Init(_)
+        ));
+        let client_key = peer.connected_peers()[0];
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        let (announcement, _) = NodeAnnouncementMessage::from_bytes(&bytes).unwrap();
+        let mut older = announcement.clone();
+        older.timestamp -= 1;
+        let mut newer = announcement.clone();
+        newer.timestamp += 1;
+
+        for message in [announcement.clone(), announcement.clone(), older] {
+            peer.handle_inbound_message(MessageContainer::NodeAnnouncement(message), client_key)
+                .await
+                .unwrap();
+        }
+        assert_eq!(peer.stale_node_announcements, 2);
+        assert_eq!(peer.message_counts[&MessageType::NodeAnnouncement], 3);
+        let stored = peer.graph.get_node(&announcement.node_id).unwrap();
+        assert_eq!(stored.timestamp, announcement.timestamp);
+
+        peer.handle_inbound_message(MessageContainer::NodeAnnouncement(newer), client_key)
+            .await
+            .unwrap();
+        assert_eq!(peer.stale_node_announcements, 2);
+        let stored = peer.graph.get_node(&announcement.node_id).unwrap();
+        assert_eq!(stored.timestamp, announcement.timestamp + 1);
+        assert_eq!(peer.graph.num_nodes(), 1);
+        assert!(peer
+            .metrics_prometheus()
+            .contains("lnminipeer_stale_node_announcements_total 2\n"));
+    }
+
+    #[test]
+    fn test_feature_histogram() {
+        let mut peer = MiniPeer::new(new_random_secret_key());
+        assert!(peer.feature_histogram().is_empty());
+        let bytes = example_message(MessageType::NodeAnnouncement);
+        // optional anchors (bit 23) on two nodes, compulsory on one
+        for (node_id, features) in [
+            ([2; 33], vec![0x80, 0x00, 0x00]),
+            ([3; 33], vec![0x80, 0x00, 0x02]),
+            ([4; 33], vec![0x40, 0x00, 0x02]),
+            ([5; 33], vec![]),
+        ] {
+            let mut announcement = NodeAnnouncementMessage::from_bytes(&bytes).unwrap().0;
+            announcement.node_id = PointElement { value: node_id };
+            announcement.features = FeaturesElement::new(features);
+            assert!(peer.graph.add_node_announcement(announcement));
+        }
+        assert_eq!(
+            peer.feature_histogram(),
+            HashMap::from([(23, 2), (22, 1), (1, 2)])
+        );
+    }
+
+    #[test]
+    fn test_metric_label_values() {
+        assert_eq!(sanitize_label_value("a\"b\\c\n"), "a\\\"b\\\\c\\n");
     }
 }
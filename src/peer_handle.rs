@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::{
+    channel_graph::{ChannelEdge, NetworkSummary},
+    messages::NodeAnnouncementMessage,
+    node::Node,
+    peer::{MessageHandlerError, MiniPeer, READ_WAIT},
+};
+
+const LOOP_INTERVAL: Duration = Duration::from_millis(500);
+
+// Shares one MiniPeer between the event loop and any number of control tasks. Every
+// method takes the lock only for the duration of the call, connect only around the dial.
+#[derive(Clone)]
+pub struct MiniPeerHandle {
+    peer: Arc<Mutex<MiniPeer>>,
+}
+
+impl MiniPeerHandle {
+    pub fn new(peer: MiniPeer) -> Self {
+        MiniPeerHandle {
+            peer: Arc::new(Mutex::new(peer)),
+        }
+    }
+
+    pub fn spawn_event_loop(&self) -> JoinHandle<()> {
+        let peer = self.peer.clone();
+        tokio::spawn(async move {
+            loop {
+                peer.lock().await.process_ready_connections(READ_WAIT).await;
+                tokio::time::sleep(LOOP_INTERVAL).await;
+            }
+        })
+    }
+
+    pub async fn connect(&self, node: &Node) -> Result<(), MessageHandlerError> {
+        let (secret_key, config) = {
+            let peer = self.peer.lock().await;
+            peer.check_dial_target(node)?;
+            peer.dial_settings()
+        };
+        // a dial can take the whole connect_budget, the event loop keeps running meanwhile
+        let attempt = MiniPeer::dial(node, secret_key, &config).await;
+        self.peer
+            .lock()
+            .await
+            .add_dialed_connection(node, attempt)
+            .await
+    }
+
+    pub async fn disconnect(&self, node_public_key: [u8; 33]) -> bool {
+        self.peer.lock().await.disconnect(node_public_key).await
+    }
+
+    pub async fn peers(&self) -> Vec<[u8; 33]> {
+        self.peer.lock().await.connected_peers()
+    }
+
+    pub async fn ping(&self, node_public_key: [u8; 33]) -> Result<(), MessageHandlerError> {
+        self.peer.lock().await.ping(node_public_key).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn stats(&self) -> NetworkSummary {
+        self.peer.lock().await.network_summary()
+    }
+
+    pub async fn channels(&self) -> Vec<ChannelEdge> {
+        self.peer.lock().await.graph().channels()
+    }
+
+    pub async fn nodes(&self) -> Vec<NodeAnnouncementMessage> {
+        self.peer.lock().await.graph().nodes()
+    }
+
+    pub async fn dump_gossip(&self, path: &str) -> std::io::Result<usize> {
+        self.peer.lock().await.dump_gossip(path)
+    }
+
+    #[allow(dead_code)]
+    pub async fn metrics_prometheus(&self) -> String {
+        self.peer.lock().await.metrics_prometheus()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PeerConfig;
+    use crate::message_decoder::MessageContainer;
+    use crate::messages::InitMessage;
+    use crate::node_connection::NodeConnection;
+    use crate::util::{new_random_secret_key, SECP};
+    use bitcoin::secp256k1::PublicKey;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_command_while_event_loop_runs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_key = new_random_secret_key();
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &remote_key).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        // the remote completes the handshake, sends its init and then never sends anything
+        let remote = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = NodeConnection::accept(stream, remote_key).await.unwrap();
+            let init = InitMessage::new(vec![], vec![0x02], &[]);
+            conn.encrypt_and_send_message(&MessageContainer::Init(init))
+                .await
+                .unwrap();
+            conn
+        });
+
+        let handle = MiniPeerHandle::new(MiniPeer::new(new_random_secret_key()));
+        let event_loop = handle.spawn_event_loop();
+        handle.connect(&node).await.unwrap();
+        let _remote = remote.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let peers = tokio::time::timeout(Duration::from_secs(2), handle.peers())
+            .await
+            .expect("command blocked behind the event loop");
+        assert_eq!(peers, vec![node.public_key]);
+        assert!(handle.disconnect(node.public_key).await);
+        assert!(handle.peers().await.is_empty());
+        assert_eq!(handle.stats().await.total_channels, 0);
+        event_loop.abort();
+    }
+
+    #[tokio::test]
+    async fn test_commands_answer_while_connect_stalls() {
+        // accepts the tcp connection but never answers act one
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = Node {
+            public_key: PublicKey::from_secret_key(&*SECP, &new_random_secret_key()).serialize(),
+            ip_address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let config = PeerConfig {
+            connect_budget: Duration::from_secs(1),
+            ..PeerConfig::default()
+        };
+        let handle = MiniPeerHandle::new(MiniPeer::with_config(new_random_secret_key(), config));
+        let event_loop = handle.spawn_event_loop();
+        let connecting = handle.clone();
+        let connect = tokio::spawn(async move { connecting.connect(&node).await });
+        let _stream = listener.accept().await.unwrap();
+
+        let peers = tokio::time::timeout(Duration::from_millis(200), handle.peers())
+            .await
+            .expect("command blocked behind the connect");
+        assert!(peers.is_empty());
+        assert!(matches!(
+            connect.await.unwrap(),
+            Err(MessageHandlerError::Timeout)
+        ));
+        event_loop.abort();
+    }
+}
@@ -0,0 +1,173 @@
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::node::Node;
+use crate::peer::MiniPeer;
+use crate::peer_handle::MiniPeerHandle;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Peers,
+    Connect(String),
+    Disconnect([u8; 33]),
+    Channels,
+    Nodes,
+    Ping([u8; 33]),
+    Dump(String),
+    Quit,
+}
+
+fn parse_public_key(arg: Option<&str>) -> Result<[u8; 33], String> {
+    let arg = arg.ok_or("missing <pubkey> argument")?;
+    let bytes = hex::decode(arg).map_err(|_| format!("invalid hex public key: {}", arg))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("public key must be 33 bytes: {}", arg))
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut parts = line.split_whitespace();
+        let command = match parts.next() {
+            Some(command) => command,
+            None => return Err("empty command".to_string()),
+        };
+        let arg = parts.next();
+        let parsed = match command {
+            "help" => Command::Help,
+            "peers" => Command::Peers,
+            "connect" => Command::Connect(arg.ok_or("missing <node> argument")?.to_string()),
+            "disconnect" => Command::Disconnect(parse_public_key(arg)?),
+            "channels" => Command::Channels,
+            "nodes" => Command::Nodes,
+            "ping" => Command::Ping(parse_public_key(arg)?),
+            "dump" => Command::Dump(arg.ok_or("missing <file> argument")?.to_string()),
+            "quit" | "exit" => Command::Quit,
+            _ => return Err(format!("unknown command: {}", command)),
+        };
+        if parts.next().is_some() {
+            return Err(format!("too many arguments for {}", command));
+        }
+        Ok(parsed)
+    }
+}
+
+const HELP: &str = "commands:
+  peers               list connected peers
+  connect <node>      connect to pubkey@host:port
+  disconnect <pubkey> drop the connection to a peer
+  channels            list known channels
+  nodes               list known nodes
+  ping <pubkey>       send a ping to a peer
+  dump <file>         write stored gossip to a file
+  quit                exit";
+
+async fn execute(peer: &MiniPeerHandle, command: Command) {
+    match command {
+        Command::Help => println!("{}", HELP),
+        Command::Peers => {
+            for public_key in peer.peers().await {
+                println!("{}", hex::encode(public_key));
+            }
+        }
+        Command::Connect(node_str) => {
+            let node = match Node::from_str(&node_str) {
+                Some(node) => node,
+                None => {
+                    println!("Error parsing node address: {}", node_str);
+                    return;
+                }
+            };
+            match peer.connect(&node).await {
+                Ok(()) => println!("Connected to node {}", node.display_str()),
+                Err(e) => println!("Error connecting to node {}: {:?}", node.display_str(), e),
+            }
+        }
+        Command::Disconnect(public_key) => {
+            if !peer.disconnect(public_key).await {
+                println!("Not connected to {}", hex::encode(public_key));
+            }
+        }
+        Command::Channels => {
+            for edge in peer.channels().await {
+                let announcement = &edge.announcement;
+                println!(
+                    "{} {} {}",
+                    announcement.short_channel_id,
+                    hex::encode(announcement.node_id_1.value),
+                    hex::encode(announcement.node_id_2.value)
+                );
+            }
+        }
+        Command::Nodes => {
+            for node in peer.nodes().await {
+                println!(
+                    "{} {}",
+                    hex::encode(node.node_id.value),
+                    node.alias.as_string()
+                );
+            }
+        }
+        Command::Ping(public_key) => {
+            if let Err(e) = peer.ping(public_key).await {
+                println!("Error pinging {}: {:?}", hex::encode(public_key), e);
+            }
+        }
+        Command::Dump(path) => match peer.dump_gossip(&path).await {
+            Ok(count) => println!("Wrote {} messages to {}", count, path),
+            Err(e) => println!("Error writing {}: {}", path, e),
+        },
+        Command::Quit => {}
+    }
+}
+
+// reads commands from stdin while the event loop keeps running in the background
+pub async fn run(peer: MiniPeer) {
+    let peer = MiniPeerHandle::new(peer);
+    let event_loop = peer.spawn_event_loop();
+
+    println!("{}", HELP);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Command::parse(&line) {
+            Ok(Command::Quit) => break,
+            Ok(command) => execute(&peer, command).await,
+            Err(e) => println!("{}", e),
+        }
+    }
+    event_loop.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY: &str = "02c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14";
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("peers"), Ok(Command::Peers));
+        assert_eq!(Command::parse("  channels  "), Ok(Command::Channels));
+        assert_eq!(
+            Command::parse("dump gossip.txt"),
+            Ok(Command::Dump("gossip.txt".to_string()))
+        );
+        let expected: [u8; 33] = hex::decode(PUBKEY).unwrap().try_into().unwrap();
+        assert_eq!(
+            Command::parse(&format!("ping {}", PUBKEY)),
+            Ok(Command::Ping(expected))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+        assert!(Command::parse("connect").is_err());
+        assert!(Command::parse("ping 02abcd").is_err());
+        assert!(Command::parse("peers extra").is_err());
+    }
+}
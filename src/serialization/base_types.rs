@@ -1,6 +1,11 @@
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
+use bitcoin::secp256k1::{self, ecdsa::Signature};
+
+use crate::config::MAX_ADDRESSES_PER_TYPE;
 use crate::messages::MessageType;
 use crate::serialization::{SerializableToBytes, SerializationError};
 
@@ -50,6 +55,9 @@ impl SerializableToBytes for WireU16SizedBytes {
             return Err(SerializationError::TooFewBytes);
         }
         let num_bytes = u16::from_be_bytes([data[0], data[1]]);
+        if data.len() < 2 + num_bytes as usize {
+            return Err(SerializationError::TooFewBytes);
+        }
         let our_data = data[2..2 + num_bytes as usize].to_vec();
         Ok((
             WireU16SizedBytes {
@@ -129,6 +137,12 @@ pub struct FeaturesElement {
 }
 
 impl FeaturesElement {
+    pub fn new(data: Vec<u8>) -> Self {
+        FeaturesElement {
+            value: WireU16SizedBytes::new(data),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.value.value.is_empty()
@@ -145,12 +159,41 @@ impl FeaturesElement {
         }
     }
 
+    // bitwise OR of both vectors, the shorter one padded with zero bytes in front since
+    // bit 0 is in the last byte
+    pub fn merged(&self, other: &FeaturesElement) -> FeaturesElement {
+        let (a, b) = (&self.value.value, &other.value.value);
+        let len = a.len().max(b.len());
+        let byte_at = |bytes: &Vec<u8>, i: usize| match (i + bytes.len()).checked_sub(len) {
+            Some(j) => bytes[j],
+            None => 0,
+        };
+        FeaturesElement::new((0..len).map(|i| byte_at(a, i) | byte_at(b, i)).collect())
+    }
+
     fn is_bit_set(&self, bit: usize) -> bool {
-        if (bit / 8) + 1 > self.value.value.len() {
+        let bytes = &self.value.value;
+        if (bit / 8) + 1 > bytes.len() {
             return false;
         }
-        // false
-        self.value.value[bit / 8] & (1 << (bit % 8)) != 0
+        // feature vectors are big-endian, bit 0 lives in the last byte
+        bytes[bytes.len() - 1 - bit / 8] & (1 << (bit % 8)) != 0
+    }
+
+    // every set bit, lowest first, including bits no known feature uses
+    pub fn set_bits(&self) -> Vec<usize> {
+        (0..self.value.value.len() * 8)
+            .filter(|bit| self.is_bit_set(*bit))
+            .collect()
+    }
+
+    // set features in bit order, for stable output
+    pub fn set_features(&self) -> Vec<(Features, FeatureFlag)> {
+        FEATURE_BITS
+            .iter()
+            .map(|(_, feature)| (feature.clone(), self.feature_status(feature)))
+            .filter(|(_, status)| !matches!(status, FeatureFlag::Unset))
+            .collect()
     }
 
     pub fn features_list(&self) -> HashMap<Features, FeatureFlag> {
@@ -222,50 +265,189 @@ impl SerializableToBytes for IgnoredBytesElement {
 #[derive(Debug, Clone)]
 pub struct NodeAddressesElement {
     pub ipv4_addresses: Vec<[u8; 6]>,
-    pub ipv6_addresses: Vec<[u8; 16]>,
+    pub ipv6_addresses: Vec<[u8; 18]>,
     pub torv2_addresses: Vec<[u8; 12]>,
     pub torv3_addresses: Vec<[u8; 37]>,
-    pub dns_hostname: Vec<u8>,
+    pub dns_hostnames: Vec<DnsHostname>,
+    // everything from the first unknown address type on, we can't know its length so
+    // it is only kept to re-serialize the announcement unchanged
+    pub unknown: Vec<u8>,
+}
+
+// address bytes include the trailing 2-byte port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    IPv4([u8; 6]),
+    IPv6([u8; 18]),
+    TorV2([u8; 12]),
+    TorV3([u8; 37]),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::IPv4(a) => {
+                let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+                write!(f, "{}:{}", ip, u16::from_be_bytes([a[4], a[5]]))
+            }
+            Address::IPv6(a) => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&a[..16]).unwrap());
+                write!(f, "[{}]:{}", ip, u16::from_be_bytes([a[16], a[17]]))
+            }
+            Address::TorV2(a) => {
+                write!(
+                    f,
+                    "{}.onion:{}",
+                    base32(&a[..10]),
+                    u16::from_be_bytes([a[10], a[11]])
+                )
+            }
+            Address::TorV3(a) => {
+                write!(
+                    f,
+                    "{}.onion:{}",
+                    base32(&a[..35]),
+                    u16::from_be_bytes([a[35], a[36]])
+                )
+            }
+        }
+    }
+}
+
+// BOLT 7 address type 5. The hostname should be ascii, it is kept as bytes so the
+// announcement re-serializes unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsHostname {
+    pub hostname: Vec<u8>,
+    pub port: u16,
+}
+
+impl fmt::Display for DnsHostname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            String::from_utf8_lossy(&self.hostname),
+            self.port
+        )
+    }
+}
+
+// rfc4648 lowercase base32 without padding, as used for onion hostnames
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+impl NodeAddressesElement {
+    // clearnet first (ipv4, ipv6), then onion, keeping announcement order within each type
+    pub fn connectable_addresses(&self) -> Vec<Address> {
+        let mut addresses = Vec::new();
+        let candidates = self
+            .ipv4_addresses
+            .iter()
+            .map(|a| Address::IPv4(*a))
+            .chain(self.ipv6_addresses.iter().map(|a| match a[..12] {
+                // ipv4-mapped ipv6 (::ffff:a.b.c.d) is the same endpoint as the ipv4 address
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] => {
+                    Address::IPv4(a[12..].try_into().unwrap())
+                }
+                _ => Address::IPv6(*a),
+            }))
+            .chain(self.torv2_addresses.iter().map(|a| Address::TorV2(*a)))
+            .chain(self.torv3_addresses.iter().map(|a| Address::TorV3(*a)));
+        for address in candidates {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses.sort_by_key(|address| match address {
+            Address::IPv4(_) => 0,
+            Address::IPv6(_) => 1,
+            Address::TorV2(_) => 2,
+            Address::TorV3(_) => 3,
+        });
+        addresses
+    }
 }
 
 impl SerializableToBytes for NodeAddressesElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        let (wrapper_struct, rest) = WireU16SizedBytes::from_bytes(data).unwrap();
+        let (wrapper_struct, rest) = WireU16SizedBytes::from_bytes(data)?;
         let mut ipv4_addresses = Vec::new();
         let mut ipv6_addresses = Vec::new();
         let mut torv2_addresses = Vec::new();
         let mut torv3_addresses = Vec::new();
-        let mut dns_hostname = Vec::new();
+        let mut dns_hostnames = Vec::new();
+        let mut unknown = Vec::new();
         let mut buf = wrapper_struct.value.clone();
         loop {
             if buf.is_empty() {
                 break;
             }
             let single_byte = buf[0];
+            // BOLT 7: ignore the first unknown type and everything after it
+            if !(1..=5).contains(&single_byte) {
+                unknown = buf;
+                break;
+            }
             buf = buf[1..].to_vec();
             let chomp_bytes = match single_byte {
-                1 => {
-                    ipv4_addresses.push(buf[..6].try_into().unwrap());
-                    6
-                }
-                2 => {
-                    ipv6_addresses.push(buf[..18].try_into().unwrap());
-                    18
-                }
-                3 => {
-                    torv2_addresses.push(buf[..12].try_into().unwrap());
-                    12
-                }
-                4 => {
-                    torv3_addresses.push(buf[..37].try_into().unwrap());
-                    37
-                }
-                5 => {
-                    dns_hostname.extend(&buf);
-                    buf.len()
-                } // for dns_hostname chomp the rest of the buffer
-                _ => return Err(SerializationError::InvalidValue),
+                1 => 6,
+                2 => 18,
+                3 => 12,
+                4 => 37,
+                // 1-byte hostname length, the hostname, then the port
+                _ => match buf.first() {
+                    Some(hostname_len) => 1 + *hostname_len as usize + 2,
+                    None => return Err(SerializationError::TooFewBytes),
+                },
             };
+            // a truncated announcement can end partway through an address
+            if buf.len() < chomp_bytes {
+                return Err(SerializationError::TooFewBytes);
+            }
+            let address = &buf[..chomp_bytes];
+            match single_byte {
+                1 => ipv4_addresses.push(address.try_into().unwrap()),
+                2 => ipv6_addresses.push(address.try_into().unwrap()),
+                3 => torv2_addresses.push(address.try_into().unwrap()),
+                4 => torv3_addresses.push(address.try_into().unwrap()),
+                _ => {
+                    let (hostname, port) = address[1..].split_at(address.len() - 3);
+                    dns_hostnames.push(DnsHostname {
+                        hostname: hostname.to_vec(),
+                        port: u16::from_be_bytes([port[0], port[1]]),
+                    });
+                }
+            }
+            // stop an announcement from bloating our state with thousands of addresses
+            if [
+                ipv4_addresses.len(),
+                ipv6_addresses.len(),
+                torv2_addresses.len(),
+                torv3_addresses.len(),
+                dns_hostnames.len(),
+            ]
+            .iter()
+            .any(|count| *count > MAX_ADDRESSES_PER_TYPE)
+            {
+                return Err(SerializationError::InvalidValue);
+            }
             buf = buf[chomp_bytes..].to_vec();
         }
         Ok((
@@ -274,7 +456,8 @@ impl SerializableToBytes for NodeAddressesElement {
                 ipv6_addresses,
                 torv2_addresses,
                 torv3_addresses,
-                dns_hostname,
+                dns_hostnames,
+                unknown,
             },
             rest,
         ))
@@ -298,10 +481,12 @@ impl SerializableToBytes for NodeAddressesElement {
             buf.extend([4u8]);
             buf.extend(address);
         }
-        if !self.dns_hostname.is_empty() {
-            buf.extend([5u8]);
-            buf.extend(self.dns_hostname.clone());
+        for address in self.dns_hostnames.iter() {
+            buf.extend([5u8, address.hostname.len() as u8]);
+            buf.extend(address.hostname.clone());
+            buf.extend(address.port.to_be_bytes());
         }
+        buf.extend(self.unknown.clone());
         WireU16SizedBytes::new(buf).to_bytes()
     }
 }
@@ -416,7 +601,7 @@ impl WireU64Int {
 
 impl SerializableToBytes for WireU64Int {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        if data.len() < 4 {
+        if data.len() < 8 {
             return Err(SerializationError::TooFewBytes);
         }
         let value = u64::from_be_bytes([
@@ -460,6 +645,18 @@ pub struct SignatureElement {
     value: [u8; 64],
 }
 
+impl SignatureElement {
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        &self.value
+    }
+
+    // the wire carries signatures in compact form, r then s
+    pub fn as_secp_signature(&self) -> Result<Signature, secp256k1::Error> {
+        Signature::from_compact(&self.value)
+    }
+}
+
 impl SerializableToBytes for SignatureElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
         let (bytes, data) = decode_64_bytes(data)?;
@@ -493,7 +690,7 @@ pub struct Wire32Bytes {
 
 impl SerializableToBytes for Wire32Bytes {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        let (data, remainder) = decode_32_bytes(data).unwrap();
+        let (data, remainder) = decode_32_bytes(data)?;
         Ok((Wire32Bytes { value: data }, remainder))
     }
 
@@ -502,21 +699,58 @@ impl SerializableToBytes for Wire32Bytes {
     }
 }
 
+// fixed size fields too large or too rare for their own WireNBytes type
+#[derive(Clone, PartialEq)]
+pub struct WireBytes<const N: usize> {
+    pub value: [u8; N],
+}
+
+impl<const N: usize> SerializableToBytes for WireBytes<N> {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        if data.len() < N {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let (bytes, data) = data.split_at(N);
+        Ok((
+            WireBytes {
+                value: bytes.try_into().unwrap(),
+            },
+            data,
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_vec()
+    }
+}
+
+impl<const N: usize> fmt::Debug for WireBytes<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.value))
+    }
+}
+
 #[derive(Clone)]
 pub struct NodeAliasElement {
     pub value: Wire32Bytes,
 }
 
+impl NodeAliasElement {
+    pub fn as_string(&self) -> String {
+        // the alias is zero padded on the right
+        let end = self
+            .value
+            .value
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.value.value.len());
+        String::from_utf8_lossy(&self.value.value[..end]).to_string()
+    }
+}
+
 impl fmt::Debug for NodeAliasElement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // find the first byte that is zero
-        let mut i = 0;
-        while i < self.value.value.len() && self.value.value[i] == 0 {
-            i += 1;
-        }
-        // cast the first i bytes to a string
-        let alias = String::from_utf8_lossy(&self.value.value[..i]);
-        write!(f, "\"{}\"", alias)
+        write!(f, "\"{}\"", self.as_string())
     }
 }
 
@@ -553,6 +787,28 @@ impl SerializableToBytes for ChainHashElement {
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ChannelIDElement {
+    pub value: [u8; 32],
+}
+
+impl fmt::Debug for ChannelIDElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.value))
+    }
+}
+
+impl SerializableToBytes for ChannelIDElement {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (data, remainder) = decode_32_bytes(data)?;
+        Ok((ChannelIDElement { value: data }, remainder))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_vec()
+    }
+}
+
 fn decode_33_bytes(data: &[u8]) -> Result<([u8; 33], &[u8]), SerializationError> {
     if data.len() < 33 {
         return Err(SerializationError::TooFewBytes);
@@ -589,9 +845,46 @@ impl fmt::Debug for PointElement {
     }
 }
 
+thread_local! {
+    // only set inside with_strict_point_decoding
+    static STRICT_POINT_DECODING: Cell<bool> = const { Cell::new(false) };
+}
+
+// puts the previous setting back even if the decode panics
+struct StrictPointDecodingGuard(bool);
+
+impl Drop for StrictPointDecodingGuard {
+    fn drop(&mut self) {
+        STRICT_POINT_DECODING.with(|strict| strict.set(self.0));
+    }
+}
+
+// Runs decode with every PointElement checked as it is decoded. Decoding is permissive
+// otherwise, so anything we decode re-serializes unchanged, even gossip with a bad point
+// in it. The setting is per thread and only lasts for the call.
+pub fn with_strict_point_decoding<T>(decode: impl FnOnce() -> T) -> T {
+    let _guard =
+        StrictPointDecodingGuard(STRICT_POINT_DECODING.with(|strict| strict.replace(true)));
+    decode()
+}
+
+impl PointElement {
+    // like from_bytes, but the bytes must be a compressed point on the curve
+    pub fn from_bytes_strict(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (bytes, remainder) = decode_33_bytes(data)?;
+        if secp256k1::PublicKey::from_slice(&bytes).is_err() {
+            return Err(SerializationError::InvalidValue);
+        }
+        Ok((PointElement { value: bytes }, remainder))
+    }
+}
+
 impl SerializableToBytes for PointElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
-        let (bytes, remainder) = decode_33_bytes(data).unwrap();
+        if STRICT_POINT_DECODING.with(Cell::get) {
+            return PointElement::from_bytes_strict(data);
+        }
+        let (bytes, remainder) = decode_33_bytes(data)?;
         Ok((PointElement { value: bytes }, remainder))
     }
 
@@ -627,7 +920,44 @@ pub struct ShortChannelIDElement {
     pub output_index: u16,
 }
 
-impl ShortChannelIDElement {}
+impl fmt::Display for ShortChannelIDElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}x{}",
+            self.block_height, self.tx_index, self.output_index
+        )
+    }
+}
+
+impl ShortChannelIDElement {
+    // the funding tx position: block height, index in the block and output index
+    #[allow(dead_code)]
+    pub fn outpoint_hint(&self) -> (u32, u32, u16) {
+        (self.block_height, self.tx_index, self.output_index)
+    }
+
+    // the scid a channel gets once its funding tx confirms, None if the block height
+    // or tx index doesn't fit the 3 bytes the scid has for it
+    #[allow(dead_code)]
+    pub fn from_funding_position(block_height: u32, tx_index: u32, vout: u16) -> Option<Self> {
+        if block_height >= 1 << 24 || tx_index >= 1 << 24 {
+            return None;
+        }
+        Some(ShortChannelIDElement {
+            block_height,
+            tx_index,
+            output_index: vout,
+        })
+    }
+
+    // the 8 wire bytes as one integer, ordered like (block_height, tx_index, output_index)
+    pub fn to_u64(&self) -> u64 {
+        ((self.block_height as u64 & 0xffffff) << 40)
+            | ((self.tx_index as u64 & 0xffffff) << 16)
+            | self.output_index as u64
+    }
+}
 
 impl SerializableToBytes for ShortChannelIDElement {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
@@ -717,7 +1047,341 @@ impl SerializableToBytes for RemainderTypeWire {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BigSizeElement {
+    pub value: u64,
+}
+
+impl BigSizeElement {
+    pub fn new(value: u64) -> Self {
+        BigSizeElement { value }
+    }
+}
+
+impl SerializableToBytes for BigSizeElement {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        if data.is_empty() {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let (width, min) = match data[0] {
+            0xfd => (2, 0xfd),
+            0xfe => (4, 0x10000),
+            0xff => (8, 0x100000000),
+            n => return Ok((BigSizeElement { value: n as u64 }, &data[1..])),
+        };
+        if data.len() < 1 + width {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let mut buf = [0u8; 8];
+        buf[8 - width..].copy_from_slice(&data[1..1 + width]);
+        let value = u64::from_be_bytes(buf);
+        // bigsize must be minimally encoded
+        if value < min {
+            return Err(SerializationError::InvalidValue);
+        }
+        Ok((BigSizeElement { value }, &data[1 + width..]))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self.value {
+            0..=0xfc => vec![self.value as u8],
+            0xfd..=0xffff => [vec![0xfd], (self.value as u16).to_be_bytes().to_vec()].concat(),
+            0x10000..=0xffffffff => {
+                [vec![0xfe], (self.value as u32).to_be_bytes().to_vec()].concat()
+            }
+            _ => [vec![0xff], self.value.to_be_bytes().to_vec()].concat(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TLVRecord {
+    pub record_type: u64,
+    pub value: Vec<u8>,
+}
+
+impl TLVRecord {
+    pub fn new(record_type: u64, value: Vec<u8>) -> Self {
+        TLVRecord { record_type, value }
+    }
+
+    pub fn parse_stream(mut data: &[u8]) -> Result<Vec<TLVRecord>, SerializationError> {
+        let mut records: Vec<TLVRecord> = Vec::new();
+        while !data.is_empty() {
+            let (record, rest) = TLVRecord::from_bytes(data)?;
+            // types must be strictly increasing
+            if let Some(last) = records.last() {
+                if last.record_type >= record.record_type {
+                    return Err(SerializationError::InvalidValue);
+                }
+            }
+            records.push(record);
+            data = rest;
+        }
+        Ok(records)
+    }
+
+    pub fn find(records: &[TLVRecord], record_type: u64) -> Option<&TLVRecord> {
+        records.iter().find(|r| r.record_type == record_type)
+    }
+}
+
+impl SerializableToBytes for TLVRecord {
+    fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError> {
+        let (record_type, data) = BigSizeElement::from_bytes(data)?;
+        let (length, data) = BigSizeElement::from_bytes(data)?;
+        if (data.len() as u64) < length.value {
+            return Err(SerializationError::TooFewBytes);
+        }
+        let length = length.value as usize;
+        Ok((
+            TLVRecord {
+                record_type: record_type.value,
+                value: data[..length].to_vec(),
+            },
+            &data[length..],
+        ))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BigSizeElement::new(self.record_type).to_bytes();
+        bytes.extend(BigSizeElement::new(self.value.len() as u64).to_bytes());
+        bytes.extend(self.value.clone());
+        bytes
+    }
+}
+
 pub type NumPongBytesElement = WireU16Int;
 pub type TimestampElement = WireU32Int;
 pub type TimestampRangeElement = WireU32Int;
 pub type TLVStreamElement = RemainderTypeWire;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_point_decoding() {
+        let valid =
+            hex::decode("02c812a2b2ac05d8039e9fff80e6c7011162a1c56ac0a2b0de89cc356afdac6e14")
+                .unwrap();
+        assert!(PointElement::from_bytes_strict(&valid).is_ok());
+
+        // neither 0x02 nor 0x03, kept as is by the permissive decode
+        let mut invalid = valid.clone();
+        invalid[0] = 0x05;
+        let (point, _) = PointElement::from_bytes(&invalid).unwrap();
+        assert_eq!(point.to_bytes(), invalid);
+        assert!(matches!(
+            PointElement::from_bytes_strict(&invalid),
+            Err(SerializationError::InvalidValue)
+        ));
+        assert!(matches!(
+            PointElement::from_bytes_strict(&valid[..32]),
+            Err(SerializationError::TooFewBytes)
+        ));
+
+        // from_bytes is strict only inside the scope
+        assert!(with_strict_point_decoding(|| PointElement::from_bytes(&invalid)).is_err());
+        assert!(with_strict_point_decoding(|| PointElement::from_bytes(&valid)).is_ok());
+        assert!(PointElement::from_bytes(&invalid).is_ok());
+    }
+
+    #[test]
+    fn test_short_channel_id_funding_position() {
+        // 539268x845x1, a mainnet channel
+        let (scid, _) =
+            ShortChannelIDElement::from_bytes(&hex::decode("083a8400034d0001").unwrap()).unwrap();
+        assert_eq!(scid.outpoint_hint(), (539268, 845, 1));
+        assert_eq!(
+            ShortChannelIDElement::from_funding_position(539268, 845, 1),
+            Some(scid)
+        );
+        assert_eq!(
+            ShortChannelIDElement::from_funding_position(1 << 24, 0, 0),
+            None
+        );
+        assert_eq!(
+            ShortChannelIDElement::from_funding_position(0, 1 << 24, 0),
+            None
+        );
+        assert_eq!(scid.to_u64(), 0x083a8400034d0001);
+    }
+
+    #[test]
+    fn test_truncated_addresses_are_rejected() {
+        // descriptor byte followed by one byte less than the address needs
+        for (descriptor, address_len) in [(1u8, 6), (2, 18), (3, 12), (4, 37)] {
+            let mut blob = vec![descriptor];
+            blob.extend(vec![0u8; address_len - 1]);
+            let bytes = WireU16SizedBytes::new(blob).to_bytes();
+            assert!(
+                matches!(
+                    NodeAddressesElement::from_bytes(&bytes),
+                    Err(SerializationError::TooFewBytes)
+                ),
+                "descriptor {} did not fail",
+                descriptor
+            );
+        }
+        // the advertised length runs past the end of the buffer
+        assert!(matches!(
+            NodeAddressesElement::from_bytes(&[0, 7, 1, 127, 0, 0, 1]),
+            Err(SerializationError::TooFewBytes)
+        ));
+    }
+
+    #[test]
+    fn test_unknown_address_type_stops_parsing() {
+        let mut blob = vec![1, 127, 0, 0, 1, 0x26, 0x07];
+        // an unknown type followed by bytes that would parse as an ipv4 address
+        blob.extend([42, 1, 10, 0, 0, 1, 0x26, 0x07]);
+        let bytes = WireU16SizedBytes::new(blob).to_bytes();
+        let (addresses, rest) = NodeAddressesElement::from_bytes(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(addresses.ipv4_addresses, vec![[127, 0, 0, 1, 0x26, 0x07]]);
+        assert_eq!(addresses.unknown, vec![42, 1, 10, 0, 0, 1, 0x26, 0x07]);
+        assert_eq!(addresses.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_dns_hostname_followed_by_ipv4() {
+        let mut blob = vec![5, 11];
+        blob.extend(b"example.com");
+        blob.extend([0x26, 0x07]);
+        blob.extend([1, 127, 0, 0, 1, 0x26, 0x07]);
+        let bytes = WireU16SizedBytes::new(blob).to_bytes();
+        let (addresses, rest) = NodeAddressesElement::from_bytes(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            addresses.dns_hostnames,
+            vec![DnsHostname {
+                hostname: b"example.com".to_vec(),
+                port: 9735,
+            }]
+        );
+        assert_eq!(addresses.dns_hostnames[0].to_string(), "example.com:9735");
+        assert_eq!(addresses.ipv4_addresses, vec![[127, 0, 0, 1, 0x26, 0x07]]);
+        assert!(addresses.unknown.is_empty());
+
+        // re-serialized in ascending type order, which parses the same
+        let (reparsed, _) = NodeAddressesElement::from_bytes(&addresses.to_bytes()).unwrap();
+        assert_eq!(reparsed.dns_hostnames, addresses.dns_hostnames);
+        assert_eq!(reparsed.ipv4_addresses, addresses.ipv4_addresses);
+
+        // the hostname length runs past the end, or the length byte itself is missing
+        for blob in [vec![5, 11, b'a', 0x26, 0x07], vec![5]] {
+            let bytes = WireU16SizedBytes::new(blob).to_bytes();
+            assert!(matches!(
+                NodeAddressesElement::from_bytes(&bytes),
+                Err(SerializationError::TooFewBytes)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_too_many_addresses_are_rejected() {
+        let mut blob = Vec::new();
+        for _ in 0..MAX_ADDRESSES_PER_TYPE {
+            blob.extend([1, 127, 0, 0, 1, 0x26, 0x07]);
+        }
+        let bytes = WireU16SizedBytes::new(blob.clone()).to_bytes();
+        let (addresses, _) = NodeAddressesElement::from_bytes(&bytes).unwrap();
+        assert_eq!(addresses.ipv4_addresses.len(), MAX_ADDRESSES_PER_TYPE);
+
+        blob.extend([1, 127, 0, 0, 1, 0x26, 0x07]);
+        let bytes = WireU16SizedBytes::new(blob).to_bytes();
+        assert!(matches!(
+            NodeAddressesElement::from_bytes(&bytes),
+            Err(SerializationError::InvalidValue)
+        ));
+    }
+
+    #[test]
+    fn test_features_set_bits() {
+        assert!(FeaturesElement::new(vec![]).set_bits().is_empty());
+        // bit 0 is in the last byte
+        assert_eq!(
+            FeaturesElement::new(vec![0x80, 0x00, 0x21]).set_bits(),
+            vec![0, 5, 23]
+        );
+    }
+
+    #[test]
+    fn test_bigsize_roundtrip() {
+        for (value, encoded) in [
+            (0u64, "00"),
+            (252, "fc"),
+            (253, "fd00fd"),
+            (65535, "fdffff"),
+            (65536, "fe00010000"),
+            (4294967295, "feffffffff"),
+            (4294967296, "ff0000000100000000"),
+        ] {
+            let bytes = BigSizeElement::new(value).to_bytes();
+            assert_eq!(hex::encode(&bytes), encoded);
+            let (decoded, rest) = BigSizeElement::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.value, value);
+            assert!(rest.is_empty());
+        }
+        assert!(BigSizeElement::from_bytes(&hex::decode("fd00fc").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_tlv_stream() {
+        let bytes = hex::decode("0108000071000001000003020102").unwrap();
+        let records = TLVRecord::parse_stream(&bytes).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].record_type, 1);
+        assert_eq!(records[1], TLVRecord::new(3, vec![1, 2]));
+        let reencoded: Vec<u8> = records.iter().flat_map(|r| r.to_bytes()).collect();
+        assert_eq!(reencoded, bytes);
+        // out of order and truncated streams are rejected
+        assert!(TLVRecord::parse_stream(&hex::decode("030101010101").unwrap()).is_err());
+        assert!(TLVRecord::parse_stream(&hex::decode("0108000071").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_address_display() {
+        assert_eq!(
+            Address::IPv4([127, 0, 0, 1, 0x26, 0x07]).to_string(),
+            "127.0.0.1:9735"
+        );
+        let mut ipv6 = [0u8; 18];
+        ipv6[15] = 1;
+        ipv6[17] = 80;
+        assert_eq!(Address::IPv6(ipv6).to_string(), "[::1]:80");
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+    }
+
+    #[test]
+    fn test_connectable_addresses_dedup_and_order() {
+        let mut mapped = [0u8; 18];
+        mapped[10] = 0xff;
+        mapped[11] = 0xff;
+        mapped[12..].copy_from_slice(&[127, 0, 0, 1, 0x26, 0x07]);
+        let mut ipv6 = [0u8; 18];
+        ipv6[15] = 1;
+        let addresses = NodeAddressesElement {
+            ipv4_addresses: vec![
+                [10, 0, 0, 1, 0x26, 0x07],
+                [127, 0, 0, 1, 0x26, 0x07],
+                [10, 0, 0, 1, 0x26, 0x07],
+            ],
+            ipv6_addresses: vec![mapped, ipv6, ipv6],
+            torv2_addresses: vec![],
+            torv3_addresses: vec![[7u8; 37], [7u8; 37]],
+            dns_hostnames: vec![],
+            unknown: vec![],
+        };
+        assert_eq!(
+            addresses.connectable_addresses(),
+            vec![
+                Address::IPv4([10, 0, 0, 1, 0x26, 0x07]),
+                Address::IPv4([127, 0, 0, 1, 0x26, 0x07]),
+                Address::IPv6(ipv6),
+                Address::TorV3([7u8; 37]),
+            ]
+        );
+    }
+}
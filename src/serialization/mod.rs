@@ -2,12 +2,25 @@ pub use crate::serialization::base_types::*;
 
 mod base_types;
 
+use std::fmt;
+
 #[derive(Debug, Clone)]
 pub enum SerializationError {
     TooFewBytes,
     InvalidValue,
 }
 
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializationError::TooFewBytes => write!(f, "too few bytes"),
+            SerializationError::InvalidValue => write!(f, "invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for SerializationError {}
+
 pub trait SerializableToBytes: Sized {
     fn from_bytes(data: &[u8]) -> Result<(Self, &[u8]), SerializationError>;
     fn to_bytes(&self) -> Vec<u8>;
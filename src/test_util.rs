@@ -0,0 +1,55 @@
+// Fixtures shared by the unit tests. Messages come from test/examples rather than hex
+// pasted into each test module.
+use crate::message_decoder::MessageDecoder;
+use crate::messages::MessageType;
+
+pub fn read_example_messages() -> Vec<String> {
+    let contents = std::fs::read_to_string("test/examples").unwrap();
+    example_lines(&contents)
+}
+
+// one hex encoded message per line. Blank lines and # comments are skipped, lines
+// that aren't hex are reported and skipped so one bad paste doesn't hide the rest.
+pub fn example_lines(contents: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match hex::decode(line) {
+            Ok(_) => lines.push(line.to_string()),
+            Err(e) => eprintln!("test/examples line {}: {}", index + 1, e),
+        }
+    }
+    lines
+}
+
+// wire bytes of the first example of message_type
+pub fn example_message(message_type: MessageType) -> Vec<u8> {
+    read_example_messages()
+        .iter()
+        .map(|line| hex::decode(line).unwrap())
+        .find(|bytes| MessageDecoder::peek_type(bytes).ok() == Some(message_type))
+        .unwrap_or_else(|| panic!("no example for {:?}", message_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_lines_skip_comments_and_bad_hex() {
+        let contents = "# pings\n\n0012000400000000\n  \n00zz\n0013000400000000\n";
+        assert_eq!(
+            example_lines(contents),
+            vec!["0012000400000000", "0013000400000000"]
+        );
+    }
+
+    #[test]
+    fn test_example_message_takes_the_first_of_its_type() {
+        let bytes = example_message(MessageType::ChannelUpdate);
+        assert_eq!(hex::encode(&bytes[..4]), "010200b3");
+    }
+}
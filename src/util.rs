@@ -1,12 +1,21 @@
-use bitcoin::secp256k1::SecretKey as BitcoinSecretKey;
-use secp256k1::rand::rngs::OsRng;
-use secp256k1::Secp256k1;
+use bitcoin::secp256k1::{All, Secp256k1, SecretKey as BitcoinSecretKey};
+use secp256k1::rand::{rngs::OsRng, RngCore};
+use std::sync::LazyLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// Building a context precomputes its tables, so everything shares this one instead
+// of creating its own per key or per connection.
+pub static SECP: LazyLock<Secp256k1<All>> = LazyLock::new(Secp256k1::new);
+
 pub fn new_random_secret_key() -> BitcoinSecretKey {
-    let secp = Secp256k1::new();
-    let (secret_key, _) = secp.generate_keypair(&mut OsRng);
-    BitcoinSecretKey::from_slice(&secret_key.secret_bytes()).unwrap()
+    // no context needed, just retry the vanishingly rare out of range value
+    let mut bytes = [0u8; 32];
+    loop {
+        OsRng.fill_bytes(&mut bytes);
+        if let Ok(secret_key) = BitcoinSecretKey::from_slice(&bytes) {
+            return secret_key;
+        }
+    }
 }
 
 pub fn get_current_timestamp() -> u64 {
@@ -15,3 +24,50 @@ pub fn get_current_timestamp() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+// CRC32C (Castagnoli) as used by the gossip query checksums, bitwise since it only
+// runs over channel_updates
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82f63b78 & mask);
+        }
+    }
+    !crc
+}
+
+// txids go over the wire in internal byte order, explorers show them reversed
+#[allow(dead_code)]
+pub fn format_outpoint(txid: &[u8; 32], vout: u16) -> String {
+    let mut display = *txid;
+    display.reverse();
+    format!("{}:{}", hex::encode(display), vout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_outpoint() {
+        // the genesis coinbase
+        let txid: [u8; 32] =
+            hex::decode("3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            format_outpoint(&txid, 0),
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0"
+        );
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+    }
+}